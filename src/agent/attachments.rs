@@ -0,0 +1,141 @@
+use super::domain::Blob;
+use anyhow::Context;
+use base64::Engine;
+use base64::engine::general_purpose::STANDARD;
+use std::path::Path;
+
+// inline data counts towards the size limit of the whole request (20MB for gemini), and grows
+// by a third when base64 encoded
+const MAX_ATTACHMENT_SIZE_BYTES: u64 = 10 * 1024 * 1024;
+
+const MIME_TYPES: [(&str, &str); 12] = [
+    ("png", "image/png"),
+    ("jpg", "image/jpeg"),
+    ("jpeg", "image/jpeg"),
+    ("webp", "image/webp"),
+    ("gif", "image/gif"),
+    ("heic", "image/heic"),
+    ("heif", "image/heif"),
+    ("pdf", "application/pdf"),
+    ("mp3", "audio/mp3"),
+    ("wav", "audio/wav"),
+    ("mp4", "video/mp4"),
+    ("mov", "video/mov"),
+];
+
+/// Returns the MIME type of a file that can be attached, based on its extension
+pub(super) fn get_mime_type(path: &Path) -> Option<&'static str> {
+    let extension = path.extension()?.to_str()?.to_lowercase();
+
+    MIME_TYPES
+        .iter()
+        .find(|(ext, _)| *ext == extension)
+        .map(|(_, mime_type)| *mime_type)
+}
+
+/// Reads a file into base64 encoded inline data
+pub(super) fn load_attachment(path: &Path) -> anyhow::Result<Blob> {
+    let mime_type = get_mime_type(path).with_context(|| {
+        let supported = MIME_TYPES
+            .iter()
+            .map(|(ext, _)| *ext)
+            .collect::<Vec<_>>()
+            .join(", ");
+        format!(
+            "{} can't be attached; supported file types: {supported}",
+            path.to_string_lossy()
+        )
+    })?;
+
+    let metadata = std::fs::metadata(path)
+        .with_context(|| format!("couldn't read {}", path.to_string_lossy()))?;
+    if metadata.len() > MAX_ATTACHMENT_SIZE_BYTES {
+        anyhow::bail!(
+            "{} is too large to be attached ({} bytes, the limit is {MAX_ATTACHMENT_SIZE_BYTES})",
+            path.to_string_lossy(),
+            metadata.len(),
+        );
+    }
+
+    let bytes =
+        std::fs::read(path).with_context(|| format!("couldn't read {}", path.to_string_lossy()))?;
+
+    Ok(Blob {
+        mime_type: mime_type.to_string(),
+        data: STANDARD.encode(bytes),
+    })
+}
+
+/// Returns the paths of the files referenced via `@path` in user input.
+///
+/// Only words that start with @ and name an existing file of a type that can be attached are
+/// treated as references, so that eg. npm scopes, decorators or mentions are left as plain text.
+/// References are left in the text, so that the model can tell which attachment is which.
+pub(super) fn extract_attachment_paths(input: &str) -> Vec<&str> {
+    input
+        .split_whitespace()
+        .filter_map(|word| word.strip_prefix('@'))
+        .filter(|path| {
+            let path = Path::new(path);
+            get_mime_type(path).is_some() && path.is_file()
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use insta::assert_snapshot;
+
+    //-------------//
+    //  SUCCESSES  //
+    //-------------//
+
+    #[test]
+    fn attachment_paths_are_extracted_from_input() {
+        // GIVEN
+        let input = "why does @./src/tools/testdata/pixel.png look off? see @docs/missing.pdf and @Override";
+
+        // WHEN
+        let paths = extract_attachment_paths(input);
+
+        // THEN
+        assert_eq!(paths, vec!["./src/tools/testdata/pixel.png"]);
+    }
+
+    #[test]
+    fn npm_scopes_and_decorators_are_left_as_text() {
+        // GIVEN
+        let input = "bump @types/node, then check why @pytest.fixture and the @app.route handler are slow (ask dev@example.com)";
+
+        // WHEN
+        let paths = extract_attachment_paths(input);
+
+        // THEN
+        assert!(paths.is_empty());
+    }
+
+    #[test]
+    fn mime_type_is_derived_from_extension() {
+        // GIVEN
+        // WHEN
+        let mime_type = get_mime_type(Path::new("assets/Screenshot.JPG"));
+
+        // THEN
+        assert_eq!(mime_type, Some("image/jpeg"));
+    }
+
+    //------------//
+    //  FAILURES  //
+    //------------//
+
+    #[test]
+    fn unsupported_file_types_are_rejected() {
+        // GIVEN
+        // WHEN
+        let result = load_attachment(Path::new("Cargo.toml")).expect_err("result wasn't an error");
+
+        // THEN
+        assert_snapshot!(result, @"Cargo.toml can't be attached; supported file types: png, jpg, jpeg, webp, gif, heic, heif, pdf, mp3, wav, mp4, mov");
+    }
+}
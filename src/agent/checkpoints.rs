@@ -0,0 +1,387 @@
+use super::domain::{Content, Role};
+use anyhow::Context;
+use std::path::{Path, PathBuf};
+
+/// Where in the conversation a checkpoint's message starts
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Position {
+    /// The message started a new turn, after this many turns
+    Turn(usize),
+    /// The message was merged into the turn at `turn` (eg. because the previous request failed),
+    /// after this many of its parts
+    Part { turn: usize, parts: usize },
+    /// The turns before the message were compacted, so returning to it only restores files
+    Compacted,
+}
+
+/// State of the session before a user message, which /undo and /rewind return to
+#[derive(Debug)]
+pub(super) struct Checkpoint {
+    position: Position,
+    pub(super) message: String,
+    /// Files edited via edit_file since the message, along with their contents before the first
+    /// edit (`None` if the file didn't exist yet)
+    files: Vec<(PathBuf, Option<Vec<u8>>)>,
+    /// Directories that didn't exist yet when files in them were recorded, which edit_file
+    /// creates along with the files
+    dirs: Vec<PathBuf>,
+}
+
+impl Checkpoint {
+    /// Creates a checkpoint for a message that's about to be added to `contents`; it's merged
+    /// into the last turn if that's a user turn
+    pub(super) fn new(contents: &[Content], message: &str) -> Self {
+        let position = match contents.last() {
+            Some(last) if last.role == Some(Role::User) => Position::Part {
+                turn: contents.len() - 1,
+                parts: last.parts.len(),
+            },
+            _ => Position::Turn(contents.len()),
+        };
+
+        Self {
+            position,
+            message: message.to_string(),
+            files: vec![],
+            dirs: vec![],
+        }
+    }
+
+    /// Records the contents of a file that's about to be edited, unless it was already recorded
+    pub(super) fn record_file(&mut self, path: &Path) -> anyhow::Result<()> {
+        if self.files.iter().any(|(p, _)| p == path) {
+            return Ok(());
+        }
+
+        let contents = match std::fs::read(path) {
+            Ok(contents) => Some(contents),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => {
+                let missing_dirs = path
+                    .ancestors()
+                    .skip(1)
+                    .take_while(|dir| !dir.as_os_str().is_empty() && !dir.exists());
+                for dir in missing_dirs {
+                    if !self.dirs.iter().any(|d| d == dir) {
+                        self.dirs.push(dir.to_path_buf());
+                    }
+                }
+                None
+            }
+            Err(e) => {
+                return Err(e).with_context(|| format!("couldn't read {}", path.to_string_lossy()));
+            }
+        };
+        self.files.push((path.to_path_buf(), contents));
+
+        Ok(())
+    }
+
+    pub(super) fn edited_files(&self) -> impl Iterator<Item = &Path> {
+        self.files.iter().map(|(path, _)| path.as_path())
+    }
+
+    /// Whether the checkpoint's message is part of the turn at `index`
+    pub(super) fn is_in_turn(&self, index: usize) -> bool {
+        match self.position {
+            Position::Turn(turn) | Position::Part { turn, .. } => turn == index,
+            Position::Compacted => false,
+        }
+    }
+
+    /// Whether the turns before the message were compacted, so that only files can be restored
+    pub(super) fn is_compacted(&self) -> bool {
+        self.position == Position::Compacted
+    }
+
+    /// Moves the checkpoint to where its message is once the turns before `split` were replaced
+    /// with a summary, which is prepended to the turn at `split`
+    fn compact(&mut self, split: usize) {
+        self.position = match self.position {
+            Position::Turn(turn) if turn == split => Position::Part { turn: 0, parts: 1 },
+            Position::Turn(turn) if turn > split => Position::Turn(turn - split),
+            Position::Part { turn, parts } if turn == split => Position::Part {
+                turn: 0,
+                parts: parts + 1,
+            },
+            Position::Part { turn, parts } if turn > split => Position::Part {
+                turn: turn - split,
+                parts,
+            },
+            _ => Position::Compacted,
+        };
+    }
+
+    /// Drops the turns (or parts of the last one) from the message on
+    fn truncate(&self, contents: &mut Vec<Content>) {
+        match self.position {
+            Position::Turn(turn) => contents.truncate(turn),
+            Position::Part { turn, parts } => {
+                contents.truncate(turn + 1);
+                if let Some(last) = contents.get_mut(turn) {
+                    last.parts.truncate(parts);
+                }
+            }
+            Position::Compacted => {}
+        }
+    }
+
+    /// Puts the recorded files back the way they were; files that didn't exist are removed, along
+    /// with the directories created for them if nothing else was put in them since
+    fn restore(&self) -> anyhow::Result<()> {
+        for (path, contents) in &self.files {
+            let result = match contents {
+                Some(contents) => std::fs::write(path, contents),
+                None => match std::fs::remove_file(path) {
+                    Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(()),
+                    result => result,
+                },
+            };
+            result.with_context(|| format!("couldn't restore {}", path.to_string_lossy()))?;
+        }
+
+        let mut dirs = self.dirs.iter().collect::<Vec<_>>();
+        // deepest first, so that directories are empty by the time they're removed
+        dirs.sort_by_key(|dir| std::cmp::Reverse(dir.components().count()));
+        for dir in dirs {
+            match std::fs::remove_dir(dir) {
+                Err(e)
+                    if matches!(
+                        e.kind(),
+                        std::io::ErrorKind::NotFound | std::io::ErrorKind::DirectoryNotEmpty
+                    ) => {}
+                result => {
+                    result.with_context(|| format!("couldn't remove {}", dir.to_string_lossy()))?
+                }
+            }
+        }
+
+        Ok(())
+    }
+}
+
+/// Returns the session to the checkpoint at `index`: the files edited since are restored, and the
+/// turns that followed it are dropped, along with the checkpoint and those after it. Returns the
+/// files that were restored.
+///
+/// Checkpoints are undone one at a time from the most recent one, so that if restoring a file
+/// fails, the conversation still matches the files.
+pub(super) fn rewind(
+    checkpoints: &mut Vec<Checkpoint>,
+    index: usize,
+    contents: &mut Vec<Content>,
+) -> anyhow::Result<Vec<PathBuf>> {
+    if index >= checkpoints.len() {
+        anyhow::bail!("there's no turn {} to return to", index + 1);
+    }
+
+    let mut restored = vec![];
+    while let Some(checkpoint) = checkpoints.get(index..).and_then(|c| c.last()) {
+        checkpoint.restore()?;
+        restored.extend(checkpoint.edited_files().map(Path::to_path_buf));
+        checkpoint.truncate(contents);
+        checkpoints.pop();
+    }
+    restored.sort();
+    restored.dedup();
+
+    Ok(restored)
+}
+
+/// Moves the checkpoints to where their messages are once the turns before `split` were replaced
+/// with a summary. Checkpoints of compacted messages are kept, so that the files edited since can
+/// still be restored; returns how many were compacted.
+pub(super) fn compact_checkpoints(checkpoints: &mut [Checkpoint], split: usize) -> usize {
+    let mut compacted = 0;
+    for checkpoint in checkpoints {
+        let was_compacted = checkpoint.is_compacted();
+        checkpoint.compact(split);
+        if checkpoint.is_compacted() && !was_compacted {
+            compacted += 1;
+        }
+    }
+
+    compacted
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::agent::domain::{Part, Role};
+    use insta::assert_snapshot;
+
+    fn temp_dir() -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("agent1-checkpoints-{:x}", fastrand::u64(..)));
+        std::fs::create_dir_all(&dir).expect("temp dir should've been created");
+
+        dir
+    }
+
+    /// Returns a conversation of `len` turns, alternating between user and model turns
+    fn conversation(len: usize) -> Vec<Content> {
+        (0..len)
+            .map(|i| Content {
+                parts: vec![Part::Text(format!("turn {i}"))],
+                role: Some(if i % 2 == 0 { Role::User } else { Role::Model }),
+            })
+            .collect()
+    }
+
+    //-------------//
+    //  SUCCESSES  //
+    //-------------//
+
+    #[test]
+    fn rewinding_restores_edited_and_removes_created_files() {
+        // GIVEN
+        let dir = temp_dir();
+        let edited = dir.join("main.rs");
+        let created = dir.join("lib.rs");
+        std::fs::write(&edited, "fn main() {}").expect("file should've been written");
+        let mut contents = conversation(6);
+
+        let mut first = Checkpoint::new(&[], "add a library");
+        first
+            .record_file(&edited)
+            .expect("file should've been recorded");
+        first
+            .record_file(&created)
+            .expect("file should've been recorded");
+        std::fs::write(&edited, "mod lib;\nfn main() {}").expect("file should've been written");
+        std::fs::write(&created, "pub fn f() {}").expect("file should've been written");
+
+        let mut second = Checkpoint::new(&contents[..4], "use it");
+        second
+            .record_file(&edited)
+            .expect("file should've been recorded");
+        std::fs::write(&edited, "mod lib;\nfn main() { lib::f() }")
+            .expect("file should've been written");
+        let mut checkpoints = vec![first, second];
+
+        // WHEN
+        let restored =
+            rewind(&mut checkpoints, 0, &mut contents).expect("checkpoints should've been rewound");
+
+        // THEN
+        assert!(contents.is_empty());
+        assert_eq!(restored, vec![created.clone(), edited.clone()]);
+        assert!(checkpoints.is_empty());
+        assert_eq!(
+            std::fs::read_to_string(&edited).expect("file should've been read"),
+            "fn main() {}"
+        );
+        assert!(!created.exists());
+        std::fs::remove_dir_all(dir).expect("temp dir should've been removed");
+    }
+
+    #[test]
+    fn rewinding_removes_directories_created_for_new_files() {
+        // GIVEN
+        let dir = temp_dir();
+        let created = dir.join("src/tools/grep.rs");
+        let kept = dir.join("src/main.rs");
+
+        let mut checkpoint = Checkpoint::new(&[], "add a grep tool");
+        checkpoint
+            .record_file(&created)
+            .expect("file should've been recorded");
+        checkpoint
+            .record_file(&kept)
+            .expect("file should've been recorded");
+        std::fs::create_dir_all(created.parent().expect("file should've had a parent"))
+            .expect("dirs should've been created");
+        std::fs::write(&created, "pub fn grep() {}").expect("file should've been written");
+        std::fs::write(&kept, "fn main() {}").expect("file should've been written");
+        let notes = dir.join("src/NOTES.md");
+        std::fs::write(&notes, "written by a command").expect("file should've been written");
+        let mut checkpoints = vec![checkpoint];
+
+        // WHEN
+        rewind(&mut checkpoints, 0, &mut vec![]).expect("checkpoints should've been rewound");
+
+        // THEN
+        assert!(!dir.join("src/tools").exists());
+        assert!(!kept.exists());
+        // the directory is kept since it isn't empty
+        assert!(notes.exists());
+        std::fs::remove_dir_all(dir).expect("temp dir should've been removed");
+    }
+
+    #[test]
+    fn messages_merged_into_a_turn_can_be_rewound_on_their_own() {
+        // GIVEN
+        let mut contents = conversation(3);
+        // the request for the last turn failed, so the next message was merged into it
+        let checkpoint = Checkpoint::new(&contents, "and the changelog");
+        contents[2]
+            .parts
+            .push(Part::Text("and the changelog".to_string()));
+        let mut checkpoints = vec![Checkpoint::new(&contents[..2], "turn 2"), checkpoint];
+
+        // WHEN
+        rewind(&mut checkpoints, 1, &mut contents).expect("checkpoints should've been rewound");
+
+        // THEN
+        assert_eq!(checkpoints.len(), 1);
+        assert_eq!(contents.len(), 3);
+        assert_eq!(contents[2].parts.len(), 1);
+    }
+
+    #[test]
+    fn edits_can_still_be_reverted_after_compaction() {
+        // GIVEN
+        let dir = temp_dir();
+        let edited = dir.join("main.rs");
+        std::fs::write(&edited, "fn main() {}").expect("file should've been written");
+        let contents = conversation(6);
+
+        let mut first = Checkpoint::new(&[], "turn 0");
+        first
+            .record_file(&edited)
+            .expect("file should've been recorded");
+        std::fs::write(&edited, "fn main() { todo!() }").expect("file should've been written");
+        let mut checkpoints = vec![
+            first,
+            Checkpoint::new(&contents[..2], "turn 2"),
+            Checkpoint::new(&contents[..4], "turn 4"),
+        ];
+        // turns 0 and 1 are replaced with a summary, which is prepended to turn 2
+        let mut contents = contents[2..].to_vec();
+        contents[0].parts.insert(
+            0,
+            Part::Text("Summary of the earlier conversation".to_string()),
+        );
+
+        // WHEN
+        let compacted = compact_checkpoints(&mut checkpoints, 2);
+        rewind(&mut checkpoints, 0, &mut contents).expect("checkpoints should've been rewound");
+
+        // THEN
+        assert_eq!(compacted, 1);
+        assert!(checkpoints.is_empty());
+        // the summary is kept, since the conversation can't go back further
+        assert_eq!(contents.len(), 1);
+        assert_eq!(contents[0].parts.len(), 1);
+        assert_eq!(
+            std::fs::read_to_string(&edited).expect("file should've been read"),
+            "fn main() {}"
+        );
+        std::fs::remove_dir_all(dir).expect("temp dir should've been removed");
+    }
+
+    //------------//
+    //  FAILURES  //
+    //------------//
+
+    #[test]
+    fn rewinding_past_the_last_checkpoint_fails() {
+        // GIVEN
+        let mut checkpoints = vec![Checkpoint::new(&[], "hello")];
+
+        // WHEN
+        let result = rewind(&mut checkpoints, 1, &mut vec![]).expect_err("result wasn't an error");
+
+        // THEN
+        assert_snapshot!(result, @"there's no turn 2 to return to");
+        assert_eq!(checkpoints.len(), 1);
+    }
+}
@@ -0,0 +1,207 @@
+use super::domain::{Content, Part, Role, Usage};
+use super::providers::{ModelRequest, Provider};
+
+const SUMMARY_PROMPT: &str = "The conversation so far is getting too long. Summarize it, so that the summary can replace it and you can continue working from it. Include the user's requests and goals, the decisions that were made and why, the files that were read or changed and what you learned about them, and what's left to do. Don't call any functions; answer with the summary only.";
+pub(super) const SUMMARY_HEADER: &str = "Summary of the earlier conversation:";
+/// Number of most recent user messages that are kept as is, along with everything that follows
+/// them
+const KEPT_USER_MESSAGES: usize = 2;
+
+pub(super) struct Compaction {
+    pub(super) contents: Vec<Content>,
+    /// Number of turns that were replaced by the summary
+    pub(super) compacted_turns: usize,
+    pub(super) usage: Option<Usage>,
+}
+
+/// Asks the model to summarize the older turns of the request's conversation, and replaces them
+/// with the summary; `focus` is passed on to the model as additional instructions.
+///
+/// The most recent user messages and everything that follows them (including pending function
+/// calls and their responses) are kept as is.
+pub(super) fn compact(
+    provider: &dyn Provider,
+    request: &ModelRequest,
+    focus: Option<&str>,
+) -> anyhow::Result<Compaction> {
+    let split = find_split(request.contents)
+        .ok_or_else(|| anyhow::anyhow!("the conversation is too short to be compacted"))?;
+
+    let mut prompt = SUMMARY_PROMPT.to_string();
+    if let Some(focus) = focus {
+        prompt.push_str(&format!(
+            "\n\nPay particular attention to the following: {focus}"
+        ));
+    }
+
+    // the older turns always end with a model turn, since the split is at a user message
+    let mut older = request.contents[..split].to_vec();
+    older.push(Content {
+        parts: vec![Part::Text(prompt)],
+        role: Some(Role::User),
+    });
+
+    let summary_request = ModelRequest {
+        contents: &older,
+        ..*request
+    };
+    let resp = provider
+        .generate(&summary_request, &mut |_| {})
+        .map_err(|e| anyhow::anyhow!("couldn't get a summary from {}: {e}", provider.name()))?;
+
+    let summary = resp
+        .parts
+        .iter()
+        .filter_map(|part| match part {
+            Part::Text(t) => Some(t.as_str()),
+            _ => None,
+        })
+        .collect::<Vec<_>>()
+        .join("");
+    if summary.trim().is_empty() {
+        anyhow::bail!("{} returned an empty summary", provider.name());
+    }
+
+    Ok(Compaction {
+        contents: replace_with_summary(request.contents, split, summary.trim()),
+        compacted_turns: split,
+        usage: resp.usage,
+    })
+}
+
+/// Returns the index of the user message from which turns are kept, if there are older turns to
+/// compact.
+///
+/// User turns that hold function responses aren't considered, so that function calls and their
+/// responses are never separated.
+fn find_split(contents: &[Content]) -> Option<usize> {
+    let user_messages = contents
+        .iter()
+        .enumerate()
+        .filter(|(_, c)| {
+            c.role == Some(Role::User)
+                && !c
+                    .parts
+                    .iter()
+                    .any(|p| matches!(p, Part::FunctionResponse(_)))
+        })
+        .map(|(i, _)| i)
+        .collect::<Vec<_>>();
+
+    let split = *user_messages.iter().rev().nth(KEPT_USER_MESSAGES - 1)?;
+    (split > 0).then_some(split)
+}
+
+/// Replaces the turns before `split` with the summary, which is prepended to the first kept user
+/// message, since providers expect user and model turns to alternate
+fn replace_with_summary(contents: &[Content], split: usize, summary: &str) -> Vec<Content> {
+    let mut compacted = contents[split..].to_vec();
+    compacted[0]
+        .parts
+        .insert(0, Part::Text(format!("{SUMMARY_HEADER}\n\n{summary}")));
+
+    compacted
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::agent::domain::{
+        FunctionCall, FunctionCallResponse, FunctionDeclarationName, FunctionResponse,
+    };
+    use insta::assert_snapshot;
+
+    fn text(role: Role, text: &str) -> Content {
+        Content {
+            parts: vec![Part::Text(text.to_string())],
+            role: Some(role),
+        }
+    }
+
+    fn conversation() -> Vec<Content> {
+        vec![
+            text(Role::User, "what does this project do?"),
+            text(Role::Model, "it's an AI agent"),
+            text(Role::User, "read the manifest"),
+            Content {
+                parts: vec![Part::FunctionCall(FunctionCall {
+                    id: None,
+                    name: FunctionDeclarationName::ReadFile,
+                    args: None,
+                    thought_signature: None,
+                })],
+                role: Some(Role::Model),
+            },
+            Content {
+                parts: vec![Part::FunctionResponse(FunctionResponse {
+                    id: None,
+                    name: FunctionDeclarationName::ReadFile,
+                    response: FunctionCallResponse::Output("[package]".to_string()),
+                    will_continue: None,
+                })],
+                role: Some(Role::User),
+            },
+            text(Role::Model, "it's a rust project"),
+            text(Role::User, "which edition?"),
+        ]
+    }
+
+    //-------------//
+    //  SUCCESSES  //
+    //-------------//
+
+    #[test]
+    fn recent_user_messages_and_function_calls_are_kept() {
+        // GIVEN
+        let contents = conversation();
+
+        // WHEN
+        let split = find_split(&contents);
+
+        // THEN
+        assert_eq!(split, Some(2));
+    }
+
+    #[test]
+    fn older_turns_are_replaced_with_the_summary() {
+        // GIVEN
+        let contents = conversation();
+
+        // WHEN
+        let compacted = replace_with_summary(&contents, 2, "the user asked what agent1 is");
+
+        // THEN
+        assert_eq!(compacted.len(), 5);
+        assert_snapshot!(format!("{:#?}", compacted[0]), @r#"
+        Content {
+            parts: [
+                Text(
+                    "Summary of the earlier conversation:\n\nthe user asked what agent1 is",
+                ),
+                Text(
+                    "read the manifest",
+                ),
+            ],
+            role: Some(
+                User,
+            ),
+        }
+        "#);
+    }
+
+    //------------//
+    //  FAILURES  //
+    //------------//
+
+    #[test]
+    fn short_conversations_are_not_compacted() {
+        // GIVEN
+        let contents = &conversation()[2..];
+
+        // WHEN
+        let split = find_split(contents);
+
+        // THEN
+        assert_eq!(split, None);
+    }
+}
@@ -0,0 +1,154 @@
+use super::compaction::{Compaction, compact};
+use super::domain::Part;
+use super::providers::{ModelRequest, Provider, ProviderError};
+
+/// Rough number of characters per token, for providers without a token counting endpoint
+const CHARS_PER_TOKEN: usize = 4;
+/// Rough number of tokens an attachment takes up; images typically take a few hundred to a
+/// couple thousand tokens, depending on the provider and their resolution
+const TOKENS_PER_ATTACHMENT: usize = 1000;
+/// Share of the context window above which a warning is shown before sending a request
+const WARNING_THRESHOLD_PERCENT: usize = 80;
+
+/// How much of the model's context window a request takes up
+#[derive(Debug, PartialEq, Eq)]
+enum ContextUsage {
+    Fits,
+    NearlyFull { percent: usize },
+    Overflows,
+}
+
+/// Outcome of checking a request against the model's context window before sending it
+pub(super) enum ContextCheck {
+    Fits,
+    NearlyFull {
+        percent: usize,
+        tokens: usize,
+    },
+    Overflows {
+        tokens: usize,
+    },
+    /// The conversation was past the automatic compaction threshold, so compacting it was
+    /// attempted; `tokens` is the size of the request before compacting it
+    Compacted {
+        percent: usize,
+        tokens: usize,
+        result: anyhow::Result<Compaction>,
+    },
+}
+
+/// Counts the request's tokens and checks them against the context window; if they take up
+/// `auto_compact` percent of it or more, the conversation is compacted
+pub(super) fn check_context(
+    provider: &dyn Provider,
+    request: &ModelRequest,
+    context_window: usize,
+    auto_compact: Option<usize>,
+) -> Result<ContextCheck, ProviderError> {
+    let tokens = provider.count_tokens(request)?;
+
+    if auto_compact.is_some_and(|threshold| tokens * 100 >= threshold * context_window) {
+        return Ok(ContextCheck::Compacted {
+            percent: tokens * 100 / context_window.max(1),
+            tokens,
+            result: compact(provider, request, None),
+        });
+    }
+
+    Ok(match get_context_usage(tokens, context_window) {
+        ContextUsage::Fits => ContextCheck::Fits,
+        ContextUsage::NearlyFull { percent } => ContextCheck::NearlyFull { percent, tokens },
+        ContextUsage::Overflows => ContextCheck::Overflows { tokens },
+    })
+}
+
+fn get_context_usage(tokens: usize, context_window: usize) -> ContextUsage {
+    if tokens > context_window {
+        return ContextUsage::Overflows;
+    }
+
+    let percent = tokens * 100 / context_window.max(1);
+    if percent >= WARNING_THRESHOLD_PERCENT {
+        ContextUsage::NearlyFull { percent }
+    } else {
+        ContextUsage::Fits
+    }
+}
+
+/// Estimates the number of input tokens of a request (contents, system prompt and tools) from
+/// its size, for providers that can't count them
+pub(super) fn estimate_tokens(request: &ModelRequest) -> usize {
+    let mut chars = request.system_prompt.len();
+    chars += serde_json::to_string(request.tools).map_or(0, |t| t.len());
+
+    let mut attachments = 0;
+    for part in request.contents.iter().flat_map(|c| &c.parts) {
+        match part {
+            Part::Text(t) => chars += t.len(),
+            Part::Thought(t) => chars += t.text.len(),
+            Part::InlineData(_) | Part::FileData(_) => attachments += 1,
+            part => chars += serde_json::to_string(part).map_or(0, |p| p.len()),
+        }
+    }
+
+    chars.div_ceil(CHARS_PER_TOKEN) + attachments * TOKENS_PER_ATTACHMENT
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::agent::domain::{Blob, Content, Role};
+    use crate::agent::generation::GenerationSettings;
+
+    //-------------//
+    //  SUCCESSES  //
+    //-------------//
+
+    #[test]
+    fn tokens_are_estimated_from_text_and_attachments() {
+        // GIVEN
+        let contents = vec![Content {
+            parts: vec![
+                Part::Text("what's in this picture?".to_string()),
+                Part::InlineData(Blob {
+                    mime_type: "image/png".to_string(),
+                    data: "iVBORw0KGgo".repeat(1000),
+                }),
+            ],
+            role: Some(Role::User),
+        }];
+        let request = ModelRequest {
+            system_prompt: "You are a helpful assistant.",
+            contents: &contents,
+            tools: &[],
+            settings: &GenerationSettings::default(),
+        };
+
+        // WHEN
+        let tokens = estimate_tokens(&request);
+
+        // THEN
+        assert_eq!(tokens, 1014);
+    }
+
+    #[test]
+    fn requests_close_to_the_context_window_are_flagged() {
+        // GIVEN
+        // WHEN
+        let usages = [
+            get_context_usage(1000, 10_000),
+            get_context_usage(8500, 10_000),
+            get_context_usage(10_001, 10_000),
+        ];
+
+        // THEN
+        assert_eq!(
+            usages,
+            [
+                ContextUsage::Fits,
+                ContextUsage::NearlyFull { percent: 85 },
+                ContextUsage::Overflows,
+            ]
+        );
+    }
+}
@@ -4,67 +4,105 @@ use schemars::JsonSchema;
 use serde::{Deserialize, Serialize};
 use serde_json::Value;
 
-#[derive(Debug, Serialize)]
-pub(super) struct GenerateContentBody {
-    pub(super) contents: Vec<Content>,
-    pub(super) system_instruction: SystemInstruction,
-    pub(super) tools: Vec<Tool>,
-}
-
-#[derive(Debug, Serialize, Deserialize)]
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub(super) struct Content {
     pub(super) parts: Vec<Part>,
     pub(super) role: Option<Role>,
 }
 
-#[derive(Debug, Serialize, Deserialize)]
-pub(super) struct SystemInstruction {
-    pub(super) parts: Vec<Part>,
-}
-
-#[derive(Debug, Serialize, Deserialize)]
+#[derive(Debug, Clone, Serialize, Deserialize)]
 #[serde(rename_all = "camelCase")]
 pub(super) enum Part {
     Text(String),
+    Thought(Thought),
     FunctionCall(FunctionCall),
     FunctionResponse(FunctionResponse),
+    InlineData(Blob),
+    FileData(FileData),
+    ExecutableCode(ExecutableCode),
+    CodeExecutionResult(CodeExecutionResult),
+    /// A part agent1 doesn't know about, kept as the provider sent it so that it can be sent back
+    Unknown(Value),
 }
 
-#[derive(Debug, Serialize, Deserialize)]
-#[serde(rename_all = "lowercase")]
-pub(super) enum Role {
-    User,
-    Model,
+/// The model's reasoning (or a summary of it), as returned by thinking models
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub(super) struct Thought {
+    pub(super) text: String,
+    /// Opaque signature that has to be sent back along with the thought on later turns
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub(super) signature: Option<String>,
+    /// Whether the provider encrypted the thought (anthropic's redacted thinking), in which case
+    /// there's no text, and `signature` holds the encrypted thought
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub(super) redacted: bool,
 }
 
-#[derive(Debug, Deserialize)]
+/// Raw bytes of a file (eg. an image), base64 encoded
+#[derive(Debug, Clone, Serialize, Deserialize)]
 #[serde(rename_all = "camelCase")]
-pub(super) struct GenerateContentResponse {
-    pub(super) candidates: Vec<Candidate>,
-    pub(super) usage_metadata: UsageMetadata,
+pub(super) struct Blob {
+    pub(super) mime_type: String,
+    pub(super) data: String,
 }
 
-#[derive(Debug, Deserialize)]
+/// A file referenced by URI, eg. one uploaded via gemini's files API
+#[derive(Debug, Clone, Serialize, Deserialize)]
 #[serde(rename_all = "camelCase")]
-#[allow(unused)]
-pub(super) struct Candidate {
-    pub(super) content: Content,
-    pub(super) finish_reason: String,
+pub(super) struct FileData {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub(super) mime_type: Option<String>,
+    pub(super) file_uri: String,
 }
 
-#[derive(Debug, Deserialize)]
+/// Code generated by the model to be run by the provider's code execution tool
+#[derive(Debug, Clone, Serialize, Deserialize)]
 #[serde(rename_all = "camelCase")]
-#[allow(unused)]
-pub(super) struct UsageMetadata {
-    pub(super) prompt_token_count: usize,
-    pub(super) candidates_token_count: usize,
-    pub(super) total_token_count: usize,
+pub(super) struct ExecutableCode {
+    #[serde(default)]
+    pub(super) language: String,
+    pub(super) code: String,
 }
 
-#[derive(Debug, Serialize)]
+/// Result of running `ExecutableCode`
+#[derive(Debug, Clone, Serialize, Deserialize)]
 #[serde(rename_all = "camelCase")]
-pub(super) enum Tool {
-    FunctionDeclarations(Vec<FunctionDeclaration>),
+pub(super) struct CodeExecutionResult {
+    #[serde(default)]
+    pub(super) outcome: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub(super) output: Option<String>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub(super) enum Role {
+    User,
+    Model,
+}
+
+/// Tokens used by a single request
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub(super) struct Usage {
+    /// Input tokens, including cached ones
+    pub(super) prompt_tokens: usize,
+    /// Input tokens read from the provider's cache, which are billed at a lower rate
+    pub(super) cached_tokens: usize,
+    /// Generated tokens, excluding thinking tokens (for providers that report them separately)
+    pub(super) output_tokens: usize,
+    pub(super) thinking_tokens: usize,
+    pub(super) total_tokens: usize,
+}
+
+impl std::ops::AddAssign for Usage {
+    fn add_assign(&mut self, other: Self) {
+        self.prompt_tokens += other.prompt_tokens;
+        self.cached_tokens += other.cached_tokens;
+        self.output_tokens += other.output_tokens;
+        self.thinking_tokens += other.thinking_tokens;
+        self.total_tokens += other.total_tokens;
+    }
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -128,15 +166,28 @@ pub(super) struct RunCmdArgs {
     pub(super) cmd: String,
 }
 
-#[derive(Debug, Serialize, Deserialize)]
+#[derive(Debug, Clone, Serialize, Deserialize)]
 #[serde(rename_all = "camelCase")]
 pub(super) struct FunctionCall {
     pub(super) id: Option<String>,
     pub(super) name: FunctionDeclarationName,
     pub(super) args: Option<Value>,
+    /// Signature of the thinking that led to the call; gemini requires it to be sent back on
+    /// later turns for function calling to keep working
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub(super) thought_signature: Option<String>,
 }
 
-#[derive(Debug, Serialize, Deserialize)]
+impl FunctionCall {
+    /// Returns the path or command the call is about, if any
+    pub(super) fn target(&self) -> Option<&str> {
+        let args = self.args.as_ref()?;
+
+        args.get("path").or_else(|| args.get("cmd"))?.as_str()
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
 #[serde(rename_all = "camelCase")]
 pub(super) struct FunctionResponse {
     #[serde(skip_serializing_if = "Option::is_none")]
@@ -146,7 +197,7 @@ pub(super) struct FunctionResponse {
     pub(super) will_continue: Option<bool>,
 }
 
-#[derive(Debug, Serialize, Deserialize)]
+#[derive(Debug, Clone, Serialize, Deserialize)]
 #[serde(rename_all = "lowercase")]
 pub(super) enum FunctionCallResponse {
     Output(String),
@@ -0,0 +1,218 @@
+use super::domain::{
+    Content, FunctionCall, FunctionCallResponse, FunctionDeclarationName, Part, Role,
+};
+
+const DEFAULT_MAX_AGE_TURNS: usize = 20;
+const DEFAULT_MAX_OUTPUT_BYTES: usize = 32 * 1024;
+
+/// When to replace function outputs in the conversation with a placeholder, so that they aren't
+/// re-sent with every request.
+///
+/// Outputs are only ever elided after the model has responded to them.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(super) struct ElisionPolicy {
+    /// Number of model turns after which outputs are elided; 0 disables the rule
+    max_age_turns: usize,
+    /// Size in bytes above which outputs are elided; 0 disables the rule
+    max_output_bytes: usize,
+}
+
+impl ElisionPolicy {
+    /// Unset values fall back to the defaults
+    pub(super) fn new(max_age_turns: Option<usize>, max_output_bytes: Option<usize>) -> Self {
+        Self {
+            max_age_turns: max_age_turns.unwrap_or(DEFAULT_MAX_AGE_TURNS),
+            max_output_bytes: max_output_bytes.unwrap_or(DEFAULT_MAX_OUTPUT_BYTES),
+        }
+    }
+
+    fn should_elide(&self, age: usize, size: usize) -> bool {
+        (self.max_age_turns > 0 && age > self.max_age_turns)
+            || (self.max_output_bytes > 0 && size > self.max_output_bytes)
+    }
+}
+
+/// Replaces stale function outputs (and files returned along with them) with placeholders that
+/// tell the model how to get them back, and returns the number of outputs that were elided.
+///
+/// Function responses themselves are kept, so that every call is still paired with a response.
+pub(super) fn elide_stale_outputs(contents: &mut [Content], policy: &ElisionPolicy) -> usize {
+    let mut elided = 0;
+    let mut age = 0;
+
+    for i in (1..contents.len()).rev() {
+        if contents[i].role == Some(Role::Model) {
+            age += 1;
+            continue;
+        }
+        if age == 0 {
+            continue;
+        }
+
+        let calls = contents[i - 1]
+            .parts
+            .iter()
+            .filter_map(|part| match part {
+                Part::FunctionCall(call) => Some(call.clone()),
+                _ => None,
+            })
+            .collect::<Vec<_>>();
+
+        let mut response_index = 0;
+        for part in &mut contents[i].parts {
+            match part {
+                Part::FunctionResponse(resp) => {
+                    let call = calls
+                        .iter()
+                        .find(|c| c.id.is_some() && c.id == resp.id)
+                        .or_else(|| calls.get(response_index));
+                    response_index += 1;
+
+                    if let FunctionCallResponse::Output(output) = &resp.response
+                        && policy.should_elide(age, output.len())
+                    {
+                        let placeholder = get_placeholder(&resp.name, call);
+                        if *output != placeholder {
+                            resp.response = FunctionCallResponse::Output(placeholder);
+                            elided += 1;
+                        }
+                    }
+                }
+                // files returned by read_file
+                Part::InlineData(blob)
+                    if response_index > 0 && policy.should_elide(age, blob.data.len()) =>
+                {
+                    *part = Part::Text(format!(
+                        "[{} file elided; re-read it if needed]",
+                        blob.mime_type
+                    ));
+                    elided += 1;
+                }
+                _ => {}
+            }
+        }
+    }
+
+    elided
+}
+
+/// Describes the call whose output was elided, eg. "[output of read_file src/x.rs elided; re-read
+/// if needed]"
+fn get_placeholder(name: &FunctionDeclarationName, call: Option<&FunctionCall>) -> String {
+    let target = call
+        .and_then(|c| c.target())
+        .map(|target| format!(" {target}"))
+        .unwrap_or_default();
+
+    let action = match name {
+        FunctionDeclarationName::RunCmd => "re-run",
+        _ => "re-read",
+    };
+
+    format!("[output of {name}{target} elided; {action} if needed]")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::agent::domain::FunctionResponse;
+    use insta::assert_snapshot;
+    use serde_json::json;
+
+    fn call_and_response(
+        name: FunctionDeclarationName,
+        args: serde_json::Value,
+        output: &str,
+    ) -> [Content; 2] {
+        [
+            Content {
+                parts: vec![Part::FunctionCall(FunctionCall {
+                    id: None,
+                    name: name.clone(),
+                    args: Some(args),
+                    thought_signature: None,
+                })],
+                role: Some(Role::Model),
+            },
+            Content {
+                parts: vec![Part::FunctionResponse(FunctionResponse {
+                    id: None,
+                    name,
+                    response: FunctionCallResponse::Output(output.to_string()),
+                    will_continue: None,
+                })],
+                role: Some(Role::User),
+            },
+        ]
+    }
+
+    fn outputs(contents: &[Content]) -> String {
+        contents
+            .iter()
+            .flat_map(|c| &c.parts)
+            .filter_map(|part| match part {
+                Part::FunctionResponse(FunctionResponse {
+                    response: FunctionCallResponse::Output(o),
+                    ..
+                }) => Some(o.as_str()),
+                _ => None,
+            })
+            .collect::<Vec<_>>()
+            .join("\n")
+    }
+
+    //-------------//
+    //  SUCCESSES  //
+    //-------------//
+
+    #[test]
+    fn old_and_large_outputs_are_elided() {
+        // GIVEN
+        let mut contents = vec![];
+        contents.extend(call_and_response(
+            FunctionDeclarationName::RunCmd,
+            json!({"cmd": "cargo test"}),
+            "test result: ok",
+        ));
+        contents.extend(call_and_response(
+            FunctionDeclarationName::ReadFile,
+            json!({"path": "src/main.rs"}),
+            &"fn main() {}\n".repeat(100),
+        ));
+        contents.extend(call_and_response(
+            FunctionDeclarationName::ListFiles,
+            json!({"path": "src"}),
+            "main.rs",
+        ));
+        let policy = ElisionPolicy::new(Some(1), Some(1000));
+
+        // WHEN
+        let elided = elide_stale_outputs(&mut contents, &policy);
+
+        // THEN
+        assert_eq!(elided, 2);
+        assert_snapshot!(outputs(&contents), @r#"
+        [output of run_command cargo test elided; re-run if needed]
+        [output of read_file src/main.rs elided; re-read if needed]
+        main.rs
+        "#);
+    }
+
+    #[test]
+    fn outputs_the_model_hasnt_seen_yet_are_kept() {
+        // GIVEN
+        let mut contents = call_and_response(
+            FunctionDeclarationName::ReadFile,
+            json!({"path": "src/main.rs"}),
+            &"fn main() {}\n".repeat(100),
+        )
+        .to_vec();
+        let policy = ElisionPolicy::new(Some(1), Some(10));
+
+        // WHEN
+        let elided = elide_stale_outputs(&mut contents, &policy);
+
+        // THEN
+        assert_eq!(elided, 0);
+    }
+}
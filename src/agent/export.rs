@@ -0,0 +1,549 @@
+use super::compaction::SUMMARY_HEADER;
+use super::domain::{
+    Content, FunctionCall, FunctionCallResponse, FunctionResponse, Part, Role, Thought,
+};
+use super::run::{CONTINUE_PROMPT, MALFORMED_CALL_PROMPT};
+use super::sessions::{SessionMeta, get_sessions_dir, load_session};
+use anyhow::Context;
+use clap::ValueEnum;
+use std::path::Path;
+
+const HTML_STYLE: &str = "body { max-width: 56rem; margin: 2rem auto; padding: 0 1rem; font-family: system-ui, sans-serif; line-height: 1.5; color: #1f2328; }
+h2 { margin-top: 2rem; padding-bottom: 0.25rem; border-bottom: 1px solid #d0d7de; font-size: 1.1rem; }
+.meta { color: #59636e; }
+.text { white-space: pre-wrap; }
+pre { padding: 0.75rem; overflow-x: auto; background: #f6f8fa; border-radius: 6px; }
+details { margin: 0.5rem 0; padding: 0.25rem 0.75rem; border: 1px solid #d0d7de; border-radius: 6px; }
+summary { cursor: pointer; font-family: ui-monospace, monospace; }
+details.error { border-color: #cf222e; }
+details.thought { color: #59636e; }
+img { max-width: 100%; }";
+
+/// Format of exported session transcripts
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum ExportFormat {
+    Markdown,
+    Html,
+}
+
+impl ExportFormat {
+    /// Picks the format from the extension of the file the transcript is written to, defaulting to
+    /// Markdown
+    pub(super) fn from_path(path: &Path) -> Self {
+        match path.extension().and_then(|ext| ext.to_str()) {
+            Some("html" | "htm") => ExportFormat::Html,
+            _ => ExportFormat::Markdown,
+        }
+    }
+
+    fn extension(self) -> &'static str {
+        match self {
+            ExportFormat::Markdown => "md",
+            ExportFormat::Html => "html",
+        }
+    }
+}
+
+/// Who a part of the transcript is shown as coming from
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Speaker {
+    User,
+    Model,
+    /// Messages agent1 sent on the user's behalf, eg. conversation summaries
+    Agent,
+}
+
+/// A run of turns shown under a single heading; function responses are shown along with the model
+/// turns that called them
+struct Section<'a> {
+    speaker: Speaker,
+    parts: Vec<&'a Part>,
+}
+
+/// Exports a saved session, to `output` if set and to stdout otherwise
+pub fn export_session(
+    id_or_prefix: &str,
+    output: Option<&Path>,
+    format: Option<ExportFormat>,
+) -> anyhow::Result<()> {
+    let sessions_dir = get_sessions_dir().context("couldn't determine sessions directory")?;
+    let (session, contents) = load_session(&sessions_dir, id_or_prefix)?;
+
+    let format = format
+        .or_else(|| output.map(ExportFormat::from_path))
+        .unwrap_or(ExportFormat::Markdown);
+    let transcript = render(&session.meta, &contents, format);
+
+    match output {
+        Some(path) => std::fs::write(path, transcript)
+            .with_context(|| format!("couldn't write {}", path.to_string_lossy()))?,
+        None => print!("{transcript}"),
+    }
+
+    Ok(())
+}
+
+/// Returns the default file name for exports of the given session, eg. "agent1-6712f3a41c.md"
+pub(super) fn get_export_file_name(meta: &SessionMeta, format: ExportFormat) -> String {
+    format!("agent1-{}.{}", meta.id, format.extension())
+}
+
+pub(super) fn render(meta: &SessionMeta, contents: &[Content], format: ExportFormat) -> String {
+    let sections = get_sections(contents);
+
+    match format {
+        ExportFormat::Markdown => render_markdown(meta, &sections),
+        ExportFormat::Html => render_html(meta, &sections),
+    }
+}
+
+fn get_sections(contents: &[Content]) -> Vec<Section<'_>> {
+    let mut sections: Vec<Section> = vec![];
+
+    for content in contents {
+        let is_function_response = content
+            .parts
+            .iter()
+            .any(|part| matches!(part, Part::FunctionResponse(_)));
+
+        for part in &content.parts {
+            let speaker = match (content.role, part) {
+                (Some(Role::User), _) if is_function_response => Speaker::Model,
+                // continued responses are shown as one
+                (Some(Role::User), Part::Text(text)) if text == CONTINUE_PROMPT => continue,
+                (Some(Role::User), Part::Text(text))
+                    if text == MALFORMED_CALL_PROMPT || text.starts_with(SUMMARY_HEADER) =>
+                {
+                    Speaker::Agent
+                }
+                (Some(Role::User), _) => Speaker::User,
+                _ => Speaker::Model,
+            };
+
+            match sections.last_mut() {
+                Some(section) if section.speaker == speaker => section.parts.push(part),
+                _ => sections.push(Section {
+                    speaker,
+                    parts: vec![part],
+                }),
+            }
+        }
+    }
+
+    sections
+}
+
+fn get_title(meta: &SessionMeta) -> &str {
+    if meta.title.is_empty() {
+        "agent1 session"
+    } else {
+        &meta.title
+    }
+}
+
+fn get_heading(meta: &SessionMeta, speaker: Speaker) -> &str {
+    match speaker {
+        Speaker::User => "You",
+        Speaker::Model => &meta.model,
+        Speaker::Agent => "agent1",
+    }
+}
+
+/// Returns the text of a thought; encrypted thoughts have none to show
+fn get_thought_text(thought: &Thought) -> &str {
+    if thought.redacted {
+        "[redacted]"
+    } else {
+        thought.text.trim()
+    }
+}
+
+/// Describes a function call, eg. "read_file src/main.rs"
+fn describe_call(call: &FunctionCall) -> String {
+    match call.target() {
+        Some(target) => format!("{} {target}", call.name),
+        None => call.name.to_string(),
+    }
+}
+
+fn get_pretty_args(call: &FunctionCall) -> String {
+    call.args
+        .as_ref()
+        .and_then(|args| serde_json::to_string_pretty(args).ok())
+        .unwrap_or_else(|| "{}".to_string())
+}
+
+fn render_markdown(meta: &SessionMeta, sections: &[Section]) -> String {
+    let mut md = format!(
+        "# {}\n\nSession `{}` with `{}`, {} turns\n",
+        get_title(meta),
+        meta.id,
+        meta.model,
+        meta.turns
+    );
+
+    for section in sections {
+        md.push_str(&format!("\n## {}\n", get_heading(meta, section.speaker)));
+
+        for part in &section.parts {
+            md.push('\n');
+            match part {
+                Part::Text(text) => md.push_str(&format!("{}\n", text.trim())),
+                Part::Thought(thought) => {
+                    md.push_str("> *Thinking*\n>\n");
+                    for line in get_thought_text(thought).lines() {
+                        md.push_str(format!("> {line}").trim_end());
+                        md.push('\n');
+                    }
+                }
+                Part::FunctionCall(call) => {
+                    md.push_str(&format!("**Call:** `{}`\n\n", describe_call(call)));
+                    md.push_str(&fence(&get_pretty_args(call), "json"));
+                }
+                Part::FunctionResponse(FunctionResponse { name, response, .. }) => {
+                    let (label, text) = match response {
+                        FunctionCallResponse::Output(output) => ("Output of", output),
+                        FunctionCallResponse::Error(error) => ("Error from", error),
+                    };
+                    md.push_str(&format!("**{label} `{name}`:**\n\n"));
+                    md.push_str(&fence(text, ""));
+                }
+                Part::InlineData(blob) => {
+                    md.push_str(&format!("*[{} attachment]*\n", blob.mime_type))
+                }
+                Part::FileData(file) => md.push_str(&format!("*[file: {}]*\n", file.file_uri)),
+                Part::ExecutableCode(code) => {
+                    md.push_str(&fence(&code.code, &code.language.to_lowercase()))
+                }
+                Part::CodeExecutionResult(result) => {
+                    md.push_str(&fence(result.output.as_deref().unwrap_or_default(), ""))
+                }
+                Part::Unknown(_) => md.push_str("*[unsupported part]*\n"),
+            }
+        }
+    }
+
+    md
+}
+
+/// Wraps text in a code fence that's longer than any run of backticks in it
+fn fence(text: &str, language: &str) -> String {
+    let mut longest_run = 0;
+    let mut run = 0;
+    for c in text.chars() {
+        run = if c == '`' { run + 1 } else { 0 };
+        longest_run = longest_run.max(run);
+    }
+
+    let fence = "`".repeat(longest_run.max(2) + 1);
+    format!("{fence}{language}\n{}\n{fence}\n", text.trim_end())
+}
+
+fn render_html(meta: &SessionMeta, sections: &[Section]) -> String {
+    let title = escape_html(get_title(meta));
+    let mut html = format!(
+        "<!DOCTYPE html>\n<html lang=\"en\">\n<head>\n<meta charset=\"utf-8\">\n<title>{title}</title>\n<style>\n{HTML_STYLE}\n</style>\n</head>\n<body>\n<h1>{title}</h1>\n<p class=\"meta\">Session <code>{}</code> with <code>{}</code>, {} turns</p>\n",
+        escape_html(&meta.id),
+        escape_html(&meta.model),
+        meta.turns
+    );
+
+    for section in sections {
+        html.push_str(&format!(
+            "<h2>{}</h2>\n",
+            escape_html(get_heading(meta, section.speaker))
+        ));
+
+        for part in &section.parts {
+            let element = match part {
+                Part::Text(text) => {
+                    format!("<div class=\"text\">{}</div>", escape_html(text.trim()))
+                }
+                Part::Thought(thought) => details(
+                    "thought",
+                    "Thinking",
+                    &format!(
+                        "<div class=\"text\">{}</div>",
+                        escape_html(get_thought_text(thought))
+                    ),
+                ),
+                Part::FunctionCall(call) => details(
+                    "call",
+                    &format!("Call: {}", describe_call(call)),
+                    &pre(&get_pretty_args(call)),
+                ),
+                Part::FunctionResponse(FunctionResponse { name, response, .. }) => match response {
+                    FunctionCallResponse::Output(output) => {
+                        details("output", &format!("Output of {name}"), &pre(output))
+                    }
+                    FunctionCallResponse::Error(error) => {
+                        details("error", &format!("Error from {name}"), &pre(error))
+                    }
+                },
+                Part::InlineData(blob) if blob.mime_type.starts_with("image/") => format!(
+                    "<img src=\"data:{};base64,{}\" alt=\"attached image\">",
+                    escape_html(&blob.mime_type),
+                    blob.data
+                ),
+                Part::InlineData(blob) => format!(
+                    "<p><em>[{} attachment]</em></p>",
+                    escape_html(&blob.mime_type)
+                ),
+                Part::FileData(file) => {
+                    format!("<p><em>[file: {}]</em></p>", escape_html(&file.file_uri))
+                }
+                Part::ExecutableCode(code) => pre(&code.code),
+                Part::CodeExecutionResult(result) => {
+                    pre(result.output.as_deref().unwrap_or_default())
+                }
+                Part::Unknown(_) => "<p><em>[unsupported part]</em></p>".to_string(),
+            };
+            html.push_str(&element);
+            html.push('\n');
+        }
+    }
+
+    html.push_str("</body>\n</html>\n");
+    html
+}
+
+fn details(class: &str, summary: &str, body: &str) -> String {
+    format!(
+        "<details class=\"{class}\"><summary>{}</summary>{body}</details>",
+        escape_html(summary)
+    )
+}
+
+fn pre(text: &str) -> String {
+    format!("<pre>{}</pre>", escape_html(text.trim_end()))
+}
+
+fn escape_html(text: &str) -> String {
+    text.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::agent::domain::FunctionDeclarationName;
+    use crate::agent::providers::ProviderKind;
+    use insta::assert_snapshot;
+    use serde_json::json;
+
+    fn session_meta() -> SessionMeta {
+        SessionMeta {
+            id: "6712f3a41c".to_string(),
+            created_at: 0,
+            updated_at: 0,
+            cwd: "/tmp".into(),
+            provider: ProviderKind::Gemini,
+            model: "gemini-2.5-flash".to_string(),
+            title: "which edition is this?".to_string(),
+            turns: 4,
+            parent: None,
+            name: None,
+        }
+    }
+
+    fn conversation() -> Vec<Content> {
+        vec![
+            Content {
+                parts: vec![Part::Text("which edition is this?".to_string())],
+                role: Some(Role::User),
+            },
+            Content {
+                parts: vec![
+                    Part::Thought(Thought {
+                        text: "the manifest has it".to_string(),
+                        signature: None,
+                        redacted: false,
+                    }),
+                    Part::FunctionCall(FunctionCall {
+                        id: None,
+                        name: FunctionDeclarationName::ReadFile,
+                        args: Some(json!({"path": "Cargo.toml"})),
+                        thought_signature: None,
+                    }),
+                ],
+                role: Some(Role::Model),
+            },
+            Content {
+                parts: vec![Part::FunctionResponse(FunctionResponse {
+                    id: None,
+                    name: FunctionDeclarationName::ReadFile,
+                    response: FunctionCallResponse::Output(
+                        "[package]\nedition = \"2024\"".to_string(),
+                    ),
+                    will_continue: None,
+                })],
+                role: Some(Role::User),
+            },
+            Content {
+                parts: vec![Part::Text("It's the `2024` edition.".to_string())],
+                role: Some(Role::Model),
+            },
+        ]
+    }
+
+    //-------------//
+    //  SUCCESSES  //
+    //-------------//
+
+    #[test]
+    fn sessions_are_rendered_as_markdown() {
+        // GIVEN
+        let meta = session_meta();
+        let contents = conversation();
+
+        // WHEN
+        let md = render(&meta, &contents, ExportFormat::Markdown);
+
+        // THEN
+        assert_snapshot!(md, @r#"
+        # which edition is this?
+
+        Session `6712f3a41c` with `gemini-2.5-flash`, 4 turns
+
+        ## You
+
+        which edition is this?
+
+        ## gemini-2.5-flash
+
+        > *Thinking*
+        >
+        > the manifest has it
+
+        **Call:** `read_file Cargo.toml`
+
+        ```json
+        {
+          "path": "Cargo.toml"
+        }
+        ```
+
+        **Output of `read_file`:**
+
+        ```
+        [package]
+        edition = "2024"
+        ```
+
+        It's the `2024` edition.
+        "#);
+    }
+
+    #[test]
+    fn function_calls_are_collapsible_in_html() {
+        // GIVEN
+        let meta = session_meta();
+        let contents = conversation();
+
+        // WHEN
+        let html = render(&meta, &contents, ExportFormat::Html);
+
+        // THEN
+        let body = html
+            .lines()
+            .skip_while(|line| *line != "<body>")
+            .collect::<Vec<_>>()
+            .join("\n");
+        assert_snapshot!(body, @r#"
+        <body>
+        <h1>which edition is this?</h1>
+        <p class="meta">Session <code>6712f3a41c</code> with <code>gemini-2.5-flash</code>, 4 turns</p>
+        <h2>You</h2>
+        <div class="text">which edition is this?</div>
+        <h2>gemini-2.5-flash</h2>
+        <details class="thought"><summary>Thinking</summary><div class="text">the manifest has it</div></details>
+        <details class="call"><summary>Call: read_file Cargo.toml</summary><pre>{
+          &quot;path&quot;: &quot;Cargo.toml&quot;
+        }</pre></details>
+        <details class="output"><summary>Output of read_file</summary><pre>[package]
+        edition = &quot;2024&quot;</pre></details>
+        <div class="text">It's the `2024` edition.</div>
+        </body>
+        </html>
+        "#);
+    }
+
+    #[test]
+    fn messages_sent_by_agent1_are_not_shown_as_the_users() {
+        // GIVEN
+        let meta = session_meta();
+        let text = |text: &str| Part::Text(text.to_string());
+        let contents = vec![
+            Content {
+                parts: vec![
+                    text(&format!(
+                        "{SUMMARY_HEADER}\n\nthe user asked which edition this is"
+                    )),
+                    text("and which version?"),
+                ],
+                role: Some(Role::User),
+            },
+            Content {
+                parts: vec![text("It's version")],
+                role: Some(Role::Model),
+            },
+            Content {
+                parts: vec![text(CONTINUE_PROMPT)],
+                role: Some(Role::User),
+            },
+            Content {
+                parts: vec![text("0.1.0.")],
+                role: Some(Role::Model),
+            },
+            Content {
+                parts: vec![text(MALFORMED_CALL_PROMPT)],
+                role: Some(Role::User),
+            },
+        ];
+
+        // WHEN
+        let md = render(&meta, &contents, ExportFormat::Markdown);
+
+        // THEN
+        assert_snapshot!(md, @"
+        # which edition is this?
+
+        Session `6712f3a41c` with `gemini-2.5-flash`, 4 turns
+
+        ## agent1
+
+        Summary of the earlier conversation:
+
+        the user asked which edition this is
+
+        ## You
+
+        and which version?
+
+        ## gemini-2.5-flash
+
+        It's version
+
+        0.1.0.
+
+        ## agent1
+
+        Your last function call was malformed and couldn't be parsed. Call the function again, with arguments that are valid JSON and match the function's declared parameters.
+        ");
+    }
+
+    #[test]
+    fn fences_are_longer_than_backtick_runs_in_the_text() {
+        // GIVEN
+        let text = "```rust\nfn main() {}\n```";
+
+        // WHEN
+        let fenced = fence(text, "");
+
+        // THEN
+        assert!(fenced.starts_with("````\n"));
+        assert!(fenced.ends_with("\n````\n"));
+    }
+}
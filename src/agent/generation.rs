@@ -0,0 +1,234 @@
+use serde::Deserialize;
+use std::collections::BTreeMap;
+
+/// Settings that control how the model generates its responses.
+///
+/// Unset values fall back to the provider's defaults; settings a provider has no equivalent for
+/// are ignored.
+///
+/// ```toml
+/// [generation]
+/// temperature = 0.2
+/// max_output_tokens = 4096
+/// safety_settings = { HARM_CATEGORY_DANGEROUS_CONTENT = "BLOCK_ONLY_HIGH" }
+/// ```
+#[derive(Debug, Default, Clone, PartialEq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct GenerationSettings {
+    pub temperature: Option<f64>,
+    pub top_p: Option<f64>,
+    pub max_output_tokens: Option<u32>,
+    #[serde(default)]
+    pub stop_sequences: Vec<String>,
+    pub seed: Option<i64>,
+    /// Tokens the model may spend on thinking before answering; for gemini, 0 disables thinking
+    /// and -1 lets the model decide
+    pub thinking_budget: Option<i32>,
+    /// Whether to ask the model for (summaries of) its thoughts; for other providers than gemini
+    /// and ollama, thoughts are returned whenever thinking is enabled
+    pub include_thoughts: Option<bool>,
+    /// Block threshold per harm category (eg. HARM_CATEGORY_HARASSMENT = "BLOCK_NONE"); only
+    /// used by gemini
+    #[serde(default)]
+    pub safety_settings: BTreeMap<String, String>,
+}
+
+const KEYS: [&str; 8] = [
+    "temperature",
+    "top_p",
+    "max_output_tokens",
+    "stop_sequences",
+    "seed",
+    "thinking_budget",
+    "include_thoughts",
+    "safety_settings",
+];
+
+impl GenerationSettings {
+    /// Overrides settings with the ones set in `other`
+    pub fn merge(&mut self, other: GenerationSettings) {
+        if other.temperature.is_some() {
+            self.temperature = other.temperature;
+        }
+        if other.top_p.is_some() {
+            self.top_p = other.top_p;
+        }
+        if other.max_output_tokens.is_some() {
+            self.max_output_tokens = other.max_output_tokens;
+        }
+        if !other.stop_sequences.is_empty() {
+            self.stop_sequences = other.stop_sequences;
+        }
+        if other.seed.is_some() {
+            self.seed = other.seed;
+        }
+        if other.thinking_budget.is_some() {
+            self.thinking_budget = other.thinking_budget;
+        }
+        if other.include_thoughts.is_some() {
+            self.include_thoughts = other.include_thoughts;
+        }
+        self.safety_settings.extend(other.safety_settings);
+    }
+
+    /// Changes a single setting, as done via `/set <key> [value]`; a missing value resets the
+    /// setting to the provider's default.
+    ///
+    /// Stop sequences are given either as a JSON array of strings, or as a single sequence;
+    /// safety settings are given as CATEGORY=THRESHOLD, and are added to the existing ones.
+    pub(super) fn set(&mut self, key: &str, value: Option<&str>) -> anyhow::Result<()> {
+        match (key, value) {
+            ("temperature", v) => self.temperature = v.map(parse_value).transpose()?,
+            ("top_p", v) => self.top_p = v.map(parse_value).transpose()?,
+            ("max_output_tokens", v) => self.max_output_tokens = v.map(parse_value).transpose()?,
+            ("seed", v) => self.seed = v.map(parse_value).transpose()?,
+            ("thinking_budget", v) => self.thinking_budget = v.map(parse_value).transpose()?,
+            ("include_thoughts", v) => self.include_thoughts = v.map(parse_value).transpose()?,
+            ("stop_sequences", None) => self.stop_sequences.clear(),
+            ("stop_sequences", Some(v)) => {
+                self.stop_sequences = match serde_json::from_str::<Vec<String>>(v) {
+                    Ok(sequences) => sequences,
+                    Err(_) => vec![v.to_string()],
+                };
+            }
+            ("safety_settings", None) => self.safety_settings.clear(),
+            ("safety_settings", Some(v)) => {
+                let (category, threshold) = v
+                    .split_once('=')
+                    .ok_or_else(|| anyhow::anyhow!("expected CATEGORY=THRESHOLD"))?;
+                self.safety_settings
+                    .insert(category.trim().to_string(), threshold.trim().to_string());
+            }
+            (key, _) => anyhow::bail!(
+                "unknown setting \"{key}\", expected one of: {}",
+                KEYS.join(", ")
+            ),
+        }
+
+        Ok(())
+    }
+
+    /// Returns a `key = value` line for every setting that's set
+    pub(super) fn describe(&self) -> Vec<String> {
+        let mut lines = vec![];
+        if let Some(v) = self.temperature {
+            lines.push(format!("temperature = {v}"));
+        }
+        if let Some(v) = self.top_p {
+            lines.push(format!("top_p = {v}"));
+        }
+        if let Some(v) = self.max_output_tokens {
+            lines.push(format!("max_output_tokens = {v}"));
+        }
+        if !self.stop_sequences.is_empty() {
+            lines.push(format!("stop_sequences = {:?}", self.stop_sequences));
+        }
+        if let Some(v) = self.seed {
+            lines.push(format!("seed = {v}"));
+        }
+        if let Some(v) = self.thinking_budget {
+            lines.push(format!("thinking_budget = {v}"));
+        }
+        if let Some(v) = self.include_thoughts {
+            lines.push(format!("include_thoughts = {v}"));
+        }
+        for (category, threshold) in &self.safety_settings {
+            lines.push(format!("safety_settings.{category} = {threshold}"));
+        }
+
+        lines
+    }
+}
+
+fn parse_value<T>(value: &str) -> anyhow::Result<T>
+where
+    T: std::str::FromStr,
+    T::Err: std::fmt::Display,
+{
+    value
+        .trim()
+        .parse()
+        .map_err(|e| anyhow::anyhow!("invalid value \"{value}\": {e}"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use insta::assert_snapshot;
+
+    //-------------//
+    //  SUCCESSES  //
+    //-------------//
+
+    #[test]
+    fn settings_can_be_changed_one_at_a_time() {
+        // GIVEN
+        let mut settings = GenerationSettings {
+            seed: Some(7),
+            ..Default::default()
+        };
+
+        // WHEN
+        settings
+            .set("temperature", Some("0.2"))
+            .expect("temperature should've been set");
+        settings
+            .set("stop_sequences", Some(r#"["END", "STOP"]"#))
+            .expect("stop sequences should've been set");
+        settings
+            .set(
+                "safety_settings",
+                Some("HARM_CATEGORY_HARASSMENT=BLOCK_NONE"),
+            )
+            .expect("safety setting should've been set");
+        settings
+            .set("seed", None)
+            .expect("seed should've been reset");
+
+        // THEN
+        assert_snapshot!(settings.describe().join("\n"), @r#"
+        temperature = 0.2
+        stop_sequences = ["END", "STOP"]
+        safety_settings.HARM_CATEGORY_HARASSMENT = BLOCK_NONE
+        "#);
+    }
+
+    #[test]
+    fn merged_settings_take_precedence() {
+        // GIVEN
+        let mut settings = GenerationSettings {
+            temperature: Some(1.0),
+            top_p: Some(0.9),
+            ..Default::default()
+        };
+        let overrides = GenerationSettings {
+            temperature: Some(0.0),
+            ..Default::default()
+        };
+
+        // WHEN
+        settings.merge(overrides);
+
+        // THEN
+        assert_eq!(settings.temperature, Some(0.0));
+        assert_eq!(settings.top_p, Some(0.9));
+    }
+
+    //------------//
+    //  FAILURES  //
+    //------------//
+
+    #[test]
+    fn unknown_settings_are_rejected() {
+        // GIVEN
+        let mut settings = GenerationSettings::default();
+
+        // WHEN
+        let result = settings
+            .set("temprature", Some("0.2"))
+            .expect_err("result wasn't an error");
+
+        // THEN
+        assert_snapshot!(result, @r#"unknown setting "temprature", expected one of: temperature, top_p, max_output_tokens, stop_sequences, seed, thinking_budget, include_thoughts, safety_settings"#);
+    }
+}
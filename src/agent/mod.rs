@@ -1,5 +1,23 @@
+mod attachments;
+mod checkpoints;
+mod compaction;
+mod context;
 mod domain;
+mod elision;
+mod export;
+mod generation;
+mod oneshot;
+mod piped;
+mod providers;
 mod run;
+mod sessions;
 mod tools;
+mod usage;
 
-pub use run::run;
+pub use export::{ExportFormat, export_session};
+pub use generation::GenerationSettings;
+pub use oneshot::run_once;
+pub use piped::read_piped_input;
+pub use providers::{ProviderConfig, ProviderKind};
+pub use run::{RunOptions, run};
+pub use sessions::ResumeTarget;
@@ -0,0 +1,657 @@
+use super::attachments::extract_attachment_paths;
+use super::context::{ContextCheck, check_context};
+use super::domain::*;
+use super::elision::{ElisionPolicy, elide_stale_outputs};
+use super::generation::GenerationSettings;
+use super::piped::{MAX_PIPED_INPUT_BYTES, format_piped_input};
+use super::providers::{FinishReason, ModelRequest, Provider, ProviderConfig, get_provider};
+use super::run::{
+    CONTINUE_PROMPT, MALFORMED_CALL_PROMPT, MAX_AUTO_CONTINUATIONS, MAX_MALFORMED_CALL_CORRECTIONS,
+    RunOptions, SYSTEM_PROMPT, explain_finish_reason, load_attachment_for, push_user_parts,
+};
+use super::sessions::{Session, get_sessions_dir};
+use super::tools::{execute_function_call, get_tools};
+use super::usage::SessionUsage;
+use anyhow::Context;
+use colored::Colorize;
+use reqwest::blocking::Client;
+
+/// Runs the agent on a single prompt, without asking the user for anything, until the model stops
+/// calling functions, and prints its final answer to stdout.
+///
+/// Everything else (function calls, usage, etc.) is printed to stderr, so that the answer can be
+/// piped elsewhere. Function calls that fail are reported to the model, like in the REPL, so that
+/// it can recover; if any did, an error listing them is returned once the answer is printed.
+/// Failed requests, and responses the model didn't finish, are returned as errors right away.
+///
+/// `piped_input` (what was piped to stdin, if anything) is sent along with the prompt.
+pub fn run_once(
+    client: Client,
+    provider_config: ProviderConfig,
+    options: RunOptions,
+    prompt: &str,
+    piped_input: Option<&str>,
+) -> anyhow::Result<()> {
+    let kind = provider_config.kind;
+    let provider = get_provider(provider_config, client)?;
+
+    let mut session_usage = SessionUsage::default();
+    let mut parts = vec![Part::Text(prompt.to_string())];
+    if let Some(input) = piped_input.filter(|input| !input.trim().is_empty()) {
+        if input.len() > MAX_PIPED_INPUT_BYTES {
+            eprintln!(
+                "{}",
+                format!(
+                    "stdin is {} bytes long; only its start and end are sent ({MAX_PIPED_INPUT_BYTES} bytes in total)",
+                    input.len()
+                )
+                .dimmed()
+            );
+        }
+        parts.push(Part::Text(format_piped_input(input, MAX_PIPED_INPUT_BYTES)));
+    }
+    for path in extract_attachment_paths(prompt) {
+        // the reference stays in the prompt as text, so the model can still read the file itself
+        match load_attachment_for(provider.as_ref(), path) {
+            Ok(blob) => {
+                eprintln!(
+                    "{}",
+                    format!("sending {path} ({})", blob.mime_type).dimmed()
+                );
+                parts.push(Part::InlineData(blob));
+            }
+            Err(e) => eprintln!("{}", format!("{path} wasn't attached: {e:#}").yellow()),
+        }
+    }
+
+    let outcome = run_agent(provider.as_ref(), &options, parts, &mut session_usage)?;
+    println!("{}", outcome.answer);
+
+    for line in session_usage.summary() {
+        eprintln!("{}", line.dimmed());
+    }
+    let sessions_dir = get_sessions_dir().context("couldn't determine sessions directory")?;
+    let mut session = Session::new(sessions_dir, kind, provider.model());
+    match session.save(&outcome.contents, provider.model()) {
+        Ok(()) => eprintln!(
+            "{}",
+            format!(
+                "session saved; resume it with agent1 --resume {}",
+                session.meta.id
+            )
+            .dimmed()
+        ),
+        Err(e) => eprintln!("{}", format!("couldn't save session: {e:#}").yellow()),
+    }
+
+    if !outcome.failed_calls.is_empty() {
+        anyhow::bail!(
+            "{} function calls failed:\n{}",
+            outcome.failed_calls.len(),
+            outcome.failed_calls.join("\n")
+        );
+    }
+
+    Ok(())
+}
+
+/// What running the agent on a prompt resulted in
+#[derive(Debug)]
+struct Outcome {
+    /// Text of the model's final response
+    answer: String,
+    contents: Vec<Content>,
+    /// Function calls that failed, eg. "read_file src/x.rs: couldn't read file"
+    failed_calls: Vec<String>,
+}
+
+/// Sends the prompt's parts, and keeps running the function calls the model makes until it
+/// answers without calling any
+fn run_agent(
+    provider: &dyn Provider,
+    options: &RunOptions,
+    parts: Vec<Part>,
+    session_usage: &mut SessionUsage,
+) -> anyhow::Result<Outcome> {
+    let settings = &options.generation_settings;
+    let elision = ElisionPolicy::new(options.elide_after, options.elide_larger_than);
+    let tools = get_tools();
+
+    let mut contents: Vec<Content> = vec![];
+    push_user_parts(&mut contents, parts);
+
+    let mut continuations = 0;
+    let mut malformed_call_corrections = 0;
+    // text of the final answer, which spans several responses if it had to be continued
+    let mut answer = String::new();
+    let mut failed_calls = vec![];
+
+    loop {
+        let context_window = options.context_window.or_else(|| provider.context_window());
+        if let Some(context_window) = context_window {
+            ensure_context_fits(
+                provider,
+                &mut contents,
+                &tools,
+                settings,
+                context_window,
+                options.auto_compact,
+                session_usage,
+            )?;
+        }
+
+        let request = ModelRequest {
+            system_prompt: SYSTEM_PROMPT,
+            contents: &contents,
+            tools: &tools,
+            settings,
+        };
+        let resp = provider.generate(&request, &mut |_| {}).map_err(|e| {
+            anyhow::anyhow!("couldn't get a response from {}: {e}", provider.name())
+        })?;
+
+        if let Some(usage) = resp.usage {
+            let line = session_usage.record(provider.model(), usage);
+            eprintln!("{}", line.dimmed());
+        }
+
+        let finish_reason = resp.finish_reason.unwrap_or(FinishReason::Stop);
+        if finish_reason == FinishReason::MalformedFunctionCall {
+            if malformed_call_corrections == MAX_MALFORMED_CALL_CORRECTIONS {
+                anyhow::bail!(
+                    "{} kept generating malformed function calls",
+                    provider.name()
+                );
+            }
+            malformed_call_corrections += 1;
+            eprintln!(
+                "{}",
+                format!(
+                    "{} generated a malformed function call; asking it to try again",
+                    provider.name()
+                )
+                .yellow()
+            );
+            push_user_parts(
+                &mut contents,
+                vec![Part::Text(MALFORMED_CALL_PROMPT.to_string())],
+            );
+            continue;
+        }
+        malformed_call_corrections = 0;
+
+        if !matches!(finish_reason, FinishReason::Stop | FinishReason::MaxTokens)
+            && let Some(explanation) = explain_finish_reason(provider.name(), &finish_reason)
+        {
+            anyhow::bail!(explanation);
+        }
+
+        let content = Content {
+            parts: resp.parts,
+            role: Some(Role::Model),
+        };
+
+        let mut function_results: Vec<Part> = vec![];
+        let mut follow_ups: Vec<Part> = vec![];
+        for part in &content.parts {
+            match part {
+                Part::Text(text) => answer.push_str(text),
+                Part::FunctionCall(call) => {
+                    let description = match call.target() {
+                        Some(target) => format!("{} {target}", call.name),
+                        None => call.name.to_string(),
+                    };
+                    eprintln!("{}", description.dimmed());
+                    let supports_attachment =
+                        |mime_type: &str| provider.supports_attachment(mime_type);
+                    let response = match execute_function_call(call, &supports_attachment) {
+                        Ok(result) => {
+                            follow_ups.extend(result.follow_up);
+                            result.response
+                        }
+                        Err(e) => FunctionResponse {
+                            id: call.id.clone(),
+                            name: call.name.clone(),
+                            response: FunctionCallResponse::Error(e.to_string()),
+                            will_continue: None,
+                        },
+                    };
+                    if let FunctionCallResponse::Error(e) = &response.response {
+                        eprintln!("{}", format!("{description} failed: {e}").yellow());
+                        failed_calls.push(format!("{description}: {e}"));
+                    }
+                    function_results.push(Part::FunctionResponse(response));
+                }
+                _ => {}
+            }
+        }
+        contents.push(content);
+        elide_stale_outputs(&mut contents, &elision);
+
+        if function_results.is_empty() {
+            if finish_reason == FinishReason::MaxTokens {
+                if continuations == MAX_AUTO_CONTINUATIONS {
+                    anyhow::bail!(
+                        "{}'s response was still cut off by the output token limit after {MAX_AUTO_CONTINUATIONS} continuations",
+                        provider.name()
+                    );
+                }
+                continuations += 1;
+                push_user_parts(&mut contents, vec![Part::Text(CONTINUE_PROMPT.to_string())]);
+                continue;
+            }
+
+            break;
+        }
+
+        // text that came along with function calls isn't part of the final answer
+        answer.clear();
+        continuations = 0;
+        function_results.extend(follow_ups);
+        contents.push(Content {
+            parts: function_results,
+            role: Some(Role::User),
+        });
+    }
+
+    let answer = answer.trim();
+    if answer.is_empty() {
+        anyhow::bail!("{} returned an empty response", provider.name());
+    }
+
+    Ok(Outcome {
+        answer: answer.to_string(),
+        contents,
+        failed_calls,
+    })
+}
+
+/// Makes sure the conversation fits in the context window, compacting it if it's past the
+/// `auto_compact` threshold
+fn ensure_context_fits(
+    provider: &dyn Provider,
+    contents: &mut Vec<Content>,
+    tools: &[FunctionDeclaration],
+    settings: &GenerationSettings,
+    context_window: usize,
+    auto_compact: Option<usize>,
+    session_usage: &mut SessionUsage,
+) -> anyhow::Result<()> {
+    let request = ModelRequest {
+        system_prompt: SYSTEM_PROMPT,
+        contents,
+        tools,
+        settings,
+    };
+
+    let overflow_error = |tokens: usize| {
+        anyhow::anyhow!(
+            "the request is {tokens} tokens long, which exceeds {}'s context window of {context_window} tokens",
+            provider.model()
+        )
+    };
+
+    match check_context(provider, &request, context_window, auto_compact) {
+        Ok(ContextCheck::Fits | ContextCheck::NearlyFull { .. }) => {}
+        Ok(ContextCheck::Overflows { tokens }) => return Err(overflow_error(tokens)),
+        Ok(ContextCheck::Compacted {
+            percent,
+            tokens,
+            result,
+        }) => {
+            eprintln!(
+                "{}",
+                format!(
+                    "the conversation takes up {percent}% of {}'s context window; compacting it",
+                    provider.model()
+                )
+                .dimmed()
+            );
+            let compaction = match result {
+                Ok(compaction) => compaction,
+                // like in the REPL, the request is sent anyway as long as it fits
+                Err(e) => {
+                    eprintln!(
+                        "{}",
+                        format!("couldn't compact the conversation: {e:#}").yellow()
+                    );
+                    if tokens > context_window {
+                        return Err(overflow_error(tokens));
+                    }
+                    return Ok(());
+                }
+            };
+            if let Some(usage) = compaction.usage {
+                let line = session_usage.record(provider.model(), usage);
+                eprintln!("{}", line.dimmed());
+            }
+            *contents = compaction.contents;
+
+            // the kept turns alone can still be too large for the context window
+            return ensure_context_fits(
+                provider,
+                contents,
+                tools,
+                settings,
+                context_window,
+                None,
+                session_usage,
+            );
+        }
+        Err(e) => eprintln!("{}", format!("couldn't count tokens: {e}").dimmed()),
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::agent::context::estimate_tokens;
+    use crate::agent::providers::ProviderKind;
+    use insta::assert_snapshot;
+    use serde_json::{Map, json};
+
+    fn ollama_response(content: &str, tool_calls: serde_json::Value, done_reason: &str) -> String {
+        json!({
+            "model": "llama3.1",
+            "created_at": "2025-01-01T00:00:00Z",
+            "message": { "role": "assistant", "content": content, "tool_calls": tool_calls },
+            "done_reason": done_reason,
+            "done": true,
+            "prompt_eval_count": 310,
+            "eval_count": 22
+        })
+        .to_string()
+    }
+
+    fn provider(server: &mockito::Server) -> Box<dyn Provider> {
+        let config = ProviderConfig {
+            kind: ProviderKind::Ollama,
+            model: Some("llama3.1".to_string()),
+            base_url: Some(server.url()),
+            ollama_options: Map::new(),
+            max_retries: Some(0),
+            cache_ttl: None,
+        };
+
+        get_provider(config, Client::new()).expect("provider should've been created")
+    }
+
+    fn options() -> RunOptions {
+        RunOptions {
+            generation_settings: GenerationSettings::default(),
+            auto_continue: true,
+            show_thoughts: false,
+            context_window: None,
+            auto_compact: None,
+            elide_after: None,
+            elide_larger_than: None,
+            resume: None,
+        }
+    }
+
+    fn prompt(text: &str) -> Vec<Part> {
+        vec![Part::Text(text.to_string())]
+    }
+
+    //-------------//
+    //  SUCCESSES  //
+    //-------------//
+
+    #[test]
+    fn only_the_final_answer_is_returned() {
+        // GIVEN
+        let mut server = mockito::Server::new();
+        let tool_turn = server
+            .mock("POST", "/api/chat")
+            .with_status(200)
+            .with_body(ollama_response(
+                "Let me read the manifest first.",
+                json!([{ "function": { "name": "read_file", "arguments": { "path": "Cargo.toml" } } }]),
+                "stop",
+            ))
+            .expect(1)
+            .create();
+        let answer_turn = server
+            .mock("POST", "/api/chat")
+            .with_status(200)
+            .with_body(ollama_response(
+                "The crate is called agent1.",
+                json!([]),
+                "stop",
+            ))
+            .expect(1)
+            .create();
+        let provider = provider(&server);
+
+        // WHEN
+        let outcome = run_agent(
+            provider.as_ref(),
+            &options(),
+            prompt("what's the crate called?"),
+            &mut SessionUsage::default(),
+        )
+        .expect("agent should've answered");
+
+        // THEN
+        tool_turn.assert();
+        answer_turn.assert();
+        assert_snapshot!(outcome.answer, @"The crate is called agent1.");
+        assert_eq!(outcome.contents.len(), 4);
+        assert!(outcome.failed_calls.is_empty());
+    }
+
+    #[test]
+    fn answers_cut_off_by_the_output_token_limit_are_continued() {
+        // GIVEN
+        let mut server = mockito::Server::new();
+        let first_half = server
+            .mock("POST", "/api/chat")
+            .with_status(200)
+            .with_body(ollama_response("The crate is ", json!([]), "length"))
+            .expect(1)
+            .create();
+        let second_half = server
+            .mock("POST", "/api/chat")
+            .with_status(200)
+            .with_body(ollama_response("called agent1.", json!([]), "stop"))
+            .expect(1)
+            .create();
+        let provider = provider(&server);
+
+        // WHEN
+        let outcome = run_agent(
+            provider.as_ref(),
+            &options(),
+            prompt("what's the crate called?"),
+            &mut SessionUsage::default(),
+        )
+        .expect("agent should've answered");
+
+        // THEN
+        first_half.assert();
+        second_half.assert();
+        assert_snapshot!(outcome.answer, @"The crate is called agent1.");
+    }
+
+    #[test]
+    fn failed_function_calls_are_reported_to_the_model_and_recorded() {
+        // GIVEN
+        let mut server = mockito::Server::new();
+        server
+            .mock("POST", "/api/chat")
+            .with_status(200)
+            .with_body(ollama_response(
+                "",
+                json!([{ "function": { "name": "read_file", "arguments": { "path": "missing.md" } } }]),
+                "stop",
+            ))
+            .expect(1)
+            .create();
+        server
+            .mock("POST", "/api/chat")
+            .with_status(200)
+            .with_body(ollama_response("There's no such file.", json!([]), "stop"))
+            .expect(1)
+            .create();
+        let provider = provider(&server);
+
+        // WHEN
+        let outcome = run_agent(
+            provider.as_ref(),
+            &options(),
+            prompt("summarize missing.md"),
+            &mut SessionUsage::default(),
+        )
+        .expect("agent should've answered");
+
+        // THEN
+        assert_snapshot!(outcome.answer, @"There's no such file.");
+        assert_eq!(outcome.failed_calls.len(), 1);
+        assert!(outcome.failed_calls[0].starts_with("read_file missing.md: "));
+    }
+
+    #[test]
+    fn requests_that_cant_be_compacted_are_sent_if_they_fit() {
+        // GIVEN
+        let mut server = mockito::Server::new();
+        let answer_turn = server
+            .mock("POST", "/api/chat")
+            .with_status(200)
+            .with_body(ollama_response("It's a build log.", json!([]), "stop"))
+            .expect(1)
+            .create();
+        let provider = provider(&server);
+        let parts = vec![
+            Part::Text("what's this?".to_string()),
+            Part::Text(format_piped_input(
+                &"build log\n".repeat(2000),
+                MAX_PIPED_INPUT_BYTES,
+            )),
+        ];
+        let contents = vec![Content {
+            parts: parts.clone(),
+            role: Some(Role::User),
+        }];
+        let tokens = estimate_tokens(&ModelRequest {
+            system_prompt: SYSTEM_PROMPT,
+            contents: &contents,
+            tools: &get_tools(),
+            settings: &GenerationSettings::default(),
+        });
+        // a single message is too short to be compacted, but it takes up 2/3 of the window
+        let options = RunOptions {
+            context_window: Some(tokens * 3 / 2),
+            auto_compact: Some(50),
+            ..options()
+        };
+
+        // WHEN
+        let outcome = run_agent(
+            provider.as_ref(),
+            &options,
+            parts,
+            &mut SessionUsage::default(),
+        )
+        .expect("agent should've answered");
+
+        // THEN
+        answer_turn.assert();
+        assert_snapshot!(outcome.answer, @"It's a build log.");
+    }
+
+    //------------//
+    //  FAILURES  //
+    //------------//
+
+    #[test]
+    fn requests_that_still_overflow_after_compaction_are_errors() {
+        // GIVEN
+        let mut server = mockito::Server::new();
+        let summary = server
+            .mock("POST", "/api/chat")
+            .with_status(200)
+            .with_body(ollama_response("The user said hi.", json!([]), "stop"))
+            .expect(1)
+            .create();
+        let provider = provider(&server);
+        let text = |text: &str, role: Role| Content {
+            parts: vec![Part::Text(text.to_string())],
+            role: Some(role),
+        };
+        let large = "x".repeat(40_000);
+        let mut contents = vec![
+            text("hi", Role::User),
+            text("hello!", Role::Model),
+            text(&large, Role::User),
+            text("ok", Role::Model),
+            text(&large, Role::User),
+        ];
+
+        // WHEN
+        let result = ensure_context_fits(
+            provider.as_ref(),
+            &mut contents,
+            &get_tools(),
+            &GenerationSettings::default(),
+            16_000,
+            Some(80),
+            &mut SessionUsage::default(),
+        )
+        .expect_err("result wasn't an error");
+
+        // THEN
+        summary.assert();
+        assert_eq!(contents.len(), 3);
+        assert_snapshot!(result, @"the request is 21752 tokens long, which exceeds llama3.1's context window of 16000 tokens");
+    }
+
+    #[test]
+    fn failed_requests_are_errors() {
+        // GIVEN
+        let mut server = mockito::Server::new();
+        server
+            .mock("POST", "/api/chat")
+            .with_status(400)
+            .with_body(r#"{"error": "model 'llama3.1' not found"}"#)
+            .create();
+        let provider = provider(&server);
+
+        // WHEN
+        let result = run_agent(
+            provider.as_ref(),
+            &options(),
+            prompt("what's the crate called?"),
+            &mut SessionUsage::default(),
+        )
+        .expect_err("result wasn't an error");
+
+        // THEN
+        assert_snapshot!(result, @"couldn't get a response from Ollama: API returned a non success code: 400 (model 'llama3.1' not found)");
+    }
+
+    #[test]
+    fn responses_that_didnt_stop_are_errors() {
+        // GIVEN
+        let mut server = mockito::Server::new();
+        server
+            .mock("POST", "/api/chat")
+            .with_status(200)
+            .with_body(ollama_response("The crate", json!([]), "unload"))
+            .create();
+        let provider = provider(&server);
+
+        // WHEN
+        let result = run_agent(
+            provider.as_ref(),
+            &options(),
+            prompt("what's the crate called?"),
+            &mut SessionUsage::default(),
+        )
+        .expect_err("result wasn't an error");
+
+        // THEN
+        assert_snapshot!(result, @"Ollama stopped generating unexpectedly (reason: unload)");
+    }
+}
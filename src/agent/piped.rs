@@ -0,0 +1,111 @@
+use anyhow::Context;
+use std::io::Read;
+
+// large enough for the output of a failing build or test run, while leaving most of the context
+// window to the conversation
+pub(super) const MAX_PIPED_INPUT_BYTES: usize = 64 * 1024;
+
+/// Reads everything piped to agent1's stdin; invalid UTF-8 is replaced rather than rejected,
+/// since command output isn't always valid text
+pub fn read_piped_input() -> anyhow::Result<String> {
+    let mut input = vec![];
+    std::io::stdin()
+        .read_to_end(&mut input)
+        .context("couldn't read stdin")?;
+
+    Ok(String::from_utf8_lossy(&input).into_owned())
+}
+
+/// Wraps piped input in a delimited block to be sent along with the prompt; input larger than
+/// `max_bytes` is cut in the middle, keeping its start and end, where errors and summaries
+/// usually are
+pub(super) fn format_piped_input(input: &str, max_bytes: usize) -> String {
+    let input = input.trim_end();
+    if input.len() <= max_bytes {
+        return format!("The following was piped to stdin:\n<stdin>\n{input}\n</stdin>");
+    }
+
+    let head_end = get_head_end(input, max_bytes / 2);
+    let tail_start = get_tail_start(input, input.len() - max_bytes / 2);
+    let omitted = &input[head_end..tail_start];
+
+    format!(
+        "The following was piped to stdin; it was too long, so only its start and end are included:\n<stdin>\n{}\n[... {} lines ({} bytes) omitted ...]\n{}\n</stdin>",
+        input[..head_end].trim_end(),
+        omitted.lines().count(),
+        omitted.len(),
+        input[tail_start..].trim_start_matches('\n')
+    )
+}
+
+/// Returns where the kept start of the input ends: at the end of the last full line before
+/// `limit`, or at `limit` itself if the first line is longer than that
+fn get_head_end(input: &str, limit: usize) -> usize {
+    let mut end = limit;
+    while !input.is_char_boundary(end) {
+        end -= 1;
+    }
+
+    input[..end].rfind('\n').map_or(end, |i| i + 1)
+}
+
+/// Returns where the kept end of the input starts: at the start of the first full line after
+/// `limit`, or at `limit` itself if the last line is longer than that
+fn get_tail_start(input: &str, limit: usize) -> usize {
+    let mut start = limit;
+    while !input.is_char_boundary(start) {
+        start += 1;
+    }
+
+    input[start..].find('\n').map_or(start, |i| start + i + 1)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use insta::assert_snapshot;
+
+    //-------------//
+    //  SUCCESSES  //
+    //-------------//
+
+    #[test]
+    fn short_input_is_included_as_is() {
+        // GIVEN
+        let input = "test result: FAILED. 1 passed; 1 failed\n";
+
+        // WHEN
+        let block = format_piped_input(input, MAX_PIPED_INPUT_BYTES);
+
+        // THEN
+        assert_snapshot!(block, @"
+        The following was piped to stdin:
+        <stdin>
+        test result: FAILED. 1 passed; 1 failed
+        </stdin>
+        ");
+    }
+
+    #[test]
+    fn long_input_keeps_its_first_and_last_lines() {
+        // GIVEN
+        let input = (1..=20)
+            .map(|i| format!("line {i:02}\n"))
+            .collect::<String>();
+
+        // WHEN
+        let block = format_piped_input(&input, 40);
+
+        // THEN
+        assert_snapshot!(block, @"
+        The following was piped to stdin; it was too long, so only its start and end are included:
+        <stdin>
+        line 01
+        line 02
+        [... 16 lines (128 bytes) omitted ...]
+        line 19
+        line 20
+        </stdin>
+        ");
+    }
+}
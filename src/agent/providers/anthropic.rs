@@ -0,0 +1,956 @@
+use super::http::HttpClient;
+use super::{
+    Delta, FinishReason, ModelRequest, ModelResponse, Provider, ProviderConfig, ProviderError,
+    ToolCallIds, emit_deltas, get_env_var, trim_base_url,
+};
+use crate::agent::domain::{
+    Blob, FunctionCall, FunctionCallResponse, FunctionDeclarationName, Part, Role, Thought, Usage,
+};
+use reqwest::blocking::Client;
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use tracing::debug;
+
+const API_KEY_ENV_VAR: &str = "ANTHROPIC_API_KEY";
+const BASE_URL: &str = "https://api.anthropic.com";
+const API_VERSION: &str = "2023-06-01";
+const MODEL: &str = "claude-sonnet-4-5";
+const MAX_TOKENS: u32 = 8192;
+/// Context window of all current claude models (without the 1M token beta)
+const CONTEXT_WINDOW: usize = 200_000;
+const PDF_MIME_TYPE: &str = "application/pdf";
+const SUPPORTED_ATTACHMENT_TYPES: [&str; 5] = [
+    "image/png",
+    "image/jpeg",
+    "image/gif",
+    "image/webp",
+    PDF_MIME_TYPE,
+];
+
+pub(super) struct AnthropicProvider {
+    client: HttpClient,
+    api_key: String,
+    base_url: String,
+    model: String,
+}
+
+impl AnthropicProvider {
+    pub(super) fn new(
+        client: HttpClient,
+        api_key: String,
+        base_url: String,
+        model: String,
+    ) -> Self {
+        Self {
+            client,
+            api_key,
+            base_url,
+            model,
+        }
+    }
+
+    pub(super) fn from_config(client: Client, config: ProviderConfig) -> anyhow::Result<Self> {
+        let api_key = get_env_var(API_KEY_ENV_VAR)?;
+        let base_url = config
+            .base_url
+            .map(trim_base_url)
+            .unwrap_or_else(|| BASE_URL.to_string());
+        let model = config.model.unwrap_or_else(|| MODEL.to_string());
+
+        Ok(Self::new(
+            HttpClient::new(client, config.max_retries),
+            api_key,
+            base_url,
+            model,
+        ))
+    }
+}
+
+impl Provider for AnthropicProvider {
+    fn name(&self) -> &'static str {
+        "Claude"
+    }
+
+    fn model(&self) -> &str {
+        &self.model
+    }
+
+    fn set_model(&mut self, model: String) {
+        self.model = model;
+    }
+
+    fn context_window(&self) -> Option<usize> {
+        self.model.starts_with("claude-").then_some(CONTEXT_WINDOW)
+    }
+
+    fn supports_attachment(&self, mime_type: &str) -> bool {
+        SUPPORTED_ATTACHMENT_TYPES.contains(&mime_type)
+    }
+
+    fn list_models(&self) -> Result<Vec<String>, ProviderError> {
+        let resp_body = self.client.send(
+            self.client
+                .get(format!("{}/v1/models?limit=1000", self.base_url))
+                .header("x-api-key", &self.api_key)
+                .header("anthropic-version", API_VERSION),
+        )?;
+
+        let resp = serde_json::from_str::<ListModelsResponse>(&resp_body)
+            .map_err(|e| ProviderError::InvalidResponse(e.into()))?;
+
+        Ok(resp.data.into_iter().map(|m| m.id).collect())
+    }
+
+    fn count_tokens(&self, request: &ModelRequest) -> Result<usize, ProviderError> {
+        let body = CountTokensBody::from(MessagesBody::new(request, &self.model));
+
+        let resp_body = self.client.send(
+            self.client
+                .post(format!("{}/v1/messages/count_tokens", self.base_url))
+                .json(&body)
+                .header("content-type", "application/json")
+                .header("x-api-key", &self.api_key)
+                .header("anthropic-version", API_VERSION),
+        )?;
+
+        let resp = serde_json::from_str::<CountTokensResponse>(&resp_body)
+            .map_err(|e| ProviderError::InvalidResponse(e.into()))?;
+
+        Ok(resp.input_tokens)
+    }
+
+    fn generate(
+        &self,
+        request: &ModelRequest,
+        on_delta: &mut dyn FnMut(Delta),
+    ) -> Result<ModelResponse, ProviderError> {
+        let body = MessagesBody::new(request, &self.model);
+
+        if let Ok(r) = serde_json::to_string_pretty(&body) {
+            debug!("request: {}", &r);
+        } else {
+            debug!("request: {:?}", &body);
+        }
+
+        let resp_body = self.client.send(
+            self.client
+                .post(format!("{}/v1/messages", self.base_url))
+                .json(&body)
+                .header("content-type", "application/json")
+                .header("x-api-key", &self.api_key)
+                .header("anthropic-version", API_VERSION),
+        )?;
+
+        let resp = serde_json::from_str::<MessagesResponse>(&resp_body)
+            .map_err(|e| ProviderError::InvalidResponse(e.into()))?;
+
+        let parts: Vec<Part> = resp
+            .content
+            .into_iter()
+            .filter_map(|block| match block {
+                ContentBlock::Text { text } => Some(Part::Text(text)),
+                ContentBlock::Thinking {
+                    thinking,
+                    signature,
+                } => Some(Part::Thought(Thought {
+                    text: thinking,
+                    signature: Some(signature),
+                    redacted: false,
+                })),
+                ContentBlock::RedactedThinking { data } => Some(Part::Thought(Thought {
+                    text: String::new(),
+                    signature: Some(data),
+                    redacted: true,
+                })),
+                ContentBlock::ToolUse { id, name, input } => {
+                    Some(Part::FunctionCall(FunctionCall {
+                        id: Some(id),
+                        name,
+                        args: Some(input),
+                        thought_signature: None,
+                    }))
+                }
+                ContentBlock::Image { .. }
+                | ContentBlock::Document { .. }
+                | ContentBlock::ToolResult { .. }
+                | ContentBlock::Unsupported => None,
+            })
+            .collect();
+
+        emit_deltas(&parts, on_delta);
+
+        Ok(ModelResponse {
+            parts,
+            usage: Some(resp.usage.into()),
+            finish_reason: resp.stop_reason.as_deref().map(parse_stop_reason),
+        })
+    }
+}
+
+#[derive(Debug, Serialize)]
+struct MessagesBody<'a> {
+    model: &'a str,
+    max_tokens: u32,
+    system: &'a str,
+    messages: Vec<Message>,
+    tools: Vec<ToolDefinition<'a>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    temperature: Option<f64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    top_p: Option<f64>,
+    #[serde(skip_serializing_if = "<[String]>::is_empty")]
+    stop_sequences: &'a [String],
+    #[serde(skip_serializing_if = "Option::is_none")]
+    thinking: Option<Thinking>,
+}
+
+#[derive(Debug, Serialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+enum Thinking {
+    Enabled { budget_tokens: u32 },
+}
+
+#[derive(Debug, Serialize)]
+struct Message {
+    role: &'static str,
+    content: Vec<ContentBlock>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+enum ContentBlock {
+    Text {
+        text: String,
+    },
+    Thinking {
+        thinking: String,
+        signature: String,
+    },
+    /// Thinking that was flagged by safety systems, which comes encrypted
+    RedactedThinking {
+        data: String,
+    },
+    Image {
+        source: Base64Source,
+    },
+    Document {
+        source: Base64Source,
+    },
+    ToolUse {
+        id: String,
+        name: FunctionDeclarationName,
+        input: Value,
+    },
+    ToolResult {
+        tool_use_id: String,
+        content: String,
+        #[serde(skip_serializing_if = "std::ops::Not::not")]
+        is_error: bool,
+    },
+    #[serde(other)]
+    Unsupported,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+struct Base64Source {
+    #[serde(rename = "type")]
+    kind: String,
+    media_type: String,
+    data: String,
+}
+
+impl From<&Blob> for Base64Source {
+    fn from(blob: &Blob) -> Self {
+        Self {
+            kind: "base64".to_string(),
+            media_type: blob.mime_type.clone(),
+            data: blob.data.clone(),
+        }
+    }
+}
+
+#[derive(Debug, Serialize)]
+struct ToolDefinition<'a> {
+    name: &'a FunctionDeclarationName,
+    description: &'a str,
+    input_schema: &'a Value,
+}
+
+impl<'a> MessagesBody<'a> {
+    fn new(request: &ModelRequest<'a>, model: &'a str) -> Self {
+        let mut ids = ToolCallIds::default();
+
+        let messages = request
+            .contents
+            .iter()
+            .map(|content| {
+                let blocks = content
+                    .parts
+                    .iter()
+                    .filter_map(|part| match part {
+                        Part::Text(text) if text.is_empty() => None,
+                        Part::Text(text) => Some(ContentBlock::Text { text: text.clone() }),
+                        // the messages API only accepts thinking blocks it signed itself, and
+                        // requires redacted ones to be sent back as they came
+                        Part::Thought(Thought {
+                            signature: Some(data),
+                            redacted: true,
+                            ..
+                        }) => Some(ContentBlock::RedactedThinking { data: data.clone() }),
+                        Part::Thought(Thought {
+                            text,
+                            signature: Some(signature),
+                            redacted: false,
+                        }) => Some(ContentBlock::Thinking {
+                            thinking: text.clone(),
+                            signature: signature.clone(),
+                        }),
+                        Part::Thought(_) => None,
+                        Part::InlineData(blob) if blob.mime_type == PDF_MIME_TYPE => {
+                            Some(ContentBlock::Document {
+                                source: blob.into(),
+                            })
+                        }
+                        Part::InlineData(blob) => Some(ContentBlock::Image {
+                            source: blob.into(),
+                        }),
+                        // only produced by gemini
+                        Part::FileData(_)
+                        | Part::ExecutableCode(_)
+                        | Part::CodeExecutionResult(_)
+                        | Part::Unknown(_) => None,
+                        Part::FunctionCall(call) => Some(ContentBlock::ToolUse {
+                            id: ids.for_call(call),
+                            name: call.name.clone(),
+                            input: call
+                                .args
+                                .clone()
+                                .unwrap_or_else(|| Value::Object(Default::default())),
+                        }),
+                        Part::FunctionResponse(resp) => {
+                            let tool_use_id = ids.for_response(resp);
+                            let (content, is_error) = match &resp.response {
+                                FunctionCallResponse::Output(o) => (o.clone(), false),
+                                FunctionCallResponse::Error(e) => (e.clone(), true),
+                            };
+
+                            Some(ContentBlock::ToolResult {
+                                tool_use_id,
+                                content,
+                                is_error,
+                            })
+                        }
+                    })
+                    .collect();
+
+                Message {
+                    role: match content.role {
+                        Some(Role::Model) => "assistant",
+                        Some(Role::User) | None => "user",
+                    },
+                    content: blocks,
+                }
+            })
+            // the messages API rejects empty messages, and merges consecutive messages of the
+            // same role, so turns with nothing it accepts are left out
+            .filter(|message| !message.content.is_empty())
+            .collect();
+
+        let tools = request
+            .tools
+            .iter()
+            .map(|t| ToolDefinition {
+                name: &t.name,
+                description: &t.description,
+                input_schema: &t.parameters,
+            })
+            .collect();
+
+        let settings = request.settings;
+        // budgets of 0 or less mean no thinking (or letting the model decide, for gemini), which
+        // is what the messages API does when `thinking` is left out
+        let thinking = settings
+            .thinking_budget
+            .and_then(|b| u32::try_from(b).ok())
+            .filter(|b| *b > 0)
+            .map(|budget_tokens| Thinking::Enabled { budget_tokens });
+        // max_tokens includes thinking, and the messages API rejects budgets that don't leave room
+        // for an answer, so larger budgets get MAX_TOKENS on top of them for the answer
+        let max_tokens = settings.max_output_tokens.unwrap_or(MAX_TOKENS);
+        let max_tokens = match &thinking {
+            Some(Thinking::Enabled { budget_tokens }) if *budget_tokens >= max_tokens => {
+                budget_tokens.saturating_add(MAX_TOKENS)
+            }
+            _ => max_tokens,
+        };
+        // the messages API rejects sampling settings other than the defaults along with thinking
+        let (temperature, top_p) = match thinking {
+            Some(_) => (None, None),
+            None => (settings.temperature, settings.top_p),
+        };
+
+        Self {
+            model,
+            max_tokens,
+            system: request.system_prompt,
+            messages,
+            tools,
+            temperature,
+            top_p,
+            stop_sequences: &settings.stop_sequences,
+            thinking,
+        }
+    }
+}
+
+/// The token counting endpoint rejects the messages API's output related fields
+#[derive(Debug, Serialize)]
+struct CountTokensBody<'a> {
+    model: &'a str,
+    system: &'a str,
+    messages: Vec<Message>,
+    tools: Vec<ToolDefinition<'a>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    thinking: Option<Thinking>,
+}
+
+impl<'a> From<MessagesBody<'a>> for CountTokensBody<'a> {
+    fn from(body: MessagesBody<'a>) -> Self {
+        Self {
+            model: body.model,
+            system: body.system,
+            messages: body.messages,
+            tools: body.tools,
+            thinking: body.thinking,
+        }
+    }
+}
+
+#[derive(Debug, Deserialize)]
+struct CountTokensResponse {
+    input_tokens: usize,
+}
+
+#[derive(Debug, Deserialize)]
+struct MessagesResponse {
+    content: Vec<ContentBlock>,
+    usage: MessagesUsage,
+    stop_reason: Option<String>,
+}
+
+fn parse_stop_reason(reason: &str) -> FinishReason {
+    match reason {
+        "end_turn" | "tool_use" | "stop_sequence" => FinishReason::Stop,
+        "max_tokens" => FinishReason::MaxTokens,
+        "refusal" => FinishReason::Safety,
+        other => FinishReason::Other(other.to_string()),
+    }
+}
+
+#[derive(Debug, Deserialize)]
+struct ListModelsResponse {
+    data: Vec<ModelInfo>,
+}
+
+#[derive(Debug, Deserialize)]
+struct ModelInfo {
+    id: String,
+}
+
+#[derive(Debug, Deserialize)]
+struct MessagesUsage {
+    /// Excludes the tokens read from and written to the cache
+    input_tokens: usize,
+    #[serde(default)]
+    cache_creation_input_tokens: usize,
+    #[serde(default)]
+    cache_read_input_tokens: usize,
+    /// Includes thinking tokens
+    output_tokens: usize,
+}
+
+impl From<MessagesUsage> for Usage {
+    fn from(value: MessagesUsage) -> Self {
+        let prompt_tokens =
+            value.input_tokens + value.cache_creation_input_tokens + value.cache_read_input_tokens;
+
+        Self {
+            prompt_tokens,
+            cached_tokens: value.cache_read_input_tokens,
+            output_tokens: value.output_tokens,
+            thinking_tokens: 0,
+            total_tokens: prompt_tokens + value.output_tokens,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::agent::domain::{Content, FileData, FunctionResponse};
+    use crate::agent::generation::GenerationSettings;
+    use crate::agent::tools::get_tools;
+    use insta::assert_snapshot;
+    use mockito::Matcher;
+    use serde_json::json;
+
+    const RESPONSE_WITH_TOOL_USE: &str = r#"{
+  "id": "msg_01",
+  "type": "message",
+  "role": "assistant",
+  "model": "claude-sonnet-4-5",
+  "content": [
+    { "type": "text", "text": "Let me look at that file." },
+    { "type": "tool_use", "id": "toolu_01", "name": "read_file", "input": { "path": "Cargo.toml" } }
+  ],
+  "stop_reason": "tool_use",
+  "usage": { "input_tokens": 20, "cache_read_input_tokens": 100, "output_tokens": 30 }
+}"#;
+
+    fn conversation() -> Vec<Content> {
+        vec![
+            Content {
+                parts: vec![Part::Text("what's in Cargo.toml?".to_string())],
+                role: Some(Role::User),
+            },
+            Content {
+                parts: vec![Part::FunctionCall(FunctionCall {
+                    id: Some("toolu_01".to_string()),
+                    name: FunctionDeclarationName::ReadFile,
+                    args: Some(json!({"path": "Cargo.toml"})),
+                    thought_signature: None,
+                })],
+                role: Some(Role::Model),
+            },
+            Content {
+                parts: vec![Part::FunctionResponse(FunctionResponse {
+                    id: Some("toolu_01".to_string()),
+                    name: FunctionDeclarationName::ReadFile,
+                    response: FunctionCallResponse::Error("couldn't read file".to_string()),
+                    will_continue: Some(false),
+                })],
+                role: Some(Role::User),
+            },
+        ]
+    }
+
+    //-------------//
+    //  SUCCESSES  //
+    //-------------//
+
+    #[test]
+    fn history_is_translated_into_tool_use_and_tool_result_blocks() {
+        // GIVEN
+        let contents = conversation();
+        let request = ModelRequest {
+            system_prompt: "system prompt",
+            contents: &contents,
+            tools: &[],
+            settings: &GenerationSettings::default(),
+        };
+
+        // WHEN
+        let body = MessagesBody::new(&request, MODEL);
+
+        // THEN
+        let body = serde_json::to_string_pretty(&body).expect("body should've been serialized");
+        assert_snapshot!(body, @r#"
+        {
+          "model": "claude-sonnet-4-5",
+          "max_tokens": 8192,
+          "system": "system prompt",
+          "messages": [
+            {
+              "role": "user",
+              "content": [
+                {
+                  "type": "text",
+                  "text": "what's in Cargo.toml?"
+                }
+              ]
+            },
+            {
+              "role": "assistant",
+              "content": [
+                {
+                  "type": "tool_use",
+                  "id": "toolu_01",
+                  "name": "read_file",
+                  "input": {
+                    "path": "Cargo.toml"
+                  }
+                }
+              ]
+            },
+            {
+              "role": "user",
+              "content": [
+                {
+                  "type": "tool_result",
+                  "tool_use_id": "toolu_01",
+                  "content": "couldn't read file",
+                  "is_error": true
+                }
+              ]
+            }
+          ],
+          "tools": []
+        }
+        "#);
+    }
+
+    #[test]
+    fn calls_without_ids_get_matching_synthetic_ids() {
+        // GIVEN
+        let mut contents = conversation();
+        if let Part::FunctionCall(call) = &mut contents[1].parts[0] {
+            call.id = None;
+        }
+        if let Part::FunctionResponse(resp) = &mut contents[2].parts[0] {
+            resp.id = None;
+        }
+        let request = ModelRequest {
+            system_prompt: "system prompt",
+            contents: &contents,
+            tools: &[],
+            settings: &GenerationSettings::default(),
+        };
+
+        // WHEN
+        let body = MessagesBody::new(&request, MODEL);
+
+        // THEN
+        let tool_use_id = match &body.messages[1].content[0] {
+            ContentBlock::ToolUse { id, .. } => id,
+            other => panic!("expected a tool_use block, got {other:?}"),
+        };
+        let tool_result_id = match &body.messages[2].content[0] {
+            ContentBlock::ToolResult { tool_use_id, .. } => tool_use_id,
+            other => panic!("expected a tool_result block, got {other:?}"),
+        };
+        assert_eq!(tool_use_id, "call_agent1_1");
+        assert_eq!(tool_result_id, tool_use_id);
+    }
+
+    #[test]
+    fn tool_parameters_are_used_as_input_schema() {
+        // GIVEN
+        let tools = get_tools();
+        let request = ModelRequest {
+            system_prompt: "system prompt",
+            contents: &[],
+            tools: &tools[..1],
+            settings: &GenerationSettings::default(),
+        };
+
+        // WHEN
+        let body = MessagesBody::new(&request, MODEL);
+
+        // THEN
+        let tools =
+            serde_json::to_string_pretty(&body.tools).expect("tools should've been serialized");
+        assert_snapshot!(tools, @r#"
+        [
+          {
+            "name": "read_file",
+            "description": "Read the contents of a given relative file path. Use this when you want to see what's inside a file. Do not use this with directory names.",
+            "input_schema": {
+              "properties": {
+                "path": {
+                  "description": "The relative path of a file in the working directory",
+                  "type": "string"
+                }
+              },
+              "required": [
+                "path"
+              ],
+              "type": "object"
+            }
+          }
+        ]
+        "#);
+    }
+
+    #[test]
+    fn max_tokens_leave_room_for_an_answer_after_thinking() {
+        // GIVEN
+        let settings = [Some(4096), None].map(|max_output_tokens| GenerationSettings {
+            max_output_tokens,
+            thinking_budget: Some(16_000),
+            ..Default::default()
+        });
+
+        // WHEN
+        let max_tokens = settings.map(|settings| {
+            let request = ModelRequest {
+                system_prompt: "system prompt",
+                contents: &[],
+                tools: &[],
+                settings: &settings,
+            };
+            MessagesBody::new(&request, MODEL).max_tokens
+        });
+
+        // THEN
+        assert_eq!(max_tokens, [16_000 + MAX_TOKENS; 2]);
+    }
+
+    #[test]
+    fn sampling_settings_are_left_out_when_thinking() {
+        // GIVEN
+        let settings = GenerationSettings {
+            temperature: Some(0.2),
+            top_p: Some(0.9),
+            thinking_budget: Some(2048),
+            ..Default::default()
+        };
+        let request = ModelRequest {
+            system_prompt: "system prompt",
+            contents: &[],
+            tools: &[],
+            settings: &settings,
+        };
+
+        // WHEN
+        let body = MessagesBody::new(&request, MODEL);
+
+        // THEN
+        assert_eq!(body.temperature, None);
+        assert_eq!(body.top_p, None);
+        assert!(body.thinking.is_some());
+    }
+
+    #[test]
+    fn response_from_messages_api_is_parsed() {
+        // GIVEN
+        let mut server = mockito::Server::new();
+        let mock = server
+            .mock("POST", "/v1/messages")
+            .match_header("x-api-key", "test-key")
+            .match_header("anthropic-version", API_VERSION)
+            .with_status(200)
+            .with_body(RESPONSE_WITH_TOOL_USE)
+            .create();
+        let provider = AnthropicProvider::new(
+            HttpClient::new(Client::new(), None),
+            "test-key".to_string(),
+            server.url(),
+            MODEL.to_string(),
+        );
+        let contents = conversation();
+        let request = ModelRequest {
+            system_prompt: "system prompt",
+            contents: &contents[..1],
+            tools: &[],
+            settings: &GenerationSettings::default(),
+        };
+
+        // WHEN
+        let response = provider
+            .generate(&request, &mut |_| {})
+            .expect("response should've been a success");
+
+        // THEN
+        mock.assert();
+        assert_snapshot!(format!("{:#?}", response.parts), @r#"
+        [
+            Text(
+                "Let me look at that file.",
+            ),
+            FunctionCall(
+                FunctionCall {
+                    id: Some(
+                        "toolu_01",
+                    ),
+                    name: ReadFile,
+                    args: Some(
+                        Object {
+                            "path": String("Cargo.toml"),
+                        },
+                    ),
+                    thought_signature: None,
+                },
+            ),
+        ]
+        "#);
+        let usage = response.usage.expect("usage should've been present");
+        assert_eq!(usage.total_tokens, 150);
+        assert_eq!(usage.cached_tokens, 100);
+    }
+
+    #[test]
+    fn redacted_thinking_is_sent_back_as_is() {
+        // GIVEN
+        let mut server = mockito::Server::new();
+        server
+            .mock("POST", "/v1/messages")
+            .with_status(200)
+            .with_body(
+                r#"{
+  "content": [
+    { "type": "redacted_thinking", "data": "EmwKAhgBEgy3va3pzix" },
+    { "type": "tool_use", "id": "toolu_01", "name": "read_file", "input": { "path": "Cargo.toml" } }
+  ],
+  "stop_reason": "tool_use",
+  "usage": { "input_tokens": 20, "output_tokens": 30 }
+}"#,
+            )
+            .create();
+        let provider = AnthropicProvider::new(
+            HttpClient::new(Client::new(), None),
+            "test-key".to_string(),
+            server.url(),
+            MODEL.to_string(),
+        );
+        let mut contents = conversation();
+        contents.truncate(1);
+        let request = ModelRequest {
+            system_prompt: "system prompt",
+            contents: &contents.clone(),
+            tools: &[],
+            settings: &GenerationSettings::default(),
+        };
+        let response = provider
+            .generate(&request, &mut |_| {})
+            .expect("response should've been a success");
+        contents.push(Content {
+            parts: response.parts,
+            role: Some(Role::Model),
+        });
+        // turns with nothing the messages API accepts are left out
+        contents.push(Content {
+            parts: vec![Part::FileData(FileData {
+                mime_type: None,
+                file_uri: "https://generativelanguage.googleapis.com/v1beta/files/abc".to_string(),
+            })],
+            role: Some(Role::User),
+        });
+        let request = ModelRequest {
+            contents: &contents,
+            ..request
+        };
+
+        // WHEN
+        let body = MessagesBody::new(&request, MODEL);
+
+        // THEN
+        let messages = serde_json::to_string_pretty(&body.messages)
+            .expect("messages should've been serialized");
+        assert_snapshot!(messages, @r#"
+        [
+          {
+            "role": "user",
+            "content": [
+              {
+                "type": "text",
+                "text": "what's in Cargo.toml?"
+              }
+            ]
+          },
+          {
+            "role": "assistant",
+            "content": [
+              {
+                "type": "redacted_thinking",
+                "data": "EmwKAhgBEgy3va3pzix"
+              },
+              {
+                "type": "tool_use",
+                "id": "toolu_01",
+                "name": "read_file",
+                "input": {
+                  "path": "Cargo.toml"
+                }
+              }
+            ]
+          }
+        ]
+        "#);
+    }
+
+    #[test]
+    fn tokens_are_counted_without_output_settings() {
+        // GIVEN
+        let mut server = mockito::Server::new();
+        let mock = server
+            .mock("POST", "/v1/messages/count_tokens")
+            .match_header("x-api-key", "test-key")
+            .match_header("anthropic-version", API_VERSION)
+            .match_body(Matcher::Json(json!({
+                "model": MODEL,
+                "system": "system prompt",
+                "messages": [{"role": "user", "content": [{"type": "text", "text": "hi"}]}],
+                "tools": [],
+            })))
+            .with_status(200)
+            .with_body(r#"{"input_tokens": 14}"#)
+            .create();
+        let provider = AnthropicProvider::new(
+            HttpClient::new(Client::new(), None),
+            "test-key".to_string(),
+            server.url(),
+            MODEL.to_string(),
+        );
+        let contents = vec![Content {
+            parts: vec![Part::Text("hi".to_string())],
+            role: Some(Role::User),
+        }];
+        let request = ModelRequest {
+            system_prompt: "system prompt",
+            contents: &contents,
+            tools: &[],
+            settings: &GenerationSettings {
+                max_output_tokens: Some(1024),
+                temperature: Some(0.2),
+                ..Default::default()
+            },
+        };
+
+        // WHEN
+        let tokens = provider
+            .count_tokens(&request)
+            .expect("result should've been a success");
+
+        // THEN
+        mock.assert();
+        assert_eq!(tokens, 14);
+    }
+
+    //------------//
+    //  FAILURES  //
+    //------------//
+
+    #[test]
+    fn non_success_status_is_returned_as_an_error_response() {
+        // GIVEN
+        let mut server = mockito::Server::new();
+        server
+            .mock("POST", "/v1/messages")
+            .with_status(529)
+            .with_body(
+                r#"{"type":"error","error":{"type":"overloaded_error","message":"Overloaded"}}"#,
+            )
+            .create();
+        let provider = AnthropicProvider::new(
+            HttpClient::new(Client::new(), Some(0)),
+            "test-key".to_string(),
+            server.url(),
+            MODEL.to_string(),
+        );
+        let contents = conversation();
+        let request = ModelRequest {
+            system_prompt: "system prompt",
+            contents: &contents[..1],
+            tools: &[],
+            settings: &GenerationSettings::default(),
+        };
+
+        // WHEN
+        let result = provider
+            .generate(&request, &mut |_| {})
+            .expect_err("result wasn't an error");
+
+        // THEN
+        assert_snapshot!(result, @"API returned a non success code: 529 (Overloaded)");
+    }
+}
@@ -0,0 +1,1478 @@
+use super::http::HttpClient;
+use super::{
+    Delta, FinishReason, ModelRequest, ModelResponse, Provider, ProviderConfig, ProviderError,
+    get_env_var, trim_base_url,
+};
+use crate::agent::context::estimate_tokens;
+use crate::agent::domain::{
+    Blob, CodeExecutionResult, Content, ExecutableCode, FileData, FunctionCall,
+    FunctionDeclaration, FunctionResponse, Part, Role, Thought, Usage,
+};
+use crate::agent::generation::GenerationSettings;
+use reqwest::blocking::Client;
+use serde::{Deserialize, Serialize};
+use serde_json::{Map, Value};
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::hash::{DefaultHasher, Hash, Hasher};
+use std::io::BufRead;
+use std::time::{Duration, Instant};
+use tracing::debug;
+
+const API_KEY_ENV_VAR: &str = "GEMINI_API_KEY";
+const BASE_URL: &str = "https://generativelanguage.googleapis.com/v1beta";
+const MODEL: &str = "gemini-2.0-flash";
+/// Input token limit of gemini 1.5 flash and later models (1.5 pro's is twice as large), used when
+/// the models endpoint can't be reached
+const CONTEXT_WINDOW: usize = 1_048_576;
+/// Input token limit assumed for other gemini models (eg. image generation ones) when the models
+/// endpoint can't be reached; on the low side, so that requests aren't sent if they might not fit
+const FALLBACK_CONTEXT_WINDOW: usize = 32_768;
+/// Minimum estimated size of what's cached; gemini rejects caches smaller than 1024 to 4096
+/// tokens, depending on the model
+const MIN_CACHE_TOKENS: usize = 4096;
+/// Caches that expire within this margin aren't used anymore, as they might expire in flight
+const CACHE_EXPIRY_MARGIN: Duration = Duration::from_secs(10);
+
+pub(super) struct GeminiProvider {
+    client: HttpClient,
+    api_key: String,
+    base_url: String,
+    model: String,
+    /// Context caching is disabled if not set
+    cache_ttl: Option<Duration>,
+    cache_state: RefCell<CacheState>,
+    /// Input token limits of models, as returned by the models endpoint; fetched once per model
+    context_windows: RefCell<HashMap<String, Option<usize>>>,
+}
+
+impl GeminiProvider {
+    pub(super) fn new(
+        client: HttpClient,
+        api_key: String,
+        base_url: String,
+        model: String,
+        cache_ttl: Option<Duration>,
+    ) -> Self {
+        Self {
+            client,
+            api_key,
+            base_url,
+            model,
+            cache_ttl,
+            cache_state: RefCell::default(),
+            context_windows: RefCell::default(),
+        }
+    }
+
+    pub(super) fn from_config(client: Client, config: ProviderConfig) -> anyhow::Result<Self> {
+        let api_key = get_env_var(API_KEY_ENV_VAR)?;
+        let base_url = config
+            .base_url
+            .map(trim_base_url)
+            .unwrap_or_else(|| BASE_URL.to_string());
+        let model = config.model.unwrap_or_else(|| MODEL.to_string());
+        let cache_ttl = config
+            .cache_ttl
+            .filter(|ttl| *ttl > 0)
+            .map(Duration::from_secs);
+
+        Ok(Self::new(
+            HttpClient::new(client, config.max_retries),
+            api_key,
+            base_url,
+            model,
+            cache_ttl,
+        ))
+    }
+
+    /// Returns the name of a cache holding the request's system instruction, tools and all but
+    /// the latest turns of its conversation, along with the number of turns it holds, creating
+    /// the cache if needed.
+    ///
+    /// A new cache is created once the conversation has grown by `MIN_CACHE_TOKENS` past what's
+    /// cached, or when the cached turns, system instruction or tools change; caches that are
+    /// about to expire are extended. `None` is returned if caching is disabled, or if there's too
+    /// little to cache.
+    fn get_cache(&self, request: &ModelRequest) -> Option<(String, usize)> {
+        let ttl = self.cache_ttl?;
+        let mut state = self.cache_state.borrow_mut();
+
+        if let Some(cache) = state.cache.take() {
+            let now = Instant::now();
+            let is_valid = cache.turns < request.contents.len()
+                && cache.key == self.get_cache_key(request, cache.turns)
+                && cache.expires_at > now + CACHE_EXPIRY_MARGIN;
+            let uncached_tokens = estimate_tokens(&ModelRequest {
+                system_prompt: "",
+                contents: &request.contents[cache.turns.min(request.contents.len())..],
+                tools: &[],
+                settings: request.settings,
+            });
+
+            if is_valid && uncached_tokens < MIN_CACHE_TOKENS {
+                let mut cache = cache;
+                if cache.expires_at < now + ttl / 2 {
+                    match self.extend_cache(&cache.name, ttl) {
+                        Ok(()) => cache.expires_at = now + ttl,
+                        Err(e) => debug!("couldn't extend cache {}: {e}", cache.name),
+                    }
+                }
+                let cached = (cache.name.clone(), cache.turns);
+                state.cache = Some(cache);
+                return Some(cached);
+            }
+
+            self.delete_cache(&cache.name);
+        }
+
+        let turns = request.contents.len().saturating_sub(1);
+        let cached_tokens = estimate_tokens(&ModelRequest {
+            contents: &request.contents[..turns],
+            ..*request
+        });
+        if cached_tokens < MIN_CACHE_TOKENS
+            || state
+                .failed_at_tokens
+                .is_some_and(|failed| cached_tokens < failed + MIN_CACHE_TOKENS)
+        {
+            return None;
+        }
+
+        match self.create_cache(request, turns, ttl) {
+            Ok(name) => {
+                state.cache = Some(ContextCache {
+                    name: name.clone(),
+                    key: self.get_cache_key(request, turns),
+                    turns,
+                    expires_at: Instant::now() + ttl,
+                });
+                Some((name, turns))
+            }
+            Err(e) => {
+                debug!("couldn't create cache: {e}");
+                state.failed_at_tokens = Some(cached_tokens);
+                None
+            }
+        }
+    }
+
+    fn get_cache_key(&self, request: &ModelRequest, turns: usize) -> u64 {
+        let mut hasher = DefaultHasher::new();
+        self.model.hash(&mut hasher);
+        request.system_prompt.hash(&mut hasher);
+        serde_json::to_string(request.tools)
+            .unwrap_or_default()
+            .hash(&mut hasher);
+        serde_json::to_string(&request.contents[..turns])
+            .unwrap_or_default()
+            .hash(&mut hasher);
+
+        hasher.finish()
+    }
+
+    fn create_cache(
+        &self,
+        request: &ModelRequest,
+        turns: usize,
+        ttl: Duration,
+    ) -> Result<String, ProviderError> {
+        let body = CreateCacheBody {
+            model: format!("models/{}", self.model),
+            system_instruction: SystemInstruction::new(request.system_prompt),
+            tools: vec![Tool::FunctionDeclarations(request.tools)],
+            contents: request.contents[..turns]
+                .iter()
+                .map(GeminiContent::from)
+                .collect(),
+            ttl: format_ttl(ttl),
+        };
+
+        let resp_body = self.client.send(
+            self.client
+                .post(format!("{}/cachedContents", self.base_url))
+                .json(&body)
+                .header("content-type", "application/json")
+                .header("x-goog-api-key", &self.api_key),
+        )?;
+
+        let resp = serde_json::from_str::<CachedContent>(&resp_body)
+            .map_err(|e| ProviderError::InvalidResponse(e.into()))?;
+        debug!("created cache {} holding {turns} turns", resp.name);
+
+        Ok(resp.name)
+    }
+
+    fn extend_cache(&self, name: &str, ttl: Duration) -> Result<(), ProviderError> {
+        self.client.send(
+            self.client
+                .patch(format!("{}/{name}?updateMask=ttl", self.base_url))
+                .json(&serde_json::json!({ "ttl": format_ttl(ttl) }))
+                .header("content-type", "application/json")
+                .header("x-goog-api-key", &self.api_key),
+        )?;
+
+        Ok(())
+    }
+
+    /// Caches expire on their own, so failing to delete one isn't an error
+    fn delete_cache(&self, name: &str) {
+        let result = self.client.send(
+            self.client
+                .delete(format!("{}/{name}", self.base_url))
+                .header("x-goog-api-key", &self.api_key),
+        );
+        if let Err(e) = result {
+            debug!("couldn't delete cache {name}: {e}");
+        }
+    }
+
+    /// Gets the current model's input token limit from the models endpoint, falling back to the
+    /// limits of well known models if it can't be reached
+    fn fetch_context_window(&self) -> Option<usize> {
+        let result = self
+            .client
+            .send(
+                self.client
+                    .get(format!("{}/models/{}", self.base_url, self.model))
+                    .header("x-goog-api-key", &self.api_key),
+            )
+            .and_then(|body| {
+                serde_json::from_str::<ModelInfo>(&body)
+                    .map_err(|e| ProviderError::InvalidResponse(e.into()))
+            });
+
+        match result {
+            Ok(ModelInfo {
+                input_token_limit: Some(limit),
+                ..
+            }) => Some(limit),
+            result => {
+                debug!(
+                    "couldn't get {}'s input token limit: {result:?}",
+                    self.model
+                );
+                get_known_context_window(&self.model)
+            }
+        }
+    }
+}
+
+impl Drop for GeminiProvider {
+    fn drop(&mut self) {
+        if let Some(cache) = self.cache_state.get_mut().cache.take() {
+            self.delete_cache(&cache.name);
+        }
+    }
+}
+
+#[derive(Debug, Default)]
+struct CacheState {
+    cache: Option<ContextCache>,
+    /// Estimated size of what couldn't be cached last time, so that creating a cache is only
+    /// tried again once the conversation has grown
+    failed_at_tokens: Option<usize>,
+}
+
+/// A cache created via gemini's `cachedContents` endpoint
+#[derive(Debug)]
+struct ContextCache {
+    /// Name of the cache, eg. `cachedContents/abc123`
+    name: String,
+    /// Hash of the model, system instruction, tools and cached turns the cache was created for
+    key: u64,
+    /// Number of turns of the conversation the cache holds
+    turns: usize,
+    expires_at: Instant,
+}
+
+fn format_ttl(ttl: Duration) -> String {
+    format!("{}s", ttl.as_secs())
+}
+
+impl Provider for GeminiProvider {
+    fn name(&self) -> &'static str {
+        "Gemini"
+    }
+
+    fn model(&self) -> &str {
+        &self.model
+    }
+
+    fn set_model(&mut self, model: String) {
+        // caches are tied to a model; an outdated one is replaced on the next request
+        self.model = model;
+    }
+
+    fn context_window(&self) -> Option<usize> {
+        if let Some(context_window) = self.context_windows.borrow().get(&self.model) {
+            return *context_window;
+        }
+
+        let context_window = self.fetch_context_window();
+        self.context_windows
+            .borrow_mut()
+            .insert(self.model.clone(), context_window);
+
+        context_window
+    }
+
+    fn supports_attachment(&self, _mime_type: &str) -> bool {
+        // gemini supports all types of files agent1 can attach
+        true
+    }
+
+    fn list_models(&self) -> Result<Vec<String>, ProviderError> {
+        let resp_body = self.client.send(
+            self.client
+                .get(format!("{}/models?pageSize=1000", self.base_url))
+                .header("x-goog-api-key", &self.api_key),
+        )?;
+
+        let resp = serde_json::from_str::<ListModelsResponse>(&resp_body)
+            .map_err(|e| ProviderError::InvalidResponse(e.into()))?;
+
+        let mut context_windows = self.context_windows.borrow_mut();
+        let models = resp
+            .models
+            .into_iter()
+            .inspect(|m| {
+                if let Some(limit) = m.input_token_limit {
+                    let name = m.name.strip_prefix("models/").unwrap_or(&m.name);
+                    context_windows.insert(name.to_string(), Some(limit));
+                }
+            })
+            .filter(|m| {
+                m.supported_generation_methods
+                    .iter()
+                    .any(|method| method == "generateContent")
+            })
+            .map(|m| {
+                m.name
+                    .strip_prefix("models/")
+                    .map(str::to_string)
+                    .unwrap_or(m.name)
+            })
+            .collect();
+
+        Ok(models)
+    }
+
+    fn count_tokens(&self, request: &ModelRequest) -> Result<usize, ProviderError> {
+        let body = CountTokensBody {
+            generate_content_request: CountTokensRequest {
+                model: format!("models/{}", self.model),
+                body: GenerateContentBody::new(request),
+            },
+        };
+
+        let resp_body = self.client.send(
+            self.client
+                .post(format!(
+                    "{}/models/{}:countTokens",
+                    self.base_url, self.model
+                ))
+                .json(&body)
+                .header("content-type", "application/json")
+                .header("x-goog-api-key", &self.api_key),
+        )?;
+
+        let resp = serde_json::from_str::<CountTokensResponse>(&resp_body)
+            .map_err(|e| ProviderError::InvalidResponse(e.into()))?;
+
+        Ok(resp.total_tokens)
+    }
+
+    fn generate(
+        &self,
+        request: &ModelRequest,
+        on_delta: &mut dyn FnMut(Delta),
+    ) -> Result<ModelResponse, ProviderError> {
+        let body = match self.get_cache(request) {
+            Some((name, turns)) => GenerateContentBody::with_cache(request, name, turns),
+            None => GenerateContentBody::new(request),
+        };
+
+        if let Ok(r) = serde_json::to_string_pretty(&body) {
+            debug!("request: {}", &r);
+        } else {
+            debug!("request: {:?}", &body);
+        }
+
+        let resp = self.client.send_for_response(
+            self.client
+                .post(format!(
+                    "{}/models/{}:streamGenerateContent?alt=sse",
+                    self.base_url, self.model
+                ))
+                .json(&body)
+                .header("content-type", "application/json")
+                .header("x-goog-api-key", &self.api_key),
+        )?;
+
+        read_stream(std::io::BufReader::new(resp), on_delta)
+    }
+}
+
+/// Reads a server-sent events stream of `GenerateContentResponse` chunks, passing text to
+/// `on_delta` as it arrives, and assembles the chunks into a single response
+fn read_stream(
+    reader: impl BufRead,
+    on_delta: &mut dyn FnMut(Delta),
+) -> Result<ModelResponse, ProviderError> {
+    let mut parts: Vec<Part> = vec![];
+    let mut usage = None;
+    let mut finish_reason = None;
+
+    for line in reader.lines() {
+        let line = line.map_err(|e| {
+            ProviderError::InvalidResponse(anyhow::anyhow!("couldn't read response stream: {e}"))
+        })?;
+
+        let Some(data) = line.strip_prefix("data:") else {
+            continue;
+        };
+        debug!("response chunk: {}", data);
+
+        let chunk = serde_json::from_str::<GenerateContentResponse>(data.trim())
+            .map_err(|e| ProviderError::InvalidResponse(e.into()))?;
+
+        if let Some(usage_metadata) = chunk.usage_metadata {
+            usage = Some(usage_metadata.into());
+        }
+
+        let Some(candidate) = chunk.candidates.into_iter().next() else {
+            continue;
+        };
+        if let Some(reason) = candidate.finish_reason {
+            finish_reason = Some(parse_finish_reason(&reason));
+        }
+        let Some(content) = candidate.content else {
+            continue;
+        };
+
+        for part in content.parts.into_iter().filter_map(GeminiPart::into_part) {
+            match (part, parts.last_mut()) {
+                (Part::Text(delta), Some(Part::Text(text))) => {
+                    on_delta(Delta::Text(&delta));
+                    text.push_str(&delta);
+                }
+                (Part::Text(delta), _) => {
+                    on_delta(Delta::Text(&delta));
+                    parts.push(Part::Text(delta));
+                }
+                (Part::Thought(delta), Some(Part::Thought(thought))) => {
+                    on_delta(Delta::Thought(&delta.text));
+                    thought.text.push_str(&delta.text);
+                    if delta.signature.is_some() {
+                        thought.signature = delta.signature;
+                    }
+                }
+                (Part::Thought(delta), _) => {
+                    on_delta(Delta::Thought(&delta.text));
+                    parts.push(Part::Thought(delta));
+                }
+                (part, _) => parts.push(part),
+            }
+        }
+    }
+
+    Ok(ModelResponse {
+        parts,
+        usage,
+        finish_reason,
+    })
+}
+
+fn parse_finish_reason(reason: &str) -> FinishReason {
+    match reason {
+        "STOP" => FinishReason::Stop,
+        "MAX_TOKENS" => FinishReason::MaxTokens,
+        "SAFETY" | "BLOCKLIST" | "PROHIBITED_CONTENT" | "SPII" => FinishReason::Safety,
+        "RECITATION" => FinishReason::Recitation,
+        "MALFORMED_FUNCTION_CALL" => FinishReason::MalformedFunctionCall,
+        other => FinishReason::Other(other.to_string()),
+    }
+}
+
+#[derive(Debug, Serialize)]
+struct GenerateContentBody<'a> {
+    contents: Vec<GeminiContent>,
+    /// Part of the cache when using one
+    #[serde(skip_serializing_if = "Option::is_none")]
+    system_instruction: Option<SystemInstruction>,
+    /// Part of the cache when using one
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    tools: Vec<Tool<'a>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    cached_content: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    generation_config: Option<GenerationConfig<'a>>,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    safety_settings: Vec<SafetySetting<'a>>,
+}
+
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+struct GenerationConfig<'a> {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    temperature: Option<f64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    top_p: Option<f64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    max_output_tokens: Option<u32>,
+    #[serde(skip_serializing_if = "<[String]>::is_empty")]
+    stop_sequences: &'a [String],
+    #[serde(skip_serializing_if = "Option::is_none")]
+    seed: Option<i64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    thinking_config: Option<ThinkingConfig>,
+}
+
+impl<'a> GenerationConfig<'a> {
+    /// Returns `None` if no setting that's part of the generation config is set
+    fn new(settings: &'a GenerationSettings) -> Option<Self> {
+        let config = Self {
+            temperature: settings.temperature,
+            top_p: settings.top_p,
+            max_output_tokens: settings.max_output_tokens,
+            stop_sequences: &settings.stop_sequences,
+            seed: settings.seed,
+            thinking_config: (settings.thinking_budget.is_some()
+                || settings.include_thoughts.is_some())
+            .then_some(ThinkingConfig {
+                thinking_budget: settings.thinking_budget,
+                include_thoughts: settings.include_thoughts,
+            }),
+        };
+
+        let is_empty = config.temperature.is_none()
+            && config.top_p.is_none()
+            && config.max_output_tokens.is_none()
+            && config.stop_sequences.is_empty()
+            && config.seed.is_none()
+            && config.thinking_config.is_none();
+
+        (!is_empty).then_some(config)
+    }
+}
+
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+struct ThinkingConfig {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    thinking_budget: Option<i32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    include_thoughts: Option<bool>,
+}
+
+#[derive(Debug, Serialize)]
+struct SafetySetting<'a> {
+    category: &'a str,
+    threshold: &'a str,
+}
+
+impl<'a> GenerateContentBody<'a> {
+    fn new(request: &ModelRequest<'a>) -> Self {
+        Self {
+            contents: request.contents.iter().map(GeminiContent::from).collect(),
+            system_instruction: Some(SystemInstruction::new(request.system_prompt)),
+            tools: vec![Tool::FunctionDeclarations(request.tools)],
+            cached_content: None,
+            generation_config: GenerationConfig::new(request.settings),
+            safety_settings: request
+                .settings
+                .safety_settings
+                .iter()
+                .map(|(category, threshold)| SafetySetting {
+                    category,
+                    threshold,
+                })
+                .collect(),
+        }
+    }
+}
+
+/// Counting the tokens of a whole `generateContent` request (rather than just its contents)
+/// includes the system instruction and tools in the count
+#[derive(Debug, Serialize)]
+struct CountTokensBody<'a> {
+    generate_content_request: CountTokensRequest<'a>,
+}
+
+#[derive(Debug, Serialize)]
+struct CountTokensRequest<'a> {
+    model: String,
+    #[serde(flatten)]
+    body: GenerateContentBody<'a>,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct CountTokensResponse {
+    #[serde(default)]
+    total_tokens: usize,
+}
+
+impl<'a> GenerateContentBody<'a> {
+    /// Only sends the turns that aren't part of the cache
+    fn with_cache(request: &ModelRequest<'a>, cache_name: String, cached_turns: usize) -> Self {
+        let mut body = Self::new(&ModelRequest {
+            contents: &request.contents[cached_turns..],
+            ..*request
+        });
+        body.system_instruction = None;
+        body.tools = vec![];
+        body.cached_content = Some(cache_name);
+
+        body
+    }
+}
+
+#[derive(Debug, Serialize)]
+struct CreateCacheBody<'a> {
+    model: String,
+    system_instruction: SystemInstruction,
+    tools: Vec<Tool<'a>>,
+    contents: Vec<GeminiContent>,
+    ttl: String,
+}
+
+#[derive(Debug, Deserialize)]
+struct CachedContent {
+    name: String,
+}
+
+#[derive(Debug, Serialize)]
+struct SystemInstruction {
+    parts: Vec<GeminiPart>,
+}
+
+impl SystemInstruction {
+    fn new(text: &str) -> Self {
+        Self {
+            parts: vec![GeminiPart {
+                text: Some(text.to_string()),
+                ..Default::default()
+            }],
+        }
+    }
+}
+
+/// Gemini's representation of `Content`, in which thoughts and thought signatures are flags next
+/// to a part's data rather than parts of their own
+#[derive(Debug, Serialize, Deserialize)]
+struct GeminiContent {
+    #[serde(default)]
+    parts: Vec<GeminiPart>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    role: Option<Role>,
+}
+
+#[derive(Debug, Default, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct GeminiPart {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    text: Option<String>,
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    thought: bool,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    thought_signature: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    function_call: Option<FunctionCall>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    function_response: Option<FunctionResponse>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    inline_data: Option<Blob>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    file_data: Option<FileData>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    executable_code: Option<ExecutableCode>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    code_execution_result: Option<CodeExecutionResult>,
+    /// Fields of part types agent1 doesn't know about
+    #[serde(flatten)]
+    other: Map<String, Value>,
+}
+
+impl From<&Content> for GeminiContent {
+    fn from(content: &Content) -> Self {
+        Self {
+            parts: content
+                .parts
+                .iter()
+                .filter_map(GeminiPart::from_part)
+                .collect(),
+            role: content.role,
+        }
+    }
+}
+
+impl GeminiPart {
+    /// Returns `None` for parts gemini can't be sent, ie. unknown parts that aren't objects, which
+    /// gemini never produces
+    fn from_part(part: &Part) -> Option<Self> {
+        let part = match part {
+            Part::Text(text) => Self {
+                text: Some(text.clone()),
+                ..Default::default()
+            },
+            Part::Thought(thought) => Self {
+                text: Some(thought.text.clone()),
+                thought: true,
+                thought_signature: thought.signature.clone(),
+                ..Default::default()
+            },
+            Part::FunctionCall(call) => {
+                let mut call = call.clone();
+                Self {
+                    thought_signature: call.thought_signature.take(),
+                    function_call: Some(call),
+                    ..Default::default()
+                }
+            }
+            Part::FunctionResponse(response) => Self {
+                function_response: Some(response.clone()),
+                ..Default::default()
+            },
+            Part::InlineData(blob) => Self {
+                inline_data: Some(blob.clone()),
+                ..Default::default()
+            },
+            Part::FileData(file_data) => Self {
+                file_data: Some(file_data.clone()),
+                ..Default::default()
+            },
+            Part::ExecutableCode(code) => Self {
+                executable_code: Some(code.clone()),
+                ..Default::default()
+            },
+            Part::CodeExecutionResult(result) => Self {
+                code_execution_result: Some(result.clone()),
+                ..Default::default()
+            },
+            Part::Unknown(Value::Object(fields)) => Self {
+                other: fields.clone(),
+                ..Default::default()
+            },
+            Part::Unknown(_) => return None,
+        };
+
+        Some(part)
+    }
+
+    /// Returns `None` for parts that carry nothing agent1 keeps.
+    ///
+    /// Signatures that come with text parts are dropped, since gemini only requires the ones that
+    /// come with function calls to be sent back.
+    fn into_part(self) -> Option<Part> {
+        if let Some(mut call) = self.function_call {
+            call.thought_signature = self.thought_signature;
+            return Some(Part::FunctionCall(call));
+        }
+
+        let part = if let Some(response) = self.function_response {
+            Part::FunctionResponse(response)
+        } else if let Some(blob) = self.inline_data {
+            Part::InlineData(blob)
+        } else if let Some(file_data) = self.file_data {
+            Part::FileData(file_data)
+        } else if let Some(code) = self.executable_code {
+            Part::ExecutableCode(code)
+        } else if let Some(result) = self.code_execution_result {
+            Part::CodeExecutionResult(result)
+        } else if let Some(text) = self.text {
+            if self.thought {
+                Part::Thought(Thought {
+                    text,
+                    signature: self.thought_signature,
+                    redacted: false,
+                })
+            } else {
+                Part::Text(text)
+            }
+        } else if !self.other.is_empty() {
+            let mut fields = self.other;
+            if self.thought {
+                fields.insert("thought".to_string(), Value::Bool(true));
+            }
+            if let Some(signature) = self.thought_signature {
+                fields.insert("thoughtSignature".to_string(), Value::String(signature));
+            }
+            Part::Unknown(Value::Object(fields))
+        } else {
+            return None;
+        };
+
+        Some(part)
+    }
+}
+
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+enum Tool<'a> {
+    FunctionDeclarations(&'a [FunctionDeclaration]),
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct GenerateContentResponse {
+    #[serde(default)]
+    candidates: Vec<Candidate>,
+    usage_metadata: Option<UsageMetadata>,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct Candidate {
+    content: Option<GeminiContent>,
+    finish_reason: Option<String>,
+}
+
+#[derive(Debug, Deserialize)]
+struct ListModelsResponse {
+    #[serde(default)]
+    models: Vec<ModelInfo>,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct ModelInfo {
+    name: String,
+    #[serde(default)]
+    supported_generation_methods: Vec<String>,
+    input_token_limit: Option<usize>,
+}
+
+/// Returns the input token limit of well known gemini models, for when the models endpoint can't
+/// be reached
+fn get_known_context_window(model: &str) -> Option<usize> {
+    if model.starts_with("gemini-1.5-pro") {
+        Some(2 * CONTEXT_WINDOW)
+    } else if model.contains("image") || model.contains("tts") || model.contains("embedding") {
+        Some(FALLBACK_CONTEXT_WINDOW)
+    } else if ["gemini-1.5-", "gemini-2.", "gemini-3"]
+        .iter()
+        .any(|prefix| model.starts_with(prefix))
+    {
+        Some(CONTEXT_WINDOW)
+    } else if model.starts_with("gemini-") {
+        Some(FALLBACK_CONTEXT_WINDOW)
+    } else {
+        None
+    }
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct UsageMetadata {
+    #[serde(default)]
+    prompt_token_count: usize,
+    #[serde(default)]
+    cached_content_token_count: usize,
+    #[serde(default)]
+    candidates_token_count: usize,
+    #[serde(default)]
+    thoughts_token_count: usize,
+    #[serde(default)]
+    total_token_count: usize,
+}
+
+impl From<UsageMetadata> for Usage {
+    fn from(value: UsageMetadata) -> Self {
+        Self {
+            prompt_tokens: value.prompt_token_count,
+            cached_tokens: value.cached_content_token_count,
+            output_tokens: value.candidates_token_count,
+            thinking_tokens: value.thoughts_token_count,
+            total_tokens: value.total_token_count,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::agent::domain::FunctionDeclarationName;
+    use insta::assert_snapshot;
+    use mockito::Matcher;
+    use serde_json::json;
+
+    const STREAMED_RESPONSE: &str = r#"data: {"candidates": [{"content": {"parts": [{"text": "**Reading the manifest**","thought": true}],"role": "model"}}],"usageMetadata": {"promptTokenCount": 90,"totalTokenCount": 90}}
+
+data: {"candidates": [{"content": {"parts": [{"text": "Let me "}],"role": "model"}}],"usageMetadata": {"promptTokenCount": 90,"totalTokenCount": 90}}
+
+data: {"candidates": [{"content": {"parts": [{"text": "check that file."}],"role": "model"}}],"usageMetadata": {"promptTokenCount": 90,"totalTokenCount": 90}}
+
+data: {"candidates": [{"content": {"parts": [{"functionCall": {"name": "read_file","args": {"path": "Cargo.toml"}},"thoughtSignature": "c2lnbmF0dXJl"}],"role": "model"},"finishReason": "STOP"}],"usageMetadata": {"promptTokenCount": 90,"cachedContentTokenCount": 64,"candidatesTokenCount": 20,"thoughtsTokenCount": 12,"totalTokenCount": 122}}
+
+"#;
+
+    //-------------//
+    //  SUCCESSES  //
+    //-------------//
+
+    #[test]
+    fn streamed_chunks_are_assembled_into_a_single_response() {
+        // GIVEN
+        let mut deltas = vec![];
+
+        // WHEN
+        let response = read_stream(STREAMED_RESPONSE.as_bytes(), &mut |delta| {
+            deltas.push(format!("{delta:?}"));
+        })
+        .expect("result should've been a success");
+
+        // THEN
+        assert_eq!(
+            deltas,
+            vec![
+                r#"Thought("**Reading the manifest**")"#,
+                r#"Text("Let me ")"#,
+                r#"Text("check that file.")"#
+            ]
+        );
+        assert_snapshot!(format!("{:#?}", response.parts), @r#"
+        [
+            Thought(
+                Thought {
+                    text: "**Reading the manifest**",
+                    signature: None,
+                    redacted: false,
+                },
+            ),
+            Text(
+                "Let me check that file.",
+            ),
+            FunctionCall(
+                FunctionCall {
+                    id: None,
+                    name: ReadFile,
+                    args: Some(
+                        Object {
+                            "path": String("Cargo.toml"),
+                        },
+                    ),
+                    thought_signature: Some(
+                        "c2lnbmF0dXJl",
+                    ),
+                },
+            ),
+        ]
+        "#);
+        let usage = response.usage.expect("usage should've been present");
+        assert_eq!(
+            usage,
+            Usage {
+                prompt_tokens: 90,
+                cached_tokens: 64,
+                output_tokens: 20,
+                thinking_tokens: 12,
+                total_tokens: 122,
+            }
+        );
+        assert_eq!(response.finish_reason, Some(FinishReason::Stop));
+    }
+
+    #[test]
+    fn streaming_endpoint_is_used_for_generating_content() {
+        // GIVEN
+        let mut server = mockito::Server::new();
+        let mock = server
+            .mock(
+                "POST",
+                "/models/gemini-2.0-flash:streamGenerateContent?alt=sse",
+            )
+            .match_header("x-goog-api-key", "test-key")
+            .with_status(200)
+            .with_header("content-type", "text/event-stream")
+            .with_body(STREAMED_RESPONSE)
+            .create();
+        let provider = GeminiProvider::new(
+            HttpClient::new(Client::new(), None),
+            "test-key".to_string(),
+            server.url(),
+            MODEL.to_string(),
+            None,
+        );
+        let request = ModelRequest {
+            system_prompt: "system prompt",
+            contents: &[],
+            tools: &[],
+            settings: &GenerationSettings::default(),
+        };
+
+        // WHEN
+        let response = provider
+            .generate(&request, &mut |_| {})
+            .expect("result should've been a success");
+
+        // THEN
+        mock.assert();
+        assert_eq!(response.parts.len(), 3);
+    }
+
+    #[test]
+    fn thoughts_and_thought_signatures_are_sent_back() {
+        // GIVEN
+        let contents = vec![Content {
+            parts: vec![
+                Part::Thought(Thought {
+                    text: "**Reading the manifest**".to_string(),
+                    signature: None,
+                    redacted: false,
+                }),
+                Part::FunctionCall(FunctionCall {
+                    id: None,
+                    name: FunctionDeclarationName::ReadFile,
+                    args: Some(serde_json::json!({"path": "Cargo.toml"})),
+                    thought_signature: Some("c2lnbmF0dXJl".to_string()),
+                }),
+            ],
+            role: Some(Role::Model),
+        }];
+        let request = ModelRequest {
+            system_prompt: "system prompt",
+            contents: &contents,
+            tools: &[],
+            settings: &GenerationSettings::default(),
+        };
+
+        // WHEN
+        let body = GenerateContentBody::new(&request);
+
+        // THEN
+        let contents = serde_json::to_string_pretty(&body.contents)
+            .expect("contents should've been serialized");
+        assert_snapshot!(contents, @r#"
+        [
+          {
+            "parts": [
+              {
+                "text": "**Reading the manifest**",
+                "thought": true
+              },
+              {
+                "thoughtSignature": "c2lnbmF0dXJl",
+                "functionCall": {
+                  "id": null,
+                  "name": "read_file",
+                  "args": {
+                    "path": "Cargo.toml"
+                  }
+                }
+              }
+            ],
+            "role": "model"
+          }
+        ]
+        "#);
+    }
+
+    #[test]
+    fn all_part_types_are_parsed_and_sent_back_as_is() {
+        // GIVEN
+        let stream = r#"data: {"candidates": [{"content": {"parts": [{"executableCode": {"language": "PYTHON","code": "print(6 * 7)"}},{"codeExecutionResult": {"outcome": "OUTCOME_OK","output": "42\n"}},{"inlineData": {"mimeType": "image/png","data": "iVBORw0KGgo="}},{"fileData": {"mimeType": "application/pdf","fileUri": "https://generativelanguage.googleapis.com/v1beta/files/abc"}},{"newPartType": {"value": 1},"thought": true,"thoughtSignature": "c2lnbmF0dXJl"}],"role": "model"},"finishReason": "STOP"}]}
+
+"#;
+
+        // WHEN
+        let response =
+            read_stream(stream.as_bytes(), &mut |_| {}).expect("result should've been a success");
+
+        // THEN
+        let contents = vec![Content {
+            parts: response.parts,
+            role: Some(Role::Model),
+        }];
+        let request = ModelRequest {
+            system_prompt: "system prompt",
+            contents: &contents,
+            tools: &[],
+            settings: &GenerationSettings::default(),
+        };
+        let body = GenerateContentBody::new(&request);
+        let contents = serde_json::to_string_pretty(&body.contents)
+            .expect("contents should've been serialized");
+        assert_snapshot!(contents, @r#"
+        [
+          {
+            "parts": [
+              {
+                "executableCode": {
+                  "language": "PYTHON",
+                  "code": "print(6 * 7)"
+                }
+              },
+              {
+                "codeExecutionResult": {
+                  "outcome": "OUTCOME_OK",
+                  "output": "42\n"
+                }
+              },
+              {
+                "inlineData": {
+                  "mimeType": "image/png",
+                  "data": "iVBORw0KGgo="
+                }
+              },
+              {
+                "fileData": {
+                  "mimeType": "application/pdf",
+                  "fileUri": "https://generativelanguage.googleapis.com/v1beta/files/abc"
+                }
+              },
+              {
+                "newPartType": {
+                  "value": 1
+                },
+                "thought": true,
+                "thoughtSignature": "c2lnbmF0dXJl"
+              }
+            ],
+            "role": "model"
+          }
+        ]
+        "#);
+    }
+
+    #[test]
+    fn unknown_parts_that_arent_objects_are_left_out() {
+        // GIVEN
+        let contents = vec![Content {
+            parts: vec![
+                Part::Unknown(Value::String("not a part".to_string())),
+                Part::Text("hi".to_string()),
+            ],
+            role: Some(Role::User),
+        }];
+        let request = ModelRequest {
+            system_prompt: "system prompt",
+            contents: &contents,
+            tools: &[],
+            settings: &GenerationSettings::default(),
+        };
+
+        // WHEN
+        let body = GenerateContentBody::new(&request);
+
+        // THEN
+        let contents =
+            serde_json::to_string(&body.contents).expect("contents should've been serialized");
+        assert_snapshot!(contents, @r#"[{"parts":[{"text":"hi"}],"role":"user"}]"#);
+    }
+
+    #[test]
+    fn generation_and_safety_settings_are_sent_when_set() {
+        // GIVEN
+        let mut settings = GenerationSettings {
+            temperature: Some(0.2),
+            max_output_tokens: Some(1024),
+            thinking_budget: Some(0),
+            ..Default::default()
+        };
+        settings.safety_settings.insert(
+            "HARM_CATEGORY_DANGEROUS_CONTENT".to_string(),
+            "BLOCK_ONLY_HIGH".to_string(),
+        );
+        let request = ModelRequest {
+            system_prompt: "system prompt",
+            contents: &[],
+            tools: &[],
+            settings: &settings,
+        };
+
+        // WHEN
+        let body = GenerateContentBody::new(&request);
+
+        // THEN
+        let body = serde_json::to_string_pretty(&body).expect("body should've been serialized");
+        assert_snapshot!(body, @r#"
+        {
+          "contents": [],
+          "system_instruction": {
+            "parts": [
+              {
+                "text": "system prompt"
+              }
+            ]
+          },
+          "tools": [
+            {
+              "functionDeclarations": []
+            }
+          ],
+          "generation_config": {
+            "temperature": 0.2,
+            "maxOutputTokens": 1024,
+            "thinkingConfig": {
+              "thinkingBudget": 0
+            }
+          },
+          "safety_settings": [
+            {
+              "category": "HARM_CATEGORY_DANGEROUS_CONTENT",
+              "threshold": "BLOCK_ONLY_HIGH"
+            }
+          ]
+        }
+        "#);
+    }
+
+    #[test]
+    fn tokens_of_the_whole_request_are_counted() {
+        // GIVEN
+        let mut server = mockito::Server::new();
+        let mock = server
+            .mock("POST", "/models/gemini-2.0-flash:countTokens")
+            .match_header("x-goog-api-key", "test-key")
+            .match_body(Matcher::PartialJson(json!({
+                "generate_content_request": {
+                    "model": "models/gemini-2.0-flash",
+                    "system_instruction": {"parts": [{"text": "system prompt"}]},
+                },
+            })))
+            .with_status(200)
+            .with_body(r#"{"totalTokens": 31}"#)
+            .create();
+        let provider = GeminiProvider::new(
+            HttpClient::new(Client::new(), None),
+            "test-key".to_string(),
+            server.url(),
+            MODEL.to_string(),
+            None,
+        );
+        let request = ModelRequest {
+            system_prompt: "system prompt",
+            contents: &[],
+            tools: &[],
+            settings: &GenerationSettings::default(),
+        };
+
+        // WHEN
+        let tokens = provider
+            .count_tokens(&request)
+            .expect("result should've been a success");
+
+        // THEN
+        mock.assert();
+        assert_eq!(tokens, 31);
+    }
+
+    #[test]
+    fn older_turns_are_cached_and_the_cache_is_reused() {
+        // GIVEN
+        let mut server = mockito::Server::new();
+        let create_cache = server
+            .mock("POST", "/cachedContents")
+            .match_body(Matcher::PartialJson(json!({
+                "model": "models/gemini-2.0-flash",
+                "system_instruction": {"parts": [{"text": "system prompt"}]},
+                "ttl": "300s",
+            })))
+            .with_status(200)
+            .with_body(r#"{"name": "cachedContents/abc123"}"#)
+            .expect(1)
+            .create();
+        let generate = server
+            .mock(
+                "POST",
+                "/models/gemini-2.0-flash:streamGenerateContent?alt=sse",
+            )
+            .match_body(Matcher::PartialJson(json!({
+                "cached_content": "cachedContents/abc123",
+            })))
+            .with_status(200)
+            .with_header("content-type", "text/event-stream")
+            .with_body(STREAMED_RESPONSE)
+            .expect(2)
+            .create();
+        let delete_cache = server
+            .mock("DELETE", "/cachedContents/abc123")
+            .with_status(200)
+            .with_body("{}")
+            .create();
+        let provider = GeminiProvider::new(
+            HttpClient::new(Client::new(), None),
+            "test-key".to_string(),
+            server.url(),
+            MODEL.to_string(),
+            Some(Duration::from_secs(300)),
+        );
+        let mut contents = vec![
+            Content {
+                parts: vec![Part::Text("a long document ".repeat(2000))],
+                role: Some(Role::User),
+            },
+            Content {
+                parts: vec![Part::Text("got it".to_string())],
+                role: Some(Role::Model),
+            },
+            Content {
+                parts: vec![Part::Text("summarize it".to_string())],
+                role: Some(Role::User),
+            },
+        ];
+        let settings = GenerationSettings::default();
+
+        // WHEN
+        let request = ModelRequest {
+            system_prompt: "system prompt",
+            contents: &contents,
+            tools: &[],
+            settings: &settings,
+        };
+        provider
+            .generate(&request, &mut |_| {})
+            .expect("first result should've been a success");
+        let body = serde_json::to_string_pretty(&GenerateContentBody::with_cache(
+            &request,
+            "cachedContents/abc123".to_string(),
+            2,
+        ))
+        .expect("body should've been serialized");
+
+        contents.push(Content {
+            parts: vec![Part::Text("it's about caching".to_string())],
+            role: Some(Role::Model),
+        });
+        contents.push(Content {
+            parts: vec![Part::Text("thanks".to_string())],
+            role: Some(Role::User),
+        });
+        let request = ModelRequest {
+            system_prompt: "system prompt",
+            contents: &contents,
+            tools: &[],
+            settings: &settings,
+        };
+        provider
+            .generate(&request, &mut |_| {})
+            .expect("second result should've been a success");
+        drop(provider);
+
+        // THEN
+        create_cache.assert();
+        generate.assert();
+        delete_cache.assert();
+        assert_snapshot!(body, @r#"
+        {
+          "contents": [
+            {
+              "parts": [
+                {
+                  "text": "summarize it"
+                }
+              ],
+              "role": "user"
+            }
+          ],
+          "cached_content": "cachedContents/abc123"
+        }
+        "#);
+    }
+
+    #[test]
+    fn models_that_can_generate_content_are_listed() {
+        // GIVEN
+        let mut server = mockito::Server::new();
+        let mock = server
+            .mock("GET", "/models?pageSize=1000")
+            .match_header("x-goog-api-key", "test-key")
+            .with_status(200)
+            .with_body(
+                r#"{
+  "models": [
+    { "name": "models/gemini-2.5-flash", "supportedGenerationMethods": ["generateContent", "countTokens"] },
+    { "name": "models/text-embedding-004", "supportedGenerationMethods": ["embedContent"] },
+    { "name": "models/gemini-2.5-pro", "supportedGenerationMethods": ["generateContent"] }
+  ]
+}"#,
+            )
+            .create();
+        let provider = GeminiProvider::new(
+            HttpClient::new(Client::new(), None),
+            "test-key".to_string(),
+            server.url(),
+            MODEL.to_string(),
+            None,
+        );
+
+        // WHEN
+        let models = provider
+            .list_models()
+            .expect("result should've been a success");
+
+        // THEN
+        mock.assert();
+        assert_eq!(models, vec!["gemini-2.5-flash", "gemini-2.5-pro"]);
+    }
+
+    #[test]
+    fn context_window_is_read_from_the_models_endpoint_once() {
+        // GIVEN
+        let mut server = mockito::Server::new();
+        let mock = server
+            .mock("GET", "/models/gemini-2.0-flash-preview-image-generation")
+            .match_header("x-goog-api-key", "test-key")
+            .with_status(200)
+            .with_body(
+                r#"{ "name": "models/gemini-2.0-flash-preview-image-generation", "inputTokenLimit": 32000, "outputTokenLimit": 8192 }"#,
+            )
+            .expect(1)
+            .create();
+        let provider = GeminiProvider::new(
+            HttpClient::new(Client::new(), None),
+            "test-key".to_string(),
+            server.url(),
+            "gemini-2.0-flash-preview-image-generation".to_string(),
+            None,
+        );
+
+        // WHEN
+        let context_windows = [provider.context_window(), provider.context_window()];
+
+        // THEN
+        mock.assert();
+        assert_eq!(context_windows, [Some(32_000); 2]);
+    }
+
+    #[test]
+    fn context_window_falls_back_to_known_models() {
+        // GIVEN
+        let mut server = mockito::Server::new();
+        server
+            .mock("GET", mockito::Matcher::Any)
+            .with_status(404)
+            .create();
+        let provider = |model: &str| {
+            GeminiProvider::new(
+                HttpClient::new(Client::new(), Some(0)),
+                "test-key".to_string(),
+                server.url(),
+                model.to_string(),
+                None,
+            )
+        };
+
+        // WHEN
+        let context_windows = [
+            "gemini-2.5-pro",
+            "gemini-1.5-pro-002",
+            "gemini-exp",
+            "gemma-3",
+        ]
+        .map(|model| provider(model).context_window());
+
+        // THEN
+        assert_eq!(
+            context_windows,
+            [
+                Some(CONTEXT_WINDOW),
+                Some(2 * CONTEXT_WINDOW),
+                Some(FALLBACK_CONTEXT_WINDOW),
+                None
+            ]
+        );
+    }
+}
@@ -0,0 +1,409 @@
+use super::ProviderError;
+use colored::Colorize;
+use reqwest::blocking::{Client, RequestBuilder, Response};
+use reqwest::header::{HeaderMap, RETRY_AFTER};
+use serde::Deserialize;
+use std::time::Duration;
+use tracing::debug;
+
+const DEFAULT_MAX_RETRIES: u32 = 3;
+const BASE_RETRY_DELAY: Duration = Duration::from_secs(1);
+const MAX_BACKOFF_DELAY: Duration = Duration::from_secs(30);
+// servers sometimes ask to come back much later (eg. when a daily quota is exhausted); it's
+// better to surface the error than to hang the session in such cases
+const MAX_RETRY_DELAY: Duration = Duration::from_secs(120);
+// error bodies that aren't JSON can be whole HTML pages (eg. from proxies)
+const MAX_ERROR_MESSAGE_CHARS: usize = 300;
+
+/// A blocking HTTP client that retries requests that fail with 429 or 5xx status codes
+pub(super) struct HttpClient {
+    client: Client,
+    max_retries: u32,
+}
+
+impl HttpClient {
+    pub(super) fn new(client: Client, max_retries: Option<u32>) -> Self {
+        Self {
+            client,
+            max_retries: max_retries.unwrap_or(DEFAULT_MAX_RETRIES),
+        }
+    }
+
+    pub(super) fn get(&self, url: String) -> RequestBuilder {
+        self.client.get(url)
+    }
+
+    pub(super) fn post(&self, url: String) -> RequestBuilder {
+        self.client.post(url)
+    }
+
+    pub(super) fn patch(&self, url: String) -> RequestBuilder {
+        self.client.patch(url)
+    }
+
+    pub(super) fn delete(&self, url: String) -> RequestBuilder {
+        self.client.delete(url)
+    }
+
+    /// Sends a request and returns the body of a successful response
+    pub(super) fn send(&self, request: RequestBuilder) -> Result<String, ProviderError> {
+        let resp = self.send_for_response(request)?;
+
+        let resp_body = resp.text().map_err(|e| {
+            ProviderError::InvalidResponse(anyhow::anyhow!("couldn't get text: {e}"))
+        })?;
+        debug!("response: {}", &resp_body);
+
+        Ok(resp_body)
+    }
+
+    /// Sends a request and returns a successful response without reading its body
+    pub(super) fn send_for_response(
+        &self,
+        request: RequestBuilder,
+    ) -> Result<Response, ProviderError> {
+        let mut attempt = 0;
+
+        loop {
+            // bodies of requests sent by agent1 are always buffered, so this always succeeds
+            let Some(current_request) = request.try_clone() else {
+                return send_once(request).map_err(Failure::into_error);
+            };
+
+            let (error, requested_delay) = match send_once(current_request) {
+                Ok(resp) => return Ok(resp),
+                Err(Failure::Final(e)) => return Err(e),
+                Err(Failure::Retryable {
+                    error,
+                    requested_delay,
+                }) => (error, requested_delay),
+            };
+
+            if attempt >= self.max_retries {
+                return Err(error);
+            }
+
+            let delay = requested_delay.unwrap_or_else(|| backoff_delay(attempt, fastrand::f64()));
+            if delay > MAX_RETRY_DELAY {
+                return Err(error);
+            }
+
+            attempt += 1;
+            eprintln!(
+                "{}",
+                format!(
+                    "{error}; retrying in {}s (attempt {attempt}/{})",
+                    delay.as_secs_f64().ceil(),
+                    self.max_retries
+                )
+                .dimmed()
+            );
+            std::thread::sleep(delay);
+        }
+    }
+}
+
+enum Failure {
+    Final(ProviderError),
+    Retryable {
+        error: ProviderError,
+        /// Delay the server asked for, if any
+        requested_delay: Option<Duration>,
+    },
+}
+
+impl Failure {
+    fn into_error(self) -> ProviderError {
+        match self {
+            Failure::Final(e) => e,
+            Failure::Retryable { error, .. } => error,
+        }
+    }
+}
+
+fn send_once(request: RequestBuilder) -> Result<Response, Failure> {
+    let resp = request
+        .send()
+        .map_err(|e| Failure::Final(ProviderError::Request(e)))?;
+
+    if resp.status().is_success() {
+        return Ok(resp);
+    }
+
+    let status = resp.status().as_u16();
+    let headers = resp.headers().clone();
+    let body = match resp.text() {
+        Ok(t) => {
+            debug!("non success response (code: {status}): {}", &t);
+            t
+        }
+        Err(e) => {
+            debug!("non success response (code: {status}), couldn't get text: {e}",);
+            String::new()
+        }
+    };
+
+    let error = ProviderError::ErrorResponse {
+        status,
+        message: get_error_message(&body),
+    };
+    if !is_retryable(status) {
+        return Err(Failure::Final(error));
+    }
+
+    Err(Failure::Retryable {
+        error,
+        requested_delay: get_requested_delay(&headers, &body),
+    })
+}
+
+fn is_retryable(status: u16) -> bool {
+    status == 429 || ((500..600).contains(&status) && status != 501 && status != 505)
+}
+
+fn get_requested_delay(headers: &HeaderMap, body: &str) -> Option<Duration> {
+    // TODO: Retry-After can also be an HTTP date; handle that
+    headers
+        .get(RETRY_AFTER)
+        .and_then(|v| v.to_str().ok())
+        .and_then(|v| v.trim().parse::<u64>().ok())
+        .map(Duration::from_secs)
+        .or_else(|| get_delay_from_retry_info(body))
+}
+
+#[derive(Debug, Deserialize)]
+struct ErrorBody {
+    error: ErrorDetails,
+}
+
+#[derive(Debug, Deserialize)]
+struct ErrorDetails {
+    #[serde(default)]
+    details: Vec<serde_json::Value>,
+}
+
+/// Returns the message of an error response, truncated to `MAX_ERROR_MESSAGE_CHARS`; it's read
+/// from the `error.message` (gemini, anthropic, openai) or `error` (ollama) field when the body is
+/// JSON, and the body itself is used otherwise
+fn get_error_message(body: &str) -> String {
+    let json = serde_json::from_str::<serde_json::Value>(body).ok();
+    let message = json
+        .as_ref()
+        .and_then(|json| json.get("error"))
+        .and_then(|error| error.get("message").unwrap_or(error).as_str())
+        .unwrap_or(body)
+        .trim();
+
+    match message.char_indices().nth(MAX_ERROR_MESSAGE_CHARS) {
+        Some((end, _)) => format!("{}...", &message[..end]),
+        None => message.to_string(),
+    }
+}
+
+/// Extracts the delay from a `google.rpc.RetryInfo` entry in the error's details, which Gemini
+/// includes in some of its error responses, eg.
+///
+/// ```json
+/// {"error": {"details": [{"@type": "type.googleapis.com/google.rpc.RetryInfo", "retryDelay": "37s"}]}}
+/// ```
+fn get_delay_from_retry_info(body: &str) -> Option<Duration> {
+    let body = serde_json::from_str::<ErrorBody>(body).ok()?;
+
+    body.error.details.iter().find_map(|detail| {
+        let is_retry_info = detail
+            .get("@type")
+            .and_then(|t| t.as_str())
+            .is_some_and(|t| t.ends_with("google.rpc.RetryInfo"));
+        if !is_retry_info {
+            return None;
+        }
+
+        let seconds = detail
+            .get("retryDelay")?
+            .as_str()?
+            .strip_suffix('s')?
+            .parse::<f64>()
+            .ok()?;
+
+        Duration::try_from_secs_f64(seconds).ok()
+    })
+}
+
+/// Returns an exponentially increasing delay for the given attempt (starting at 0); half of it
+/// is randomized via `jitter` (a value in [0, 1)) so that clients don't retry in lockstep
+fn backoff_delay(attempt: u32, jitter: f64) -> Duration {
+    let delay = BASE_RETRY_DELAY
+        .saturating_mul(2_u32.saturating_pow(attempt))
+        .min(MAX_BACKOFF_DELAY);
+
+    delay.mul_f64(0.5 + jitter / 2.0)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use reqwest::header::HeaderValue;
+
+    //-------------//
+    //  SUCCESSES  //
+    //-------------//
+
+    #[test]
+    fn failed_requests_are_retried_until_they_succeed() {
+        // GIVEN
+        let mut server = mockito::Server::new();
+        let failure = server
+            .mock("GET", "/models")
+            .with_status(503)
+            .with_header("retry-after", "0")
+            .expect(2)
+            .create();
+        let success = server
+            .mock("GET", "/models")
+            .with_status(200)
+            .with_body("[]")
+            .create();
+        let client = HttpClient::new(Client::new(), Some(3));
+
+        // WHEN
+        let result = client
+            .send(client.get(format!("{}/models", server.url())))
+            .expect("result should've been a success");
+
+        // THEN
+        failure.assert();
+        success.assert();
+        assert_eq!(result, "[]");
+    }
+
+    #[test]
+    fn retry_after_header_takes_precedence() {
+        // GIVEN
+        let mut headers = HeaderMap::new();
+        headers.insert(RETRY_AFTER, HeaderValue::from_static("7"));
+        let body = r#"{"error": {"details": [{"@type": "type.googleapis.com/google.rpc.RetryInfo", "retryDelay": "37s"}]}}"#;
+
+        // WHEN
+        let delay = get_requested_delay(&headers, body);
+
+        // THEN
+        assert_eq!(delay, Some(Duration::from_secs(7)));
+    }
+
+    #[test]
+    fn delay_is_read_from_retry_info_in_error_body() {
+        // GIVEN
+        let body = r#"{
+  "error": {
+    "code": 429,
+    "message": "You exceeded your current quota",
+    "status": "RESOURCE_EXHAUSTED",
+    "details": [
+      { "@type": "type.googleapis.com/google.rpc.QuotaFailure", "violations": [] },
+      { "@type": "type.googleapis.com/google.rpc.RetryInfo", "retryDelay": "12.5s" }
+    ]
+  }
+}"#;
+
+        // WHEN
+        let delay = get_requested_delay(&HeaderMap::new(), body);
+
+        // THEN
+        assert_eq!(delay, Some(Duration::from_millis(12500)));
+    }
+
+    #[test]
+    fn error_messages_are_read_from_error_bodies() {
+        // GIVEN
+        let bodies = [
+            r#"{"type":"error","error":{"type":"overloaded_error","message":"Overloaded"}}"#,
+            r#"{"error":"model 'llama3.1' not found"}"#,
+            "Bad Gateway\n",
+            "",
+        ];
+
+        // WHEN
+        let messages = bodies.map(get_error_message);
+
+        // THEN
+        assert_eq!(
+            messages,
+            [
+                "Overloaded",
+                "model 'llama3.1' not found",
+                "Bad Gateway",
+                ""
+            ]
+        );
+    }
+
+    #[test]
+    fn long_error_messages_are_truncated() {
+        // GIVEN
+        let body = "<html>".repeat(100);
+
+        // WHEN
+        let message = get_error_message(&body);
+
+        // THEN
+        assert_eq!(message.len(), MAX_ERROR_MESSAGE_CHARS + "...".len());
+        assert!(message.ends_with("<html>..."));
+    }
+
+    #[test]
+    fn backoff_delay_grows_exponentially_and_is_capped() {
+        // GIVEN
+        // WHEN
+        let delays = (0..7)
+            .map(|attempt| backoff_delay(attempt, 0.999_999).as_secs())
+            .collect::<Vec<_>>();
+
+        // THEN
+        assert_eq!(delays, vec![0, 1, 3, 7, 15, 29, 29]);
+    }
+
+    //------------//
+    //  FAILURES  //
+    //------------//
+
+    #[test]
+    fn retries_stop_after_the_configured_number_of_attempts() {
+        // GIVEN
+        let mut server = mockito::Server::new();
+        let failure = server
+            .mock("GET", "/models")
+            .with_status(429)
+            .with_header("retry-after", "0")
+            .expect(3)
+            .create();
+        let client = HttpClient::new(Client::new(), Some(2));
+
+        // WHEN
+        let result = client
+            .send(client.get(format!("{}/models", server.url())))
+            .expect_err("result wasn't an error");
+
+        // THEN
+        failure.assert();
+        assert_eq!(result.to_string(), "API returned a non success code: 429");
+    }
+
+    #[test]
+    fn client_errors_are_not_retried() {
+        // GIVEN
+        let mut server = mockito::Server::new();
+        let failure = server
+            .mock("GET", "/models")
+            .with_status(400)
+            .expect(1)
+            .create();
+        let client = HttpClient::new(Client::new(), Some(2));
+
+        // WHEN
+        let result = client.send(client.get(format!("{}/models", server.url())));
+
+        // THEN
+        failure.assert();
+        assert!(result.is_err());
+    }
+}
@@ -0,0 +1,216 @@
+mod anthropic;
+mod gemini;
+mod http;
+mod ollama;
+mod openai;
+
+use super::domain::{Content, FunctionCall, FunctionDeclaration, FunctionResponse, Part, Usage};
+use super::generation::GenerationSettings;
+use anthropic::AnthropicProvider;
+use clap::ValueEnum;
+use gemini::GeminiProvider;
+use ollama::OllamaProvider;
+use openai::OpenAiProvider;
+use reqwest::blocking::Client;
+use serde::{Deserialize, Serialize};
+use serde_json::{Map, Value};
+use std::collections::VecDeque;
+use std::env::VarError;
+use std::fmt::Display;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ProviderKind {
+    Gemini,
+    Anthropic,
+    /// Any server that implements OpenAI's Chat Completions API (llama.cpp, vLLM, etc.)
+    #[value(name = "openai")]
+    OpenAi,
+    Ollama,
+}
+
+#[derive(Debug)]
+pub struct ProviderConfig {
+    pub kind: ProviderKind,
+    /// Falls back to the provider's default model if not set
+    pub model: Option<String>,
+    /// Falls back to the provider's default endpoint if not set
+    pub base_url: Option<String>,
+    /// Model options passed as is to ollama, eg. `num_ctx`
+    pub ollama_options: Map<String, Value>,
+    /// How many times to retry requests that fail with a 429 or 5xx status code
+    pub max_retries: Option<u32>,
+    /// How long (in seconds) gemini caches the system instruction, tools and older turns for;
+    /// context caching is disabled if not set or 0
+    pub cache_ttl: Option<u64>,
+}
+
+pub(super) trait Provider {
+    /// Name of the provider, as shown in the REPL
+    fn name(&self) -> &'static str;
+
+    fn model(&self) -> &str;
+
+    fn set_model(&mut self, model: String);
+
+    /// Size of the current model's context window in tokens, if known
+    fn context_window(&self) -> Option<usize>;
+
+    /// Whether files of the given MIME type can be sent to the model as inline data
+    fn supports_attachment(&self, mime_type: &str) -> bool;
+
+    /// Lists the models available via the provider's models endpoint
+    fn list_models(&self) -> Result<Vec<String>, ProviderError>;
+
+    /// Counts the input tokens of a request (contents, system prompt and tools), via the
+    /// provider's token counting endpoint if it has one, or by estimating them otherwise
+    fn count_tokens(&self, request: &ModelRequest) -> Result<usize, ProviderError>;
+
+    /// Generates the model's next turn; text is passed to `on_delta` as soon as it's available,
+    /// either in chunks (for providers that stream responses) or all at once
+    fn generate(
+        &self,
+        request: &ModelRequest,
+        on_delta: &mut dyn FnMut(Delta),
+    ) -> Result<ModelResponse, ProviderError>;
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(super) enum Delta<'a> {
+    Text(&'a str),
+    Thought(&'a str),
+}
+
+pub(super) struct ModelRequest<'a> {
+    pub(super) system_prompt: &'a str,
+    pub(super) contents: &'a [Content],
+    pub(super) tools: &'a [FunctionDeclaration],
+    pub(super) settings: &'a GenerationSettings,
+}
+
+#[derive(Debug)]
+pub(super) struct ModelResponse {
+    pub(super) parts: Vec<Part>,
+    pub(super) usage: Option<Usage>,
+    /// `None` if the provider didn't say why the model stopped
+    pub(super) finish_reason: Option<FinishReason>,
+}
+
+/// Why the model stopped generating its turn
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(super) enum FinishReason {
+    /// The model finished its turn, either with an answer or with function calls
+    Stop,
+    /// The response was cut off by the output token limit
+    MaxTokens,
+    /// The response was blocked by the provider's safety filters
+    Safety,
+    /// The response was blocked for reproducing existing content too closely
+    Recitation,
+    /// The model produced a function call that couldn't be parsed
+    MalformedFunctionCall,
+    /// Any other reason, as reported by the provider
+    Other(String),
+}
+
+#[derive(Debug)]
+pub(super) enum ProviderError {
+    Request(reqwest::Error),
+    /// `message` is the provider's explanation of the error, if it gave one
+    ErrorResponse {
+        status: u16,
+        message: String,
+    },
+    InvalidResponse(anyhow::Error),
+}
+
+impl Display for ProviderError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ProviderError::Request(e) => write!(f, "couldn't send request: {e}"),
+            ProviderError::ErrorResponse { status, message } if message.is_empty() => {
+                write!(f, "API returned a non success code: {status}")
+            }
+            ProviderError::ErrorResponse { status, message } => {
+                write!(f, "API returned a non success code: {status} ({message})")
+            }
+            ProviderError::InvalidResponse(e) => write!(f, "couldn't parse response: {e:#}"),
+        }
+    }
+}
+
+impl std::error::Error for ProviderError {}
+
+pub(super) fn get_provider(
+    config: ProviderConfig,
+    client: Client,
+) -> anyhow::Result<Box<dyn Provider>> {
+    let provider: Box<dyn Provider> = match config.kind {
+        ProviderKind::Gemini => Box::new(GeminiProvider::from_config(client, config)?),
+        ProviderKind::Anthropic => Box::new(AnthropicProvider::from_config(client, config)?),
+        ProviderKind::OpenAi => Box::new(OpenAiProvider::from_config(client, config)?),
+        ProviderKind::Ollama => Box::new(OllamaProvider::from_config(client, config)),
+    };
+
+    Ok(provider)
+}
+
+fn get_env_var(name: &str) -> anyhow::Result<String> {
+    match std::env::var(name) {
+        Ok(v) => Ok(v),
+        Err(VarError::NotPresent) => Err(anyhow::anyhow!("environment variable {name} not set")),
+        Err(VarError::NotUnicode(_)) => Err(anyhow::anyhow!(
+            "environment variable {name} is not valid unicode"
+        )),
+    }
+}
+
+/// Passes text and thought parts to `on_delta`, for providers that don't stream their responses
+fn emit_deltas(parts: &[Part], on_delta: &mut dyn FnMut(Delta)) {
+    for part in parts {
+        match part {
+            Part::Text(t) => on_delta(Delta::Text(t)),
+            Part::Thought(t) => on_delta(Delta::Thought(&t.text)),
+            _ => {}
+        }
+    }
+}
+
+/// Pairs up function calls and their responses for APIs that require both to share an id.
+///
+/// Calls recorded without an id get a synthetic one, which responses without an id pick up in
+/// order.
+#[derive(Debug, Default)]
+struct ToolCallIds {
+    pending: VecDeque<String>,
+    synthetic_count: usize,
+}
+
+impl ToolCallIds {
+    fn for_call(&mut self, call: &FunctionCall) -> String {
+        let id = match &call.id {
+            Some(id) if !id.is_empty() => id.clone(),
+            _ => {
+                self.synthetic_count += 1;
+                format!("call_agent1_{}", self.synthetic_count)
+            }
+        };
+        self.pending.push_back(id.clone());
+
+        id
+    }
+
+    fn for_response(&mut self, response: &FunctionResponse) -> String {
+        match &response.id {
+            Some(id) if !id.is_empty() => {
+                self.pending.retain(|pending| pending != id);
+                id.clone()
+            }
+            _ => self.pending.pop_front().unwrap_or_default(),
+        }
+    }
+}
+
+fn trim_base_url(base_url: String) -> String {
+    base_url.trim_end_matches('/').to_string()
+}
@@ -0,0 +1,526 @@
+use super::http::HttpClient;
+use super::{
+    Delta, FinishReason, ModelRequest, ModelResponse, Provider, ProviderConfig, ProviderError,
+    emit_deltas, get_env_var, trim_base_url,
+};
+use crate::agent::context::estimate_tokens;
+use crate::agent::domain::{
+    FunctionCall, FunctionCallResponse, FunctionDeclaration, FunctionDeclarationName, Part, Role,
+    Thought, Usage,
+};
+use reqwest::blocking::Client;
+use serde::{Deserialize, Serialize};
+use serde_json::{Map, Value};
+use tracing::debug;
+
+const HOST_ENV_VAR: &str = "OLLAMA_HOST";
+const BASE_URL: &str = "http://localhost:11434";
+const MODEL: &str = "llama3.1";
+const SUPPORTED_ATTACHMENT_TYPES: [&str; 2] = ["image/png", "image/jpeg"];
+
+pub(super) struct OllamaProvider {
+    client: HttpClient,
+    base_url: String,
+    model: String,
+    options: Map<String, Value>,
+}
+
+impl OllamaProvider {
+    pub(super) fn new(
+        client: HttpClient,
+        base_url: String,
+        model: String,
+        options: Map<String, Value>,
+    ) -> Self {
+        Self {
+            client,
+            base_url,
+            model,
+            options,
+        }
+    }
+
+    pub(super) fn from_config(client: Client, config: ProviderConfig) -> Self {
+        let base_url = match config.base_url {
+            Some(u) => trim_base_url(u),
+            None => match get_env_var(HOST_ENV_VAR) {
+                // ollama itself accepts OLLAMA_HOST values without a scheme, eg. 0.0.0.0:11434
+                Ok(h) if !h.is_empty() && !h.contains("://") => format!("http://{h}"),
+                Ok(h) if !h.is_empty() => trim_base_url(h),
+                _ => BASE_URL.to_string(),
+            },
+        };
+        let model = config.model.unwrap_or_else(|| MODEL.to_string());
+
+        Self::new(
+            HttpClient::new(client, config.max_retries),
+            base_url,
+            model,
+            config.ollama_options,
+        )
+    }
+}
+
+impl Provider for OllamaProvider {
+    fn name(&self) -> &'static str {
+        "Ollama"
+    }
+
+    fn model(&self) -> &str {
+        &self.model
+    }
+
+    fn set_model(&mut self, model: String) {
+        self.model = model;
+    }
+
+    fn context_window(&self) -> Option<usize> {
+        // ollama silently truncates requests that are larger than num_ctx
+        self.options
+            .get("num_ctx")
+            .and_then(Value::as_u64)
+            .map(|n| n as usize)
+    }
+
+    fn supports_attachment(&self, mime_type: &str) -> bool {
+        // only multimodal models accept images, which ollama reports as an error if they don't
+        SUPPORTED_ATTACHMENT_TYPES.contains(&mime_type)
+    }
+
+    fn list_models(&self) -> Result<Vec<String>, ProviderError> {
+        let resp_body = self
+            .client
+            .send(self.client.get(format!("{}/api/tags", self.base_url)))?;
+
+        let resp = serde_json::from_str::<ListModelsResponse>(&resp_body)
+            .map_err(|e| ProviderError::InvalidResponse(e.into()))?;
+
+        Ok(resp.models.into_iter().map(|m| m.name).collect())
+    }
+
+    fn count_tokens(&self, request: &ModelRequest) -> Result<usize, ProviderError> {
+        Ok(estimate_tokens(request))
+    }
+
+    fn generate(
+        &self,
+        request: &ModelRequest,
+        on_delta: &mut dyn FnMut(Delta),
+    ) -> Result<ModelResponse, ProviderError> {
+        let body = ChatBody::new(request, &self.model, &self.options);
+
+        if let Ok(r) = serde_json::to_string_pretty(&body) {
+            debug!("request: {}", &r);
+        } else {
+            debug!("request: {:?}", &body);
+        }
+
+        let resp_body = self.client.send(
+            self.client
+                .post(format!("{}/api/chat", self.base_url))
+                .json(&body)
+                .header("content-type", "application/json"),
+        )?;
+
+        let resp = serde_json::from_str::<ChatResponse>(&resp_body)
+            .map_err(|e| ProviderError::InvalidResponse(e.into()))?;
+
+        let mut parts = vec![];
+        if let Some(text) = resp.message.thinking
+            && !text.is_empty()
+        {
+            parts.push(Part::Thought(Thought {
+                text,
+                signature: None,
+                redacted: false,
+            }));
+        }
+        if !resp.message.content.is_empty() {
+            parts.push(Part::Text(resp.message.content));
+        }
+        for tool_call in resp.message.tool_calls {
+            parts.push(Part::FunctionCall(FunctionCall {
+                id: None,
+                name: tool_call.function.name,
+                args: Some(tool_call.function.arguments),
+                thought_signature: None,
+            }));
+        }
+
+        let usage = match (resp.prompt_eval_count, resp.eval_count) {
+            (None, None) => None,
+            (prompt, output) => {
+                let prompt_tokens = prompt.unwrap_or_default();
+                let output_tokens = output.unwrap_or_default();
+                Some(Usage {
+                    prompt_tokens,
+                    output_tokens,
+                    total_tokens: prompt_tokens + output_tokens,
+                    ..Default::default()
+                })
+            }
+        };
+
+        emit_deltas(&parts, on_delta);
+
+        let finish_reason = resp.done_reason.as_deref().map(|reason| match reason {
+            "stop" => FinishReason::Stop,
+            "length" => FinishReason::MaxTokens,
+            other => FinishReason::Other(other.to_string()),
+        });
+
+        Ok(ModelResponse {
+            parts,
+            usage,
+            finish_reason,
+        })
+    }
+}
+
+#[derive(Debug, Serialize)]
+struct ChatBody<'a> {
+    model: &'a str,
+    messages: Vec<Message>,
+    tools: Vec<ToolDefinition<'a>>,
+    stream: bool,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    think: Option<bool>,
+    #[serde(skip_serializing_if = "Map::is_empty")]
+    options: Map<String, Value>,
+}
+
+#[derive(Debug, Serialize)]
+struct Message {
+    role: &'static str,
+    content: String,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    tool_calls: Vec<ToolCall>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    tool_name: Option<FunctionDeclarationName>,
+    /// Base64 encoded images
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    images: Vec<String>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+struct ToolCall {
+    function: ToolCallFunction,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+struct ToolCallFunction {
+    name: FunctionDeclarationName,
+    #[serde(default)]
+    arguments: Value,
+}
+
+#[derive(Debug, Serialize)]
+struct ToolDefinition<'a> {
+    #[serde(rename = "type")]
+    kind: &'static str,
+    function: &'a FunctionDeclaration,
+}
+
+impl<'a> ChatBody<'a> {
+    fn new(request: &ModelRequest<'a>, model: &'a str, options: &'a Map<String, Value>) -> Self {
+        let mut messages = vec![Message {
+            role: "system",
+            content: request.system_prompt.to_string(),
+            tool_calls: vec![],
+            tool_name: None,
+            images: vec![],
+        }];
+
+        for content in request.contents {
+            let mut texts = vec![];
+            let mut tool_calls = vec![];
+            let mut tool_results = vec![];
+            let mut images = vec![];
+
+            for part in &content.parts {
+                match part {
+                    Part::Text(text) => texts.push(text.as_str()),
+                    // thinking isn't meant to be sent back to the model
+                    Part::Thought(_) => {}
+                    Part::InlineData(blob) => images.push(blob.data.clone()),
+                    // only produced by gemini
+                    Part::FileData(_)
+                    | Part::ExecutableCode(_)
+                    | Part::CodeExecutionResult(_)
+                    | Part::Unknown(_) => {}
+                    Part::FunctionCall(call) => tool_calls.push(ToolCall {
+                        function: ToolCallFunction {
+                            name: call.name.clone(),
+                            arguments: call
+                                .args
+                                .clone()
+                                .unwrap_or_else(|| Value::Object(Map::new())),
+                        },
+                    }),
+                    Part::FunctionResponse(resp) => tool_results.push(Message {
+                        role: "tool",
+                        content: match &resp.response {
+                            FunctionCallResponse::Output(o) => o.clone(),
+                            FunctionCallResponse::Error(e) => format!("error: {e}"),
+                        },
+                        tool_calls: vec![],
+                        tool_name: Some(resp.name.clone()),
+                        images: vec![],
+                    }),
+                }
+            }
+
+            match content.role {
+                Some(Role::Model) => messages.push(Message {
+                    role: "assistant",
+                    content: texts.join("\n"),
+                    tool_calls,
+                    tool_name: None,
+                    images: vec![],
+                }),
+                Some(Role::User) | None => {
+                    messages.extend(tool_results);
+                    if !texts.is_empty() || !images.is_empty() {
+                        messages.push(Message {
+                            role: "user",
+                            content: texts.join("\n"),
+                            tool_calls: vec![],
+                            tool_name: None,
+                            images,
+                        });
+                    }
+                }
+            }
+        }
+
+        let tools = request
+            .tools
+            .iter()
+            .map(|t| ToolDefinition {
+                kind: "function",
+                function: t,
+            })
+            .collect();
+
+        // generation settings take precedence, since they can be changed during a session
+        let settings = request.settings;
+        let mut options = options.clone();
+        let mut set_option = |key: &str, value: Option<Value>| {
+            if let Some(value) = value {
+                options.insert(key.to_string(), value);
+            }
+        };
+        set_option("temperature", settings.temperature.map(Value::from));
+        set_option("top_p", settings.top_p.map(Value::from));
+        set_option("num_predict", settings.max_output_tokens.map(Value::from));
+        set_option("seed", settings.seed.map(Value::from));
+        set_option(
+            "stop",
+            (!settings.stop_sequences.is_empty())
+                .then(|| Value::from(settings.stop_sequences.clone())),
+        );
+
+        Self {
+            model,
+            messages,
+            tools,
+            stream: false,
+            think: settings.include_thoughts,
+            options,
+        }
+    }
+}
+
+#[derive(Debug, Deserialize)]
+struct ChatResponse {
+    message: ResponseMessage,
+    done_reason: Option<String>,
+    prompt_eval_count: Option<usize>,
+    eval_count: Option<usize>,
+}
+
+#[derive(Debug, Deserialize)]
+struct ListModelsResponse {
+    models: Vec<ModelInfo>,
+}
+
+#[derive(Debug, Deserialize)]
+struct ModelInfo {
+    name: String,
+}
+
+#[derive(Debug, Deserialize)]
+struct ResponseMessage {
+    #[serde(default)]
+    content: String,
+    thinking: Option<String>,
+    #[serde(default)]
+    tool_calls: Vec<ToolCall>,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::agent::domain::{Content, FunctionResponse};
+    use crate::agent::generation::GenerationSettings;
+    use insta::assert_snapshot;
+    use mockito::Matcher;
+    use serde_json::json;
+
+    const RESPONSE_WITH_TOOL_CALL: &str = r#"{
+  "model": "llama3.1",
+  "created_at": "2025-01-01T00:00:00Z",
+  "message": {
+    "role": "assistant",
+    "content": "",
+    "tool_calls": [
+      { "function": { "name": "read_file", "arguments": { "path": "README.md" } } }
+    ]
+  },
+  "done_reason": "stop",
+  "done": true,
+  "prompt_eval_count": 310,
+  "eval_count": 22
+}"#;
+
+    fn conversation() -> Vec<Content> {
+        vec![
+            Content {
+                parts: vec![Part::Text("summarize the README".to_string())],
+                role: Some(Role::User),
+            },
+            Content {
+                parts: vec![Part::FunctionCall(FunctionCall {
+                    id: None,
+                    name: FunctionDeclarationName::ReadFile,
+                    args: Some(json!({"path": "README.md"})),
+                    thought_signature: None,
+                })],
+                role: Some(Role::Model),
+            },
+            Content {
+                parts: vec![Part::FunctionResponse(FunctionResponse {
+                    id: None,
+                    name: FunctionDeclarationName::ReadFile,
+                    response: FunctionCallResponse::Output("agent1 readme".to_string()),
+                    will_continue: Some(false),
+                })],
+                role: Some(Role::User),
+            },
+        ]
+    }
+
+    //-------------//
+    //  SUCCESSES  //
+    //-------------//
+
+    #[test]
+    fn history_is_translated_into_chat_messages() {
+        // GIVEN
+        let contents = conversation();
+        let request = ModelRequest {
+            system_prompt: "system prompt",
+            contents: &contents,
+            tools: &[],
+            settings: &GenerationSettings::default(),
+        };
+        let mut options = Map::new();
+        options.insert("num_ctx".to_string(), json!(16384));
+
+        // WHEN
+        let body = ChatBody::new(&request, MODEL, &options);
+
+        // THEN
+        let body = serde_json::to_string_pretty(&body).expect("body should've been serialized");
+        assert_snapshot!(body, @r#"
+        {
+          "model": "llama3.1",
+          "messages": [
+            {
+              "role": "system",
+              "content": "system prompt"
+            },
+            {
+              "role": "user",
+              "content": "summarize the README"
+            },
+            {
+              "role": "assistant",
+              "content": "",
+              "tool_calls": [
+                {
+                  "function": {
+                    "name": "read_file",
+                    "arguments": {
+                      "path": "README.md"
+                    }
+                  }
+                }
+              ]
+            },
+            {
+              "role": "tool",
+              "content": "agent1 readme",
+              "tool_name": "read_file"
+            }
+          ],
+          "tools": [],
+          "stream": false,
+          "options": {
+            "num_ctx": 16384
+          }
+        }
+        "#);
+    }
+
+    #[test]
+    fn tool_calls_in_response_are_parsed() {
+        // GIVEN
+        let mut server = mockito::Server::new();
+        let mock = server
+            .mock("POST", "/api/chat")
+            .match_body(Matcher::PartialJson(json!({"stream": false})))
+            .with_status(200)
+            .with_body(RESPONSE_WITH_TOOL_CALL)
+            .create();
+        let provider = OllamaProvider::new(
+            HttpClient::new(Client::new(), None),
+            server.url(),
+            MODEL.to_string(),
+            Map::new(),
+        );
+        let contents = conversation();
+        let request = ModelRequest {
+            system_prompt: "system prompt",
+            contents: &contents[..1],
+            tools: &[],
+            settings: &GenerationSettings::default(),
+        };
+
+        // WHEN
+        let response = provider
+            .generate(&request, &mut |_| {})
+            .expect("response should've been a success");
+
+        // THEN
+        mock.assert();
+        assert_snapshot!(format!("{:#?}", response.parts), @r#"
+        [
+            FunctionCall(
+                FunctionCall {
+                    id: None,
+                    name: ReadFile,
+                    args: Some(
+                        Object {
+                            "path": String("README.md"),
+                        },
+                    ),
+                    thought_signature: None,
+                },
+            ),
+        ]
+        "#);
+        let usage = response.usage.expect("usage should've been present");
+        assert_eq!(usage.total_tokens, 332);
+    }
+}
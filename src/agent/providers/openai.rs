@@ -0,0 +1,802 @@
+use super::http::HttpClient;
+use super::{
+    Delta, FinishReason, ModelRequest, ModelResponse, Provider, ProviderConfig, ProviderError,
+    ToolCallIds, emit_deltas, get_env_var, trim_base_url,
+};
+use crate::agent::context::estimate_tokens;
+use crate::agent::domain::{
+    FunctionCall, FunctionCallResponse, FunctionDeclaration, FunctionDeclarationName, Part, Role,
+    Thought, Usage,
+};
+use reqwest::blocking::{Client, RequestBuilder};
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use tracing::debug;
+
+const API_KEY_ENV_VAR: &str = "OPENAI_API_KEY";
+const BASE_URL_ENV_VAR: &str = "OPENAI_BASE_URL";
+// llama.cpp's server listens here by default
+const BASE_URL: &str = "http://localhost:8080/v1";
+const MODEL: &str = "default";
+const SUPPORTED_ATTACHMENT_TYPES: [&str; 4] =
+    ["image/png", "image/jpeg", "image/gif", "image/webp"];
+
+pub(super) struct OpenAiProvider {
+    client: HttpClient,
+    api_key: Option<String>,
+    base_url: String,
+    model: String,
+}
+
+impl OpenAiProvider {
+    pub(super) fn new(
+        client: HttpClient,
+        api_key: Option<String>,
+        base_url: String,
+        model: String,
+    ) -> Self {
+        Self {
+            client,
+            api_key,
+            base_url,
+            model,
+        }
+    }
+
+    /// Local model servers usually don't need a token, which is why `OPENAI_API_KEY` is optional
+    pub(super) fn from_config(client: Client, config: ProviderConfig) -> anyhow::Result<Self> {
+        let api_key = match get_env_var(API_KEY_ENV_VAR) {
+            Ok(k) if !k.is_empty() => Some(k),
+            _ => None,
+        };
+        let base_url = match config.base_url {
+            Some(u) => trim_base_url(u),
+            None => match get_env_var(BASE_URL_ENV_VAR) {
+                Ok(u) if !u.is_empty() => trim_base_url(u),
+                _ => BASE_URL.to_string(),
+            },
+        };
+        let model = config.model.unwrap_or_else(|| MODEL.to_string());
+
+        Ok(Self::new(
+            HttpClient::new(client, config.max_retries),
+            api_key,
+            base_url,
+            model,
+        ))
+    }
+
+    fn authorize(&self, request: RequestBuilder) -> RequestBuilder {
+        match &self.api_key {
+            Some(api_key) => request.bearer_auth(api_key),
+            None => request,
+        }
+    }
+}
+
+impl Provider for OpenAiProvider {
+    fn name(&self) -> &'static str {
+        "Assistant"
+    }
+
+    fn model(&self) -> &str {
+        &self.model
+    }
+
+    fn set_model(&mut self, model: String) {
+        self.model = model;
+    }
+
+    fn context_window(&self) -> Option<usize> {
+        // context windows vary between models and servers; see --context-window
+        None
+    }
+
+    fn supports_attachment(&self, mime_type: &str) -> bool {
+        // images are sent as data URLs, which servers only accept if the model is multimodal
+        SUPPORTED_ATTACHMENT_TYPES.contains(&mime_type)
+    }
+
+    fn list_models(&self) -> Result<Vec<String>, ProviderError> {
+        let resp_body = self
+            .client
+            .send(self.authorize(self.client.get(format!("{}/models", self.base_url))))?;
+
+        let resp = serde_json::from_str::<ListModelsResponse>(&resp_body)
+            .map_err(|e| ProviderError::InvalidResponse(e.into()))?;
+
+        Ok(resp.data.into_iter().map(|m| m.id).collect())
+    }
+
+    fn count_tokens(&self, request: &ModelRequest) -> Result<usize, ProviderError> {
+        Ok(estimate_tokens(request))
+    }
+
+    fn generate(
+        &self,
+        request: &ModelRequest,
+        on_delta: &mut dyn FnMut(Delta),
+    ) -> Result<ModelResponse, ProviderError> {
+        let body = ChatCompletionsBody::new(request, &self.model);
+
+        if let Ok(r) = serde_json::to_string_pretty(&body) {
+            debug!("request: {}", &r);
+        } else {
+            debug!("request: {:?}", &body);
+        }
+
+        let resp_body = self.client.send(
+            self.authorize(
+                self.client
+                    .post(format!("{}/chat/completions", self.base_url))
+                    .json(&body)
+                    .header("content-type", "application/json"),
+            ),
+        )?;
+
+        let resp = serde_json::from_str::<ChatCompletionsResponse>(&resp_body)
+            .map_err(|e| ProviderError::InvalidResponse(e.into()))?;
+
+        let mut parts = vec![];
+        let mut finish_reason = None;
+        if let Some(choice) = resp.choices.into_iter().next() {
+            finish_reason = choice.finish_reason.as_deref().map(parse_finish_reason);
+
+            if let Some(text) = choice.message.reasoning_content
+                && !text.is_empty()
+            {
+                parts.push(Part::Thought(Thought {
+                    text,
+                    signature: None,
+                    redacted: false,
+                }));
+            }
+
+            if let Some(text) = choice.message.content
+                && !text.is_empty()
+            {
+                parts.push(Part::Text(text));
+            }
+
+            for tool_call in choice.message.tool_calls {
+                let args = match tool_call.function.arguments {
+                    // the spec says arguments are a JSON encoded string, but some servers send
+                    // the object as is
+                    Value::String(s) if s.trim().is_empty() => None,
+                    Value::String(s) => match serde_json::from_str(&s) {
+                        Ok(args) => Some(args),
+                        Err(e) => {
+                            debug!("couldn't parse arguments for tool call: {e}");
+                            finish_reason = Some(FinishReason::MalformedFunctionCall);
+                            continue;
+                        }
+                    },
+                    Value::Null => None,
+                    v => Some(v),
+                };
+
+                parts.push(Part::FunctionCall(FunctionCall {
+                    id: Some(tool_call.id).filter(|id| !id.is_empty()),
+                    name: tool_call.function.name,
+                    args,
+                    thought_signature: None,
+                }));
+            }
+        }
+
+        emit_deltas(&parts, on_delta);
+
+        Ok(ModelResponse {
+            parts,
+            usage: resp.usage.map(Usage::from),
+            finish_reason,
+        })
+    }
+}
+
+#[derive(Debug, Serialize)]
+struct ChatCompletionsBody<'a> {
+    model: &'a str,
+    messages: Vec<Message>,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    tools: Vec<ToolDefinition<'a>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    temperature: Option<f64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    top_p: Option<f64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    max_tokens: Option<u32>,
+    #[serde(skip_serializing_if = "<[String]>::is_empty")]
+    stop: &'a [String],
+    #[serde(skip_serializing_if = "Option::is_none")]
+    seed: Option<i64>,
+}
+
+#[derive(Debug, Serialize)]
+#[serde(tag = "role", rename_all = "lowercase")]
+enum Message {
+    System {
+        content: String,
+    },
+    User {
+        content: UserContent,
+    },
+    Assistant {
+        content: Option<String>,
+        #[serde(skip_serializing_if = "Vec::is_empty")]
+        tool_calls: Vec<ToolCall>,
+    },
+    Tool {
+        tool_call_id: String,
+        content: String,
+    },
+}
+
+#[derive(Debug, Serialize)]
+#[serde(untagged)]
+enum UserContent {
+    Text(String),
+    Parts(Vec<UserContentPart>),
+}
+
+#[derive(Debug, Serialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+enum UserContentPart {
+    Text { text: String },
+    ImageUrl { image_url: ImageUrl },
+}
+
+#[derive(Debug, Serialize)]
+struct ImageUrl {
+    url: String,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+struct ToolCall {
+    #[serde(default)]
+    id: String,
+    #[serde(rename = "type", default = "function_type")]
+    kind: String,
+    function: ToolCallFunction,
+}
+
+fn function_type() -> String {
+    "function".to_string()
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+struct ToolCallFunction {
+    name: FunctionDeclarationName,
+    #[serde(default)]
+    arguments: Value,
+}
+
+#[derive(Debug, Serialize)]
+struct ToolDefinition<'a> {
+    #[serde(rename = "type")]
+    kind: &'static str,
+    function: &'a FunctionDeclaration,
+}
+
+impl<'a> ChatCompletionsBody<'a> {
+    fn new(request: &ModelRequest<'a>, model: &'a str) -> Self {
+        let mut ids = ToolCallIds::default();
+        let mut messages = vec![Message::System {
+            content: request.system_prompt.to_string(),
+        }];
+
+        for content in request.contents {
+            let mut texts = vec![];
+            let mut tool_calls = vec![];
+            let mut tool_results = vec![];
+            let mut images = vec![];
+
+            for part in &content.parts {
+                match part {
+                    Part::Text(text) => texts.push(text.as_str()),
+                    // reasoning isn't meant to be sent back to the model
+                    Part::Thought(_) => {}
+                    Part::InlineData(blob) => images.push(UserContentPart::ImageUrl {
+                        image_url: ImageUrl {
+                            url: format!("data:{};base64,{}", blob.mime_type, blob.data),
+                        },
+                    }),
+                    // only produced by gemini
+                    Part::FileData(_)
+                    | Part::ExecutableCode(_)
+                    | Part::CodeExecutionResult(_)
+                    | Part::Unknown(_) => {}
+                    Part::FunctionCall(call) => tool_calls.push(ToolCall {
+                        id: ids.for_call(call),
+                        kind: function_type(),
+                        function: ToolCallFunction {
+                            name: call.name.clone(),
+                            arguments: Value::String(
+                                call.args
+                                    .as_ref()
+                                    .map(|a| a.to_string())
+                                    .unwrap_or_else(|| "{}".to_string()),
+                            ),
+                        },
+                    }),
+                    Part::FunctionResponse(resp) => tool_results.push(Message::Tool {
+                        tool_call_id: ids.for_response(resp),
+                        content: match &resp.response {
+                            FunctionCallResponse::Output(o) => o.clone(),
+                            FunctionCallResponse::Error(e) => format!("error: {e}"),
+                        },
+                    }),
+                }
+            }
+
+            let text = (!texts.is_empty()).then(|| texts.join("\n"));
+
+            match content.role {
+                Some(Role::Model) => messages.push(Message::Assistant {
+                    content: text,
+                    tool_calls,
+                }),
+                Some(Role::User) | None => {
+                    // tool messages have to directly follow the assistant message that
+                    // requested them
+                    messages.extend(tool_results);
+                    if images.is_empty() {
+                        if let Some(content) = text {
+                            messages.push(Message::User {
+                                content: UserContent::Text(content),
+                            });
+                        }
+                    } else {
+                        let mut parts = vec![];
+                        if let Some(text) = text {
+                            parts.push(UserContentPart::Text { text });
+                        }
+                        parts.extend(images);
+                        messages.push(Message::User {
+                            content: UserContent::Parts(parts),
+                        });
+                    }
+                }
+            }
+        }
+
+        let tools = request
+            .tools
+            .iter()
+            .map(|t| ToolDefinition {
+                kind: "function",
+                function: t,
+            })
+            .collect();
+
+        let settings = request.settings;
+
+        Self {
+            model,
+            messages,
+            tools,
+            temperature: settings.temperature,
+            top_p: settings.top_p,
+            max_tokens: settings.max_output_tokens,
+            stop: &settings.stop_sequences,
+            seed: settings.seed,
+        }
+    }
+}
+
+#[derive(Debug, Deserialize)]
+struct ChatCompletionsResponse {
+    choices: Vec<Choice>,
+    usage: Option<ChatCompletionsUsage>,
+}
+
+#[derive(Debug, Deserialize)]
+struct Choice {
+    message: ResponseMessage,
+    finish_reason: Option<String>,
+}
+
+fn parse_finish_reason(reason: &str) -> FinishReason {
+    match reason {
+        "stop" | "tool_calls" | "function_call" => FinishReason::Stop,
+        "length" => FinishReason::MaxTokens,
+        "content_filter" => FinishReason::Safety,
+        other => FinishReason::Other(other.to_string()),
+    }
+}
+
+#[derive(Debug, Deserialize)]
+struct ResponseMessage {
+    content: Option<String>,
+    /// Non standard field used by servers that support reasoning models (llama.cpp, vLLM, etc.)
+    reasoning_content: Option<String>,
+    #[serde(default)]
+    tool_calls: Vec<ToolCall>,
+}
+
+#[derive(Debug, Deserialize)]
+struct ListModelsResponse {
+    data: Vec<ModelInfo>,
+}
+
+#[derive(Debug, Deserialize)]
+struct ModelInfo {
+    id: String,
+}
+
+#[derive(Debug, Deserialize)]
+struct ChatCompletionsUsage {
+    prompt_tokens: usize,
+    /// Some servers (eg. vLLM) send null instead of leaving details out
+    #[serde(default)]
+    prompt_tokens_details: Option<PromptTokensDetails>,
+    /// Includes reasoning tokens
+    completion_tokens: usize,
+    #[serde(default)]
+    completion_tokens_details: Option<CompletionTokensDetails>,
+    total_tokens: usize,
+}
+
+#[derive(Debug, Deserialize)]
+struct PromptTokensDetails {
+    #[serde(default)]
+    cached_tokens: usize,
+}
+
+#[derive(Debug, Deserialize)]
+struct CompletionTokensDetails {
+    #[serde(default)]
+    reasoning_tokens: usize,
+}
+
+impl From<ChatCompletionsUsage> for Usage {
+    fn from(value: ChatCompletionsUsage) -> Self {
+        let thinking_tokens = value
+            .completion_tokens_details
+            .map_or(0, |details| details.reasoning_tokens);
+
+        Self {
+            prompt_tokens: value.prompt_tokens,
+            cached_tokens: value
+                .prompt_tokens_details
+                .map_or(0, |details| details.cached_tokens),
+            output_tokens: value.completion_tokens.saturating_sub(thinking_tokens),
+            thinking_tokens,
+            total_tokens: value.total_tokens,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::agent::domain::{Blob, Content, FunctionResponse};
+    use crate::agent::generation::GenerationSettings;
+    use insta::assert_snapshot;
+    use mockito::Matcher;
+    use serde_json::json;
+
+    const RESPONSE_WITH_TOOL_CALL: &str = r#"{
+  "id": "chatcmpl-1",
+  "object": "chat.completion",
+  "model": "qwen2.5-coder",
+  "choices": [
+    {
+      "index": 0,
+      "message": {
+        "role": "assistant",
+        "content": null,
+        "tool_calls": [
+          {
+            "id": "call_01",
+            "type": "function",
+            "function": { "name": "list_files", "arguments": "{\"path\":\"src\"}" }
+          }
+        ]
+      },
+      "finish_reason": "tool_calls"
+    }
+  ],
+  "usage": { "prompt_tokens": 200, "completion_tokens": 15, "total_tokens": 215 }
+}"#;
+
+    fn conversation() -> Vec<Content> {
+        vec![
+            Content {
+                parts: vec![Part::Text("which files are in src?".to_string())],
+                role: Some(Role::User),
+            },
+            Content {
+                parts: vec![
+                    Part::Text("Let me check.".to_string()),
+                    Part::FunctionCall(FunctionCall {
+                        id: Some("call_01".to_string()),
+                        name: FunctionDeclarationName::ListFiles,
+                        args: Some(json!({"path": "src"})),
+                        thought_signature: None,
+                    }),
+                ],
+                role: Some(Role::Model),
+            },
+            Content {
+                parts: vec![Part::FunctionResponse(FunctionResponse {
+                    id: Some("call_01".to_string()),
+                    name: FunctionDeclarationName::ListFiles,
+                    response: FunctionCallResponse::Output("src/main.rs".to_string()),
+                    will_continue: Some(false),
+                })],
+                role: Some(Role::User),
+            },
+        ]
+    }
+
+    //-------------//
+    //  SUCCESSES  //
+    //-------------//
+
+    #[test]
+    fn history_is_translated_into_tool_calls_and_tool_messages() {
+        // GIVEN
+        let contents = conversation();
+        let request = ModelRequest {
+            system_prompt: "system prompt",
+            contents: &contents,
+            tools: &[],
+            settings: &GenerationSettings::default(),
+        };
+
+        // WHEN
+        let body = ChatCompletionsBody::new(&request, MODEL);
+
+        // THEN
+        let body = serde_json::to_string_pretty(&body).expect("body should've been serialized");
+        assert_snapshot!(body, @r#"
+        {
+          "model": "default",
+          "messages": [
+            {
+              "role": "system",
+              "content": "system prompt"
+            },
+            {
+              "role": "user",
+              "content": "which files are in src?"
+            },
+            {
+              "role": "assistant",
+              "content": "Let me check.",
+              "tool_calls": [
+                {
+                  "id": "call_01",
+                  "type": "function",
+                  "function": {
+                    "name": "list_files",
+                    "arguments": "{\"path\":\"src\"}"
+                  }
+                }
+              ]
+            },
+            {
+              "role": "tool",
+              "tool_call_id": "call_01",
+              "content": "src/main.rs"
+            }
+          ]
+        }
+        "#);
+    }
+
+    #[test]
+    fn attached_images_are_sent_as_data_urls() {
+        // GIVEN
+        let contents = vec![Content {
+            parts: vec![
+                Part::Text("what's wrong with this UI?".to_string()),
+                Part::InlineData(Blob {
+                    mime_type: "image/png".to_string(),
+                    data: "iVBORw0KGgo=".to_string(),
+                }),
+            ],
+            role: Some(Role::User),
+        }];
+        let request = ModelRequest {
+            system_prompt: "system prompt",
+            contents: &contents,
+            tools: &[],
+            settings: &GenerationSettings::default(),
+        };
+
+        // WHEN
+        let body = ChatCompletionsBody::new(&request, MODEL);
+
+        // THEN
+        let messages = serde_json::to_string_pretty(&body.messages)
+            .expect("messages should've been serialized");
+        assert_snapshot!(messages, @r#"
+        [
+          {
+            "role": "system",
+            "content": "system prompt"
+          },
+          {
+            "role": "user",
+            "content": [
+              {
+                "type": "text",
+                "text": "what's wrong with this UI?"
+              },
+              {
+                "type": "image_url",
+                "image_url": {
+                  "url": "data:image/png;base64,iVBORw0KGgo="
+                }
+              }
+            ]
+          }
+        ]
+        "#);
+    }
+
+    #[test]
+    fn tool_calls_in_response_are_parsed() {
+        // GIVEN
+        let mut server = mockito::Server::new();
+        let mock = server
+            .mock("POST", "/chat/completions")
+            .match_header("authorization", "Bearer test-token")
+            .with_status(200)
+            .with_body(RESPONSE_WITH_TOOL_CALL)
+            .create();
+        let provider = OpenAiProvider::new(
+            HttpClient::new(Client::new(), None),
+            Some("test-token".to_string()),
+            server.url(),
+            MODEL.to_string(),
+        );
+        let contents = conversation();
+        let request = ModelRequest {
+            system_prompt: "system prompt",
+            contents: &contents[..1],
+            tools: &[],
+            settings: &GenerationSettings::default(),
+        };
+
+        // WHEN
+        let response = provider
+            .generate(&request, &mut |_| {})
+            .expect("response should've been a success");
+
+        // THEN
+        mock.assert();
+        assert_snapshot!(format!("{:#?}", response.parts), @r#"
+        [
+            FunctionCall(
+                FunctionCall {
+                    id: Some(
+                        "call_01",
+                    ),
+                    name: ListFiles,
+                    args: Some(
+                        Object {
+                            "path": String("src"),
+                        },
+                    ),
+                    thought_signature: None,
+                },
+            ),
+        ]
+        "#);
+    }
+
+    #[test]
+    fn usage_with_null_details_is_parsed() {
+        // GIVEN
+        let usage = r#"{
+  "prompt_tokens": 200,
+  "completion_tokens": 15,
+  "total_tokens": 215,
+  "prompt_tokens_details": null,
+  "completion_tokens_details": null
+}"#;
+
+        // WHEN
+        let usage = serde_json::from_str::<ChatCompletionsUsage>(usage)
+            .map(Usage::from)
+            .expect("usage should've been parsed");
+
+        // THEN
+        assert_eq!(
+            usage,
+            Usage {
+                prompt_tokens: 200,
+                cached_tokens: 0,
+                output_tokens: 15,
+                thinking_tokens: 0,
+                total_tokens: 215,
+            }
+        );
+    }
+
+    #[test]
+    fn tool_calls_with_unparseable_arguments_are_reported_as_malformed() {
+        // GIVEN
+        let mut server = mockito::Server::new();
+        let mock = server
+            .mock("POST", "/chat/completions")
+            .with_status(200)
+            .with_body(
+                r#"{
+  "choices": [
+    {
+      "message": {
+        "role": "assistant",
+        "tool_calls": [
+          { "id": "call_01", "type": "function", "function": { "name": "list_files", "arguments": "{\"path\": \"src" } }
+        ]
+      },
+      "finish_reason": "tool_calls"
+    }
+  ]
+}"#,
+            )
+            .create();
+        let provider = OpenAiProvider::new(
+            HttpClient::new(Client::new(), None),
+            None,
+            server.url(),
+            MODEL.to_string(),
+        );
+        let contents = conversation();
+        let request = ModelRequest {
+            system_prompt: "system prompt",
+            contents: &contents[..1],
+            tools: &[],
+            settings: &GenerationSettings::default(),
+        };
+
+        // WHEN
+        let response = provider
+            .generate(&request, &mut |_| {})
+            .expect("response should've been a success");
+
+        // THEN
+        mock.assert();
+        assert!(response.parts.is_empty());
+        assert_eq!(
+            response.finish_reason,
+            Some(FinishReason::MalformedFunctionCall)
+        );
+    }
+
+    #[test]
+    fn no_authorization_header_is_sent_without_a_token() {
+        // GIVEN
+        let mut server = mockito::Server::new();
+        let mock = server
+            .mock("POST", "/chat/completions")
+            .match_header("authorization", Matcher::Missing)
+            .with_status(200)
+            .with_body(RESPONSE_WITH_TOOL_CALL)
+            .create();
+        let provider = OpenAiProvider::new(
+            HttpClient::new(Client::new(), None),
+            None,
+            server.url(),
+            MODEL.to_string(),
+        );
+        let contents = conversation();
+        let request = ModelRequest {
+            system_prompt: "system prompt",
+            contents: &contents[..1],
+            tools: &[],
+            settings: &GenerationSettings::default(),
+        };
+
+        // WHEN
+        let result = provider.generate(&request, &mut |_| {});
+
+        // THEN
+        mock.assert();
+        assert!(result.is_ok());
+    }
+}
@@ -1,30 +1,67 @@
+use super::attachments::{extract_attachment_paths, get_mime_type, load_attachment};
+use super::checkpoints::{Checkpoint, compact_checkpoints, rewind};
+use super::compaction::{Compaction, compact};
+use super::context::{ContextCheck, check_context};
 use super::domain::*;
+use super::elision::{ElisionPolicy, elide_stale_outputs};
+use super::export::{ExportFormat, get_export_file_name, render};
+use super::generation::GenerationSettings;
+use super::providers::{
+    Delta, FinishReason, ModelRequest, Provider, ProviderConfig, ProviderKind, get_provider,
+};
+use super::sessions::{
+    ResumeTarget, Session, SessionMeta, describe_session, get_latest_session_id, get_session_tree,
+    get_sessions_dir, list_sessions, load_session,
+};
 use super::tools::{execute_function_call, get_tools};
+use super::usage::SessionUsage;
 use anyhow::Context;
 use colored::Colorize;
 use reqwest::blocking::Client;
-use std::env::VarError;
 use std::io::Write;
-use tracing::debug;
-
-const SYSTEM_PROMPT: &str = include_str!("assets/system-prompt.txt");
-
-pub fn run(client: Client) -> anyhow::Result<()> {
-    let api_key = match std::env::var("GEMINI_API_KEY") {
-        Ok(k) => k,
-        Err(e) => match e {
-            VarError::NotPresent => {
-                return Err(anyhow::anyhow!(
-                    "environment variable GEMINI_API_KEY not set"
-                ));
-            }
-            VarError::NotUnicode(_) => {
-                return Err(anyhow::anyhow!(
-                    "environment variable GEMINI_API_KEY is not valid unicode"
-                ));
-            }
-        },
-    };
+use std::path::{Path, PathBuf};
+
+pub(super) const SYSTEM_PROMPT: &str = include_str!("assets/system-prompt.txt");
+pub(super) const CONTINUE_PROMPT: &str = "Your previous response was cut off because it reached the output token limit. Continue exactly where you left off, without repeating anything.";
+pub(super) const MALFORMED_CALL_PROMPT: &str = "Your last function call was malformed and couldn't be parsed. Call the function again, with arguments that are valid JSON and match the function's declared parameters.";
+pub(super) const MAX_AUTO_CONTINUATIONS: usize = 5;
+pub(super) const MAX_MALFORMED_CALL_CORRECTIONS: usize = 2;
+const MAX_LISTED_SESSIONS: usize = 20;
+
+#[derive(Debug)]
+pub struct RunOptions {
+    pub generation_settings: GenerationSettings,
+    /// Whether to continue responses that were cut off by the output token limit without asking
+    pub auto_continue: bool,
+    /// Whether to print the model's thoughts; can be toggled via /thoughts
+    pub show_thoughts: bool,
+    /// Overrides the size of the model's context window, in tokens
+    pub context_window: Option<usize>,
+    /// Share of the context window (in percent) past which the conversation is compacted before
+    /// sending a request; `None` disables automatic compaction
+    pub auto_compact: Option<usize>,
+    /// Number of model turns after which function outputs are replaced with a placeholder; 0
+    /// disables eliding old outputs
+    pub elide_after: Option<usize>,
+    /// Size in bytes above which function outputs are replaced with a placeholder once the model
+    /// has seen them; 0 disables eliding large outputs
+    pub elide_larger_than: Option<usize>,
+    /// Saved session to pick up; a new session is started if not set
+    pub resume: Option<ResumeTarget>,
+}
+
+pub fn run(
+    client: Client,
+    provider_config: ProviderConfig,
+    options: RunOptions,
+) -> anyhow::Result<()> {
+    let kind = provider_config.kind;
+    let mut provider = get_provider(provider_config, client)?;
+    let sessions_dir = get_sessions_dir().context("couldn't determine sessions directory")?;
+    let cwd = std::env::current_dir().unwrap_or_default();
+    let mut settings = options.generation_settings;
+    let mut show_thoughts = options.show_thoughts;
+    let elision = ElisionPolicy::new(options.elide_after, options.elide_larger_than);
 
     println!(
         "{}",
@@ -41,23 +78,102 @@ pub fn run(client: Client) -> anyhow::Result<()> {
     println!("{}", "/quit or /exit or /bye to quit".yellow());
     println!("{}", "/new to start a new session".yellow());
     println!("{}", "/clear to clear the screen".yellow());
+    println!(
+        "{}",
+        "/attach [path] to attach a file to the next message (lists attached files if no path is given); files can also be attached with @path".yellow()
+    );
+    println!(
+        "{}",
+        "/sessions [number, id or name] to switch to a saved session or fork (lists saved sessions and their forks if none is given)".yellow()
+    );
+    println!(
+        "{}",
+        "/fork [name] to continue in a copy of the conversation, keeping the original to return to"
+            .yellow()
+    );
+    println!(
+        "{}",
+        "/export [path] to save the conversation as Markdown, or as HTML if the path ends in .html"
+            .yellow()
+    );
+    println!(
+        "{}",
+        "/undo to drop your last message and the model's responses, and revert the files it edited"
+            .yellow()
+    );
+    println!(
+        "{}",
+        "/rewind [number] to return to an earlier message (lists earlier messages if no number is given)".yellow()
+    );
+    println!(
+        "{}",
+        "/compact [instructions] to replace older turns with a summary, eg. /compact keep the API design decisions".yellow()
+    );
+    println!(
+        "{}",
+        "/usage to show the tokens used so far, and their estimated cost".yellow()
+    );
+    println!(
+        "{}",
+        "/thoughts to show or hide the model's thoughts".yellow()
+    );
+    println!(
+        "{}",
+        "/retry to send the last request again (eg. after an API error)".yellow()
+    );
+    println!(
+        "{}",
+        "/model [name] to switch models (lists available models if no name is given)".yellow()
+    );
+    println!(
+        "{}",
+        "/set [key] [value] to change generation settings, eg. /set temperature 0.2 (shows current settings if no key is given; resets the setting if no value is given)".yellow()
+    );
+    println!();
+    println!("{}", format!("using model {}", provider.model()).dimmed());
     println!();
 
     let mut read_user_input = true;
     let tools = get_tools();
 
-    let mut body = GenerateContentBody {
-        contents: vec![],
-        tools,
-        system_instruction: SystemInstruction {
-            parts: vec![Part::Text(SYSTEM_PROMPT.to_string())],
-        },
-    };
+    let mut session = Session::new(sessions_dir.clone(), kind, provider.model());
+    let mut contents: Vec<Content> = vec![];
+    if let Some(target) = &options.resume {
+        let id = match target {
+            ResumeTarget::Id(id) => Some(id.clone()),
+            ResumeTarget::Latest => {
+                let id = get_latest_session_id(&sessions_dir, &cwd)?;
+                if id.is_none() {
+                    println!(
+                        "{}",
+                        "no saved session in this directory; starting a new one".dimmed()
+                    );
+                }
+                id
+            }
+            ResumeTarget::Pick => pick_session(&sessions_dir, &cwd)?,
+        };
 
-    let mut total_token_count = 0;
+        if let Some(id) = id {
+            (session, contents) = load_session(&sessions_dir, &id)?;
+            resume_session(provider.as_mut(), kind, &session.meta);
+        }
+    }
+    // files attached via /attach, sent along with the next message
+    let mut attachments: Vec<(String, Blob)> = vec![];
+    // one per user message, to return to via /undo and /rewind
+    let mut checkpoints: Vec<Checkpoint> = vec![];
+
+    let mut session_usage = SessionUsage::default();
+    let mut continuations = 0;
+    let mut malformed_call_corrections = 0;
 
     loop {
         if read_user_input {
+            if let Err(e) = session.save(&contents, provider.model()) {
+                println!("{}", format!("couldn't save session: {e:#}").red());
+            }
+
             print!("{}", "You: ".green());
             let mut user_input = String::new();
             std::io::stdout().flush().unwrap();
@@ -70,8 +186,10 @@ pub fn run(client: Client) -> anyhow::Result<()> {
             match user_input.as_str() {
                 "/bye" | "/quit" | "/exit" => break,
                 "/new" => {
-                    body.contents = vec![];
-                    total_token_count = 0;
+                    session = Session::new(sessions_dir.clone(), kind, provider.model());
+                    contents = vec![];
+                    attachments.clear();
+                    drop_checkpoints(&mut checkpoints);
                     // TODO: make this cross platform
                     print!("\x1B[2J\x1B[1;1H");
                     std::io::stdout().flush().context("couldn't clear screen")?;
@@ -84,111 +202,492 @@ pub fn run(client: Client) -> anyhow::Result<()> {
                     std::io::stdout().flush().context("couldn't clear screen")?;
                     continue;
                 }
-                _ => {}
-            }
+                "/model" => {
+                    match provider.list_models() {
+                        Ok(models) => {
+                            for model in models {
+                                if model == provider.model() {
+                                    println!("{}", format!("* {model}").green());
+                                } else {
+                                    println!("  {model}");
+                                }
+                            }
+                        }
+                        Err(e) => {
+                            println!("{}", format!("couldn't list models: {e}").red());
+                        }
+                    }
+                    continue;
+                }
+                input if input.starts_with("/model ") => {
+                    let model = input.trim_start_matches("/model ").trim().to_string();
+                    println!("{}", format!("switched to model {model}").dimmed());
+                    provider.set_model(model);
+                    continue;
+                }
+                "/set" => {
+                    let lines = settings.describe();
+                    if lines.is_empty() {
+                        println!("{}", "no generation settings set".dimmed());
+                    }
+                    for line in lines {
+                        println!("{line}");
+                    }
+                    continue;
+                }
+                input if input.starts_with("/set ") => {
+                    let args = input.trim_start_matches("/set ").trim();
+                    let (key, value) = match args.split_once(char::is_whitespace) {
+                        Some((key, value)) => (key, Some(value.trim())),
+                        None => (args, None),
+                    };
 
-            body.contents.push(Content {
-                parts: vec![Part::Text(user_input)],
-                role: Some(Role::User),
-            });
+                    match settings.set(key, value) {
+                        Ok(()) if value.is_some() => {
+                            println!(
+                                "{}",
+                                format!("{key} set to {}", value.unwrap_or_default()).dimmed()
+                            );
+                        }
+                        Ok(()) => println!("{}", format!("{key} reset").dimmed()),
+                        Err(e) => println!("{}", format!("couldn't change setting: {e}").red()),
+                    }
+                    continue;
+                }
+                input if input == "/compact" || input.starts_with("/compact ") => {
+                    let focus = input.trim_start_matches("/compact").trim();
+                    let request = ModelRequest {
+                        system_prompt: SYSTEM_PROMPT,
+                        contents: &contents,
+                        tools: &tools,
+                        settings: &settings,
+                    };
+
+                    println!("{}", "compacting the conversation".dimmed());
+                    match compact(
+                        provider.as_ref(),
+                        &request,
+                        (!focus.is_empty()).then_some(focus),
+                    ) {
+                        Ok(compaction) => apply_compaction(
+                            &mut contents,
+                            &mut checkpoints,
+                            compaction,
+                            &mut session_usage,
+                            provider.model(),
+                        ),
+                        Err(e) => {
+                            println!(
+                                "{}",
+                                format!("couldn't compact the conversation: {e:#}").red()
+                            )
+                        }
+                    }
+                    continue;
+                }
+                "/sessions" => {
+                    match list_sessions(&sessions_dir).map(get_session_tree) {
+                        Ok(sessions) if sessions.is_empty() => {
+                            println!("{}", "no saved sessions".dimmed())
+                        }
+                        Ok(sessions) => print_sessions(&sessions, &cwd, &session.meta.id),
+                        Err(e) => println!("{}", format!("couldn't list sessions: {e:#}").red()),
+                    }
+                    continue;
+                }
+                input if input.starts_with("/sessions ") => {
+                    let choice = input.trim_start_matches("/sessions ").trim();
+                    let loaded = list_sessions(&sessions_dir).and_then(|sessions| {
+                        let sessions = get_session_tree(sessions);
+                        let id = resolve_session_choice(choice, &sessions);
+                        load_session(&sessions_dir, id)
+                    });
+                    match loaded {
+                        Ok(loaded) => {
+                            (session, contents) = loaded;
+                            attachments.clear();
+                            drop_checkpoints(&mut checkpoints);
+                            resume_session(provider.as_mut(), kind, &session.meta);
+                        }
+                        Err(e) => println!("{}", format!("couldn't load session: {e:#}").red()),
+                    }
+                    continue;
+                }
+                input if input == "/export" || input.starts_with("/export ") => {
+                    let path = match input.trim_start_matches("/export").trim() {
+                        "" => PathBuf::from(get_export_file_name(
+                            &session.meta,
+                            ExportFormat::Markdown,
+                        )),
+                        path => PathBuf::from(path),
+                    };
+                    let transcript =
+                        render(&session.meta, &contents, ExportFormat::from_path(&path));
+                    match std::fs::write(&path, transcript) {
+                        Ok(()) => println!(
+                            "{}",
+                            format!("exported the conversation to {}", path.to_string_lossy())
+                                .dimmed()
+                        ),
+                        Err(e) => println!(
+                            "{}",
+                            format!("couldn't write {}: {e}", path.to_string_lossy()).red()
+                        ),
+                    }
+                    continue;
+                }
+                "/undo" => {
+                    match checkpoints.len().checked_sub(1) {
+                        Some(last) => return_to_checkpoint(&mut checkpoints, last, &mut contents),
+                        None => println!("{}", "nothing to undo".dimmed()),
+                    }
+                    continue;
+                }
+                "/rewind" => {
+                    if checkpoints.is_empty() {
+                        println!("{}", "there are no earlier messages to return to".dimmed());
+                        continue;
+                    }
+                    match pick_checkpoint(&checkpoints) {
+                        Ok(Some(index)) => {
+                            return_to_checkpoint(&mut checkpoints, index, &mut contents)
+                        }
+                        Ok(None) => {}
+                        Err(e) => println!("{}", format!("{e:#}").red()),
+                    }
+                    continue;
+                }
+                input if input.starts_with("/rewind ") => {
+                    let number = input.trim_start_matches("/rewind ").trim();
+                    match number.parse::<usize>() {
+                        Ok(number) if number > 0 => {
+                            return_to_checkpoint(&mut checkpoints, number - 1, &mut contents)
+                        }
+                        _ => println!("{}", format!("{number} isn't a message number").red()),
+                    }
+                    continue;
+                }
+                input if input == "/fork" || input.starts_with("/fork ") => {
+                    let name = input.trim_start_matches("/fork").trim();
+                    if contents.is_empty() {
+                        println!("{}", "nothing to fork yet".dimmed());
+                        continue;
+                    }
+
+                    let fork = session.fork((!name.is_empty()).then_some(name));
+                    let parent = std::mem::replace(&mut session, fork);
+                    match session.save(&contents, provider.model()) {
+                        Ok(()) => println!(
+                            "{}",
+                            format!(
+                                "forked session {} into {}; switch back with /sessions {}",
+                                parent.meta.id, session.meta.id, parent.meta.id
+                            )
+                            .dimmed()
+                        ),
+                        Err(e) => {
+                            println!("{}", format!("couldn't save the fork: {e:#}").red());
+                            session = parent;
+                        }
+                    }
+                    continue;
+                }
+                "/usage" => {
+                    for line in session_usage.summary() {
+                        println!("{line}");
+                    }
+                    continue;
+                }
+                "/thoughts" => {
+                    show_thoughts = !show_thoughts;
+                    let status = if show_thoughts { "shown" } else { "hidden" };
+                    println!("{}", format!("thoughts will be {status}").dimmed());
+                    continue;
+                }
+                "/retry" => {
+                    if !ends_with_user_turn(&contents) {
+                        println!("{}", "nothing to retry".dimmed());
+                        continue;
+                    }
+                }
+                "/attach" => {
+                    if attachments.is_empty() {
+                        println!("{}", "no files attached".dimmed());
+                    }
+                    for (path, blob) in &attachments {
+                        println!("  {path} ({})", blob.mime_type);
+                    }
+                    continue;
+                }
+                input if input.starts_with("/attach ") => {
+                    let path = input.trim_start_matches("/attach ").trim();
+                    match load_attachment_for(provider.as_ref(), path) {
+                        Ok(blob) => {
+                            println!(
+                                "{}",
+                                format!("attached {path}; it'll be sent with your next message")
+                                    .dimmed()
+                            );
+                            attachments.push((path.to_string(), blob));
+                        }
+                        Err(e) => println!("{}", format!("couldn't attach file: {e:#}").red()),
+                    }
+                    continue;
+                }
+                input => {
+                    let mut parts = vec![Part::Text(input.to_string())];
+                    let referenced = extract_attachment_paths(input)
+                        .into_iter()
+                        .map(|path| {
+                            load_attachment_for(provider.as_ref(), path)
+                                .map(|blob| (path.to_string(), blob))
+                        })
+                        .collect::<anyhow::Result<Vec<_>>>();
+                    let referenced = match referenced {
+                        Ok(r) => r,
+                        Err(e) => {
+                            println!("{}", format!("couldn't attach file: {e:#}").red());
+                            continue;
+                        }
+                    };
+
+                    for (path, blob) in attachments.drain(..).chain(referenced) {
+                        println!(
+                            "{}",
+                            format!("sending {path} ({})", blob.mime_type).dimmed()
+                        );
+                        parts.push(Part::InlineData(blob));
+                    }
+                    // messages sent after a failed request are merged into the same turn, but
+                    // still get their own checkpoint
+                    checkpoints.push(Checkpoint::new(&contents, input));
+                    push_user_parts(&mut contents, parts);
+                }
+            }
         }
 
-        if let Ok(r) = serde_json::to_string_pretty(&body) {
-            debug!("request: {}", &r);
-        } else {
-            debug!("request: {:?}", &body);
+        let context_window = options.context_window.or_else(|| provider.context_window());
+        if let Some(context_window) = context_window
+            && !ensure_context_fits(
+                provider.as_ref(),
+                &mut contents,
+                &mut checkpoints,
+                &tools,
+                &settings,
+                context_window,
+                options.auto_compact,
+                &mut session_usage,
+            )
+        {
+            // the pending user turn is kept, like when a request fails
+            read_user_input = true;
+            continue;
         }
 
-        let resp = client.post("https://generativelanguage.googleapis.com/v1beta/models/gemini-2.0-flash:generateContent")
-        .json(&body)
-        .header("content-type", "application/json")
-        .header("x-goog-api-key", &api_key)
-        .send();
+        let request = ModelRequest {
+            system_prompt: SYSTEM_PROMPT,
+            contents: &contents,
+            tools: &tools,
+            settings: &settings,
+        };
+
+        let mut printer = StreamPrinter::new(provider.name());
+        let resp = provider.generate(&request, &mut |delta| match delta {
+            Delta::Text(t) => printer.print(Segment::Text, t),
+            Delta::Thought(t) if show_thoughts => printer.print(Segment::Thought, t),
+            Delta::Thought(_) => {}
+        });
+        printer.finish();
 
         let resp = match resp {
             Ok(r) => r,
             Err(e) => {
+                // the pending user turn is kept, so that it can be re-sent via /retry, or merged
+                // with the next input
                 println!(
                     "{}",
-                    format!("couldn't send a request to Gemini API: {e}").red()
+                    format!("couldn't get a response from {}: {e}", provider.name()).red()
                 );
+                println!("{}", "use /retry to send the request again".dimmed());
+                read_user_input = true;
                 continue;
             }
         };
 
-        if !resp.status().is_success() {
-            let code = resp.status().as_u16();
-            match resp.text() {
-                Ok(t) => debug!("non success response (code: {code}): {}", &t),
-                Err(e) => debug!("non success response (code: {code}), couldn't get text: {e}",),
-            }
-            anyhow::bail!("gemini API returned a non success code: {code}");
+        if let Some(usage) = resp.usage {
+            let line = session_usage.record(provider.model(), usage);
+            println!("{}", line.dimmed());
         }
 
-        let resp_body = resp
-            .text()
-            .inspect_err(|e| debug!("couldn't get response text: {e}"))
-            .context("couldn't get response text")?;
-        debug!("response: {}", &resp_body);
+        let finish_reason = resp.finish_reason.unwrap_or(FinishReason::Stop);
 
-        let resp = match serde_json::from_str::<GenerateContentResponse>(&resp_body) {
-            Ok(r) => r,
-            Err(e) => {
+        if finish_reason == FinishReason::MalformedFunctionCall {
+            // whatever else the model generated is dropped along with the broken call, and the
+            // model is asked to try again
+            if malformed_call_corrections < MAX_MALFORMED_CALL_CORRECTIONS {
+                malformed_call_corrections += 1;
                 println!(
                     "{}",
-                    format!("couldn't parse response from Gemini: {e}").red()
+                    format!(
+                        "{} generated a malformed function call; asking it to try again",
+                        provider.name()
+                    )
+                    .yellow()
+                );
+                push_user_parts(
+                    &mut contents,
+                    vec![Part::Text(MALFORMED_CALL_PROMPT.to_string())],
                 );
+                read_user_input = false;
                 continue;
             }
-        };
 
-        total_token_count += resp.usage_metadata.total_token_count;
+            println!(
+                "{}",
+                format!(
+                    "{} kept generating malformed function calls; try rephrasing your request, or /retry",
+                    provider.name()
+                )
+                .red()
+            );
+            malformed_call_corrections = 0;
+            read_user_input = true;
+            continue;
+        }
+        malformed_call_corrections = 0;
 
-        let content = match resp.candidates.into_iter().next() {
-            Some(c) => c.content,
-            None => {
-                println!("{}", "gemini returned an empty response".dimmed());
-                continue;
+        if let Some(explanation) = explain_finish_reason(provider.name(), &finish_reason) {
+            println!("{}", explanation.yellow());
+        }
+
+        if resp.parts.is_empty() {
+            if finish_reason == FinishReason::Stop {
+                println!(
+                    "{}",
+                    format!("{} returned an empty response", provider.name()).dimmed()
+                );
             }
+            // otherwise the next message would be merged into the same turn, and sent (and
+            // likely blocked) again along with it
+            if drop_unanswered_message(&mut contents, &mut checkpoints) {
+                println!(
+                    "{}",
+                    "your message was dropped from the conversation, so that it isn't sent again"
+                        .dimmed()
+                );
+            } else {
+                println!(
+                    "{}",
+                    "use /undo to drop your last message along with the turns that followed it"
+                        .dimmed()
+                );
+            }
+            read_user_input = true;
+            continue;
+        }
+
+        let content = Content {
+            parts: resp.parts,
+            role: Some(Role::Model),
         };
 
         let mut function_results: Vec<Part> = vec![];
+        let mut follow_ups: Vec<Part> = vec![];
         for part in &content.parts {
             match part {
-                Part::Text(t) => {
-                    println!("{}: {}", "Gemini".blue(), t.trim());
-                }
                 Part::FunctionCall(call) => {
-                    println!("{}: wants to call function {}", "Gemini".blue(), call.name);
-                    let function_resp =
-                        execute_function_call(call).unwrap_or_else(|e| FunctionResponse {
-                            id: call.id.clone(),
-                            name: call.name.clone(),
-                            response: FunctionCallResponse::Error(e.to_string()),
-                            will_continue: None,
-                        });
-
-                    function_results.push(Part::FunctionResponse(function_resp));
-                }
-                _ => {}
+                    println!(
+                        "{}: wants to call function {}",
+                        provider.name().blue(),
+                        call.name
+                    );
+                    if let (FunctionDeclarationName::EditFile, Some(checkpoint)) =
+                        (&call.name, checkpoints.last_mut())
+                        && let Some(path) = call.target()
+                        && let Err(e) = checkpoint.record_file(Path::new(path))
+                    {
+                        println!(
+                            "{}",
+                            format!("{path} won't be restored by /undo: {e:#}").yellow()
+                        );
+                    }
+                    let supports_attachment =
+                        |mime_type: &str| provider.supports_attachment(mime_type);
+                    match execute_function_call(call, &supports_attachment) {
+                        Ok(result) => {
+                            function_results.push(Part::FunctionResponse(result.response));
+                            follow_ups.extend(result.follow_up);
+                        }
+                        Err(e) => {
+                            function_results.push(Part::FunctionResponse(FunctionResponse {
+                                id: call.id.clone(),
+                                name: call.name.clone(),
+                                response: FunctionCallResponse::Error(e.to_string()),
+                                will_continue: None,
+                            }));
+                        }
+                    }
+                }
+                Part::ExecutableCode(code) => {
+                    println!("{}: ran code", provider.name().blue());
+                    println!("{}", code.code.trim_end().dimmed());
+                }
+                Part::CodeExecutionResult(result) => {
+                    let output = result.output.as_deref().unwrap_or_default().trim_end();
+                    println!(
+                        "{}",
+                        format!("code execution result ({}):\n{output}", result.outcome).dimmed()
+                    );
+                }
+                Part::InlineData(blob) => println!(
+                    "{}",
+                    format!("{} returned {} data", provider.name(), blob.mime_type).dimmed()
+                ),
+                Part::FileData(file_data) => println!(
+                    "{}",
+                    format!("{} referenced {}", provider.name(), file_data.file_uri).dimmed()
+                ),
+                Part::Unknown(_) => println!(
+                    "{}",
+                    format!(
+                        "{} returned a part agent1 doesn't know how to show; it's kept in the conversation",
+                        provider.name()
+                    )
+                    .dimmed()
+                ),
+                Part::Text(_) | Part::Thought(_) | Part::FunctionResponse(_) => {}
             }
         }
-        body.contents.push(content);
+        contents.push(content);
 
-        if total_token_count > 0 {
+        let elided = elide_stale_outputs(&mut contents, &elision);
+        if elided > 0 {
             println!(
                 "{}",
-                format!("total tokens used: {total_token_count}").dimmed()
+                format!("elided {elided} stale function outputs from the conversation").dimmed()
             );
         }
 
         if function_results.is_empty() {
+            if finish_reason == FinishReason::MaxTokens
+                && should_continue(options.auto_continue, continuations)?
+            {
+                continuations += 1;
+                push_user_parts(&mut contents, vec![Part::Text(CONTINUE_PROMPT.to_string())]);
+                read_user_input = false;
+                println!("{}", "---".dimmed());
+                continue;
+            }
+
+            continuations = 0;
             read_user_input = true;
             println!("{}", "---".dimmed());
             continue;
         }
 
-        body.contents.push(Content {
+        // some APIs require function responses to come before anything else in a turn
+        function_results.extend(follow_ups);
+        contents.push(Content {
             parts: function_results,
             role: Some(Role::User),
         });
@@ -197,5 +696,612 @@ pub fn run(client: Client) -> anyhow::Result<()> {
         println!("{}", "---".dimmed());
     }
 
+    println!("{}", "usage:".blue());
+    for line in session_usage.summary() {
+        println!("{line}");
+    }
+
+    session.save(&contents, provider.model())?;
+    if !contents.is_empty() {
+        println!(
+            "{}",
+            format!(
+                "session saved; resume it with agent1 --resume {}",
+                session.meta.id
+            )
+            .dimmed()
+        );
+    }
+
     Ok(())
 }
+
+/// Checks the conversation against the context window before sending it, compacting it if it's
+/// past the `auto_compact` threshold; returns whether the request can be sent
+#[allow(clippy::too_many_arguments)]
+fn ensure_context_fits(
+    provider: &dyn Provider,
+    contents: &mut Vec<Content>,
+    checkpoints: &mut [Checkpoint],
+    tools: &[FunctionDeclaration],
+    settings: &GenerationSettings,
+    context_window: usize,
+    auto_compact: Option<usize>,
+    session_usage: &mut SessionUsage,
+) -> bool {
+    let request = ModelRequest {
+        system_prompt: SYSTEM_PROMPT,
+        contents,
+        tools,
+        settings,
+    };
+    let print_overflow = |tokens: usize| {
+        println!(
+            "{}",
+            format!(
+                "the request wasn't sent: it's {tokens} tokens long, which exceeds {}'s context window of {context_window} tokens; compact the conversation with /compact, start a new session with /new, or switch to a model with a larger context window with /model",
+                provider.model()
+            )
+            .red()
+        );
+    };
+
+    match check_context(provider, &request, context_window, auto_compact) {
+        Ok(ContextCheck::Compacted {
+            percent,
+            tokens,
+            result,
+        }) => {
+            println!(
+                "{}",
+                format!(
+                    "the conversation takes up {percent}% of {}'s context window; compacting it",
+                    provider.model()
+                )
+                .dimmed()
+            );
+            match result {
+                Ok(compaction) => {
+                    apply_compaction(
+                        contents,
+                        checkpoints,
+                        compaction,
+                        session_usage,
+                        provider.model(),
+                    );
+                    // the kept turns alone can still be too large for the context window
+                    return ensure_context_fits(
+                        provider,
+                        contents,
+                        checkpoints,
+                        tools,
+                        settings,
+                        context_window,
+                        None,
+                        session_usage,
+                    );
+                }
+                Err(e) => {
+                    println!(
+                        "{}",
+                        format!("couldn't compact the conversation: {e:#}").red()
+                    );
+                    if tokens > context_window {
+                        print_overflow(tokens);
+                        return false;
+                    }
+                }
+            }
+        }
+        Ok(ContextCheck::Fits) => {}
+        Ok(ContextCheck::NearlyFull { percent, tokens }) => println!(
+            "{}",
+            format!(
+                "the conversation takes up {percent}% of {}'s context window ({tokens} of {context_window} tokens); consider compacting it with /compact, or starting a new session with /new",
+                provider.model()
+            )
+            .yellow()
+        ),
+        Ok(ContextCheck::Overflows { tokens }) => {
+            print_overflow(tokens);
+            return false;
+        }
+        Err(e) => println!("{}", format!("couldn't count tokens: {e}").dimmed()),
+    }
+
+    true
+}
+
+/// Lets the user pick a session to resume from the most recent ones; `None` if there are none, or
+/// if the user didn't pick any
+fn pick_session(sessions_dir: &Path, cwd: &Path) -> anyhow::Result<Option<String>> {
+    let sessions = get_session_tree(list_sessions(sessions_dir)?);
+    if sessions.is_empty() {
+        println!("{}", "no saved sessions; starting a new one".dimmed());
+        return Ok(None);
+    }
+
+    print_sessions(&sessions, cwd, "");
+    print!(
+        "{}",
+        "session to resume (number, id or fork name; empty for a new one): ".yellow()
+    );
+    std::io::stdout().flush().context("couldn't flush stdout")?;
+    let mut choice = String::new();
+    std::io::stdin()
+        .read_line(&mut choice)
+        .context("couldn't read user input")?;
+
+    let choice = choice.trim();
+    if choice.is_empty() {
+        println!("{}", "starting a new session".dimmed());
+        return Ok(None);
+    }
+
+    Ok(Some(resolve_session_choice(choice, &sessions).to_string()))
+}
+
+/// Prints the sessions numbered, with forks indented under the session they were forked from
+fn print_sessions(sessions: &[(usize, SessionMeta)], cwd: &Path, current_id: &str) {
+    for (i, (depth, meta)) in sessions.iter().take(MAX_LISTED_SESSIONS).enumerate() {
+        let branch = match depth {
+            0 => String::new(),
+            depth => format!("{}└─ ", "   ".repeat(depth - 1)),
+        };
+        let line = format!("{:>2}. {branch}{}", i + 1, describe_session(meta, cwd));
+        if meta.id == current_id {
+            println!("{}", line.green());
+        } else {
+            println!("{line}");
+        }
+    }
+}
+
+/// Returns the id of the session the user chose, either by its number in the list, by its fork
+/// name or by (a prefix of) its id
+fn resolve_session_choice<'a>(choice: &'a str, sessions: &'a [(usize, SessionMeta)]) -> &'a str {
+    let by_number = choice
+        .parse::<usize>()
+        .ok()
+        .filter(|n| (1..=sessions.len().min(MAX_LISTED_SESSIONS)).contains(n))
+        .map(|n| &sessions[n - 1].1);
+    let by_name = || {
+        sessions
+            .iter()
+            .map(|(_, meta)| meta)
+            .find(|meta| meta.name.as_deref() == Some(choice))
+    };
+
+    by_number
+        .or_else(by_name)
+        .map_or(choice, |meta| meta.id.as_str())
+}
+
+/// Switches to the model a resumed session was using, if it's from the same provider
+fn resume_session(provider: &mut dyn Provider, kind: ProviderKind, meta: &SessionMeta) {
+    if meta.provider == kind && meta.model != provider.model() {
+        provider.set_model(meta.model.clone());
+    }
+
+    println!(
+        "{}",
+        format!(
+            "resumed session {} ({} turns): {}",
+            meta.id, meta.turns, meta.title
+        )
+        .dimmed()
+    );
+    if meta.provider != kind {
+        println!(
+            "{}",
+            format!(
+                "the session was started with {}; continuing it with {}",
+                meta.model,
+                provider.model()
+            )
+            .yellow()
+        );
+    }
+}
+
+/// Lists the messages that can be returned to, and lets the user pick one
+fn pick_checkpoint(checkpoints: &[Checkpoint]) -> anyhow::Result<Option<usize>> {
+    for (i, checkpoint) in checkpoints.iter().enumerate() {
+        let mut message = checkpoint
+            .message
+            .lines()
+            .next()
+            .unwrap_or_default()
+            .to_string();
+        if checkpoint.is_compacted() {
+            message.push_str(&" (compacted; only files are reverted)".dimmed().to_string());
+        }
+        let edited = checkpoint
+            .edited_files()
+            .map(|path| path.to_string_lossy())
+            .collect::<Vec<_>>();
+        if edited.is_empty() {
+            println!("{:>2}. {message}", i + 1);
+        } else {
+            println!(
+                "{:>2}. {message} {}",
+                i + 1,
+                format!("(edited {})", edited.join(", ")).dimmed()
+            );
+        }
+    }
+
+    print!(
+        "{}",
+        "message to return to (files edited since will be reverted; leave empty to cancel): "
+            .yellow()
+    );
+    std::io::stdout().flush().context("couldn't flush stdout")?;
+    let mut choice = String::new();
+    std::io::stdin()
+        .read_line(&mut choice)
+        .context("couldn't read user input")?;
+
+    match choice.trim() {
+        "" => Ok(None),
+        choice => match choice.parse::<usize>() {
+            Ok(number) if number > 0 => Ok(Some(number - 1)),
+            _ => anyhow::bail!("{choice} isn't a message number"),
+        },
+    }
+}
+
+/// Returns to the state before the checkpoint's message, and tells the user what changed
+fn return_to_checkpoint(
+    checkpoints: &mut Vec<Checkpoint>,
+    index: usize,
+    contents: &mut Vec<Content>,
+) {
+    let (message, compacted) = checkpoints
+        .get(index)
+        .map(|c| (c.message.clone(), c.is_compacted()))
+        .unwrap_or_default();
+    let contents_len = contents.len();
+
+    let result = rewind(checkpoints, index, contents);
+    if result.is_ok() {
+        // messages merged into a turn (eg. after a failed request) only remove parts of it
+        let line = match (contents_len - contents.len(), compacted) {
+            (0, true) => None,
+            (removed, true) => Some(format!(
+                "removed {removed} turns; the conversation can't go back further, since \"{message}\" was compacted"
+            )),
+            (0, false) => Some(format!("back to before \"{message}\"")),
+            (removed, false) => Some(format!(
+                "removed {removed} turns, back to before \"{message}\""
+            )),
+        };
+        if let Some(line) = line {
+            println!("{}", line.dimmed());
+        }
+    }
+    match result {
+        Ok(restored) => {
+            for path in restored {
+                println!(
+                    "{}",
+                    format!("reverted {}", path.to_string_lossy()).dimmed()
+                );
+            }
+        }
+        Err(e) => println!("{}", format!("couldn't rewind: {e:#}").red()),
+    }
+}
+
+/// Replaces the conversation with its compacted version; checkpoints of compacted messages can
+/// still restore files, but no longer return the conversation to them
+fn apply_compaction(
+    contents: &mut Vec<Content>,
+    checkpoints: &mut [Checkpoint],
+    compaction: Compaction,
+    session_usage: &mut SessionUsage,
+    model: &str,
+) {
+    if let Some(usage) = compaction.usage {
+        let line = session_usage.record(model, usage);
+        println!("{}", line.dimmed());
+    }
+
+    println!(
+        "{}",
+        format!(
+            "replaced {} turns with a summary; {} turns were kept",
+            compaction.compacted_turns,
+            compaction.contents.len()
+        )
+        .dimmed()
+    );
+    *contents = compaction.contents;
+
+    let compacted = compact_checkpoints(checkpoints, compaction.compacted_turns);
+    if compacted > 0 {
+        println!(
+            "{}",
+            format!("/rewind can no longer return the conversation to the {compacted} compacted messages, but still reverts the files edited since them").dimmed()
+        );
+    }
+}
+
+/// Drops the checkpoints when switching to another conversation, warning about the files whose
+/// edits can no longer be reverted
+fn drop_checkpoints(checkpoints: &mut Vec<Checkpoint>) {
+    let mut edited = checkpoints
+        .iter()
+        .flat_map(Checkpoint::edited_files)
+        .map(|path| path.to_string_lossy().into_owned())
+        .collect::<Vec<_>>();
+    edited.sort();
+    edited.dedup();
+    if !edited.is_empty() {
+        println!(
+            "{}",
+            format!(
+                "edits made to {} in the previous conversation can no longer be undone",
+                edited.join(", ")
+            )
+            .yellow()
+        );
+    }
+
+    checkpoints.clear();
+}
+
+/// Reads a file into inline data, if the provider accepts files of its type
+pub(super) fn load_attachment_for(provider: &dyn Provider, path: &str) -> anyhow::Result<Blob> {
+    let path = Path::new(path);
+    if let Some(mime_type) = get_mime_type(path)
+        && !provider.supports_attachment(mime_type)
+    {
+        anyhow::bail!(
+            "{} doesn't support {mime_type} attachments",
+            provider.name()
+        );
+    }
+
+    load_attachment(path)
+}
+
+/// Returns a message explaining why the model stopped, unless it simply finished its turn
+pub(super) fn explain_finish_reason(name: &str, reason: &FinishReason) -> Option<String> {
+    match reason {
+        FinishReason::Stop | FinishReason::MalformedFunctionCall => None,
+        FinishReason::MaxTokens => Some(format!(
+            "{name}'s response was cut off because it reached the output token limit (see max_output_tokens in /set)"
+        )),
+        FinishReason::Safety => Some(format!(
+            "{name} blocked the response for safety reasons; try rephrasing your request (for gemini, thresholds can be changed via safety_settings in /set)"
+        )),
+        FinishReason::Recitation => Some(format!(
+            "{name} blocked the response because it too closely resembled existing content it was trained on; try asking for a different approach"
+        )),
+        FinishReason::Other(reason) => Some(format!(
+            "{name} stopped generating unexpectedly (reason: {reason})"
+        )),
+    }
+}
+
+/// Decides whether to continue a response that was cut off by the output token limit, asking
+/// the user unless continuing automatically
+fn should_continue(auto_continue: bool, continuations: usize) -> anyhow::Result<bool> {
+    if auto_continue && continuations < MAX_AUTO_CONTINUATIONS {
+        println!("{}", "continuing the response".dimmed());
+        return Ok(true);
+    }
+
+    print!("{}", "continue the response? [y/N] ".yellow());
+    std::io::stdout().flush().context("couldn't flush stdout")?;
+    let mut answer = String::new();
+    std::io::stdin()
+        .read_line(&mut answer)
+        .context("couldn't read user input")?;
+
+    Ok(matches!(answer.trim().to_lowercase().as_str(), "y" | "yes"))
+}
+
+fn ends_with_user_turn(contents: &[Content]) -> bool {
+    contents
+        .last()
+        .is_some_and(|c| matches!(c.role, Some(Role::User)))
+}
+
+/// Drops the user's pending message along with its checkpoint, when the model didn't respond to
+/// it (eg. because the response was blocked); returns whether there was one to drop.
+///
+/// Nothing is dropped if the conversation ends with function responses instead, since the model
+/// turn that called the functions has to be dropped along with them.
+fn drop_unanswered_message(contents: &mut Vec<Content>, checkpoints: &mut Vec<Checkpoint>) -> bool {
+    let pending = contents.len().saturating_sub(1);
+    // the model didn't respond, so no files were edited since the message, and rewinding only
+    // drops turns
+    match checkpoints.iter().position(|c| c.is_in_turn(pending)) {
+        Some(index) if ends_with_user_turn(contents) => {
+            rewind(checkpoints, index, contents).is_ok()
+        }
+        _ => false,
+    }
+}
+
+/// Adds parts to the conversation as a user turn.
+///
+/// If the conversation already ends with a user turn (eg. because the previous request failed),
+/// the parts are merged into it, since providers expect user and model turns to alternate.
+pub(super) fn push_user_parts(contents: &mut Vec<Content>, parts: Vec<Part>) {
+    if ends_with_user_turn(contents)
+        && let Some(last) = contents.last_mut()
+    {
+        last.parts.extend(parts);
+        return;
+    }
+
+    contents.push(Content {
+        parts,
+        role: Some(Role::User),
+    });
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Segment {
+    Text,
+    Thought,
+}
+
+/// Prints text and thoughts as they stream in; text is prefixed with the name of the model's
+/// provider, and thoughts are dimmed.
+///
+/// Leading and trailing whitespace of each segment is left out.
+struct StreamPrinter {
+    name: &'static str,
+    current: Option<Segment>,
+    pending_whitespace: String,
+}
+
+impl StreamPrinter {
+    fn new(name: &'static str) -> Self {
+        Self {
+            name,
+            current: None,
+            pending_whitespace: String::new(),
+        }
+    }
+
+    fn print(&mut self, segment: Segment, delta: &str) {
+        let continues_segment = self.current == Some(segment);
+        let delta = if continues_segment {
+            delta
+        } else {
+            delta.trim_start()
+        };
+
+        let text = delta.trim_end();
+        if text.is_empty() {
+            if continues_segment {
+                self.pending_whitespace.push_str(delta);
+            }
+            return;
+        }
+
+        if !continues_segment {
+            if self.current.is_some() {
+                println!();
+            }
+            match segment {
+                Segment::Text => print!("{}: ", self.name.blue()),
+                Segment::Thought => print!("{}", format!("{} (thinking): ", self.name).dimmed()),
+            }
+            self.current = Some(segment);
+            self.pending_whitespace.clear();
+        }
+
+        let output = format!("{}{text}", self.pending_whitespace);
+        match segment {
+            Segment::Text => print!("{output}"),
+            Segment::Thought => print!("{}", output.dimmed()),
+        }
+        self.pending_whitespace = delta[text.len()..].to_string();
+        let _ = std::io::stdout().flush();
+    }
+
+    fn finish(&mut self) {
+        if self.current.is_some() {
+            println!();
+        }
+        self.current = None;
+        self.pending_whitespace.clear();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    //-------------//
+    //  SUCCESSES  //
+    //-------------//
+
+    #[test]
+    fn input_after_a_failed_request_is_merged_into_the_pending_user_turn() {
+        // GIVEN
+        let mut contents = vec![Content {
+            parts: vec![Part::Text("summarize the README".to_string())],
+            role: Some(Role::User),
+        }];
+
+        // WHEN
+        push_user_parts(
+            &mut contents,
+            vec![Part::Text("and the changelog".to_string())],
+        );
+
+        // THEN
+        assert_eq!(contents.len(), 1);
+        assert_eq!(contents[0].parts.len(), 2);
+    }
+
+    #[test]
+    fn blocked_messages_are_not_sent_again_with_the_next_one() {
+        // GIVEN
+        let text = |text: &str| Part::Text(text.to_string());
+        let mut contents = vec![
+            Content {
+                parts: vec![text("hi")],
+                role: Some(Role::User),
+            },
+            Content {
+                parts: vec![text("hello!")],
+                role: Some(Role::Model),
+            },
+            Content {
+                parts: vec![text("something that gets blocked")],
+                role: Some(Role::User),
+            },
+        ];
+        let mut checkpoints = vec![
+            Checkpoint::new(&contents[..0], "hi"),
+            Checkpoint::new(&contents[..2], "something that gets blocked"),
+        ];
+
+        // WHEN
+        let dropped = drop_unanswered_message(&mut contents, &mut checkpoints);
+        push_user_parts(&mut contents, vec![text("something else")]);
+
+        // THEN
+        assert!(dropped);
+        assert_eq!(checkpoints.len(), 1);
+        let texts = contents
+            .iter()
+            .flat_map(|c| &c.parts)
+            .filter_map(|part| match part {
+                Part::Text(text) => Some(text.as_str()),
+                _ => None,
+            })
+            .collect::<Vec<_>>();
+        assert_eq!(texts, vec!["hi", "hello!", "something else"]);
+    }
+
+    #[test]
+    fn input_after_a_model_turn_starts_a_new_user_turn() {
+        // GIVEN
+        let mut contents = vec![
+            Content {
+                parts: vec![Part::Text("hi".to_string())],
+                role: Some(Role::User),
+            },
+            Content {
+                parts: vec![Part::Text("hello!".to_string())],
+                role: Some(Role::Model),
+            },
+        ];
+
+        // WHEN
+        push_user_parts(&mut contents, vec![Part::Text("bye".to_string())]);
+
+        // THEN
+        assert_eq!(contents.len(), 3);
+        assert!(ends_with_user_turn(&contents));
+    }
+}
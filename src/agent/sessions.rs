@@ -0,0 +1,458 @@
+use super::domain::{Content, Part, Role};
+use super::providers::ProviderKind;
+use anyhow::Context;
+use etcetera::{BaseStrategy, choose_base_strategy};
+use serde::{Deserialize, Serialize};
+use std::path::{Path, PathBuf};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+const SESSION_FILE_EXTENSION: &str = "jsonl";
+const MAX_TITLE_CHARS: usize = 60;
+
+/// Which past session to pick up when agent1 starts
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ResumeTarget {
+    /// The session with the given id (or unique id prefix)
+    Id(String),
+    /// The most recently updated session started in the current directory
+    Latest,
+    /// A session the user picks from a list
+    Pick,
+}
+
+/// Metadata stored on the first line of a session file; the remaining lines hold the session's
+/// turns, one per line
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub(super) struct SessionMeta {
+    pub(super) id: String,
+    /// Unix timestamps, in seconds
+    pub(super) created_at: u64,
+    pub(super) updated_at: u64,
+    /// Directory agent1 was started in
+    pub(super) cwd: PathBuf,
+    pub(super) provider: ProviderKind,
+    pub(super) model: String,
+    /// The session's first message, shortened
+    pub(super) title: String,
+    pub(super) turns: usize,
+    /// Id of the session this one was forked from
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub(super) parent: Option<String>,
+    /// Name given to the fork via /fork
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub(super) name: Option<String>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+enum Record {
+    Session(SessionMeta),
+    Content(Content),
+}
+
+/// A conversation that's saved to `<id>.jsonl` in the sessions directory
+#[derive(Debug)]
+pub(super) struct Session {
+    dir: PathBuf,
+    pub(super) meta: SessionMeta,
+}
+
+impl Session {
+    pub(super) fn new(dir: PathBuf, provider: ProviderKind, model: &str) -> Self {
+        let now = now();
+        let cwd = std::env::current_dir().unwrap_or_default();
+
+        Self {
+            dir,
+            meta: SessionMeta {
+                id: format!("{now:x}{:04x}", fastrand::u16(..)),
+                created_at: now,
+                updated_at: now,
+                cwd,
+                provider,
+                model: model.to_string(),
+                title: String::new(),
+                turns: 0,
+                parent: None,
+                name: None,
+            },
+        }
+    }
+
+    /// Returns a new session that's forked from this one; it's saved once it's given the
+    /// conversation
+    pub(super) fn fork(&self, name: Option<&str>) -> Self {
+        let mut fork = Session::new(self.dir.clone(), self.meta.provider, &self.meta.model);
+        fork.meta.title = self.meta.title.clone();
+        fork.meta.parent = Some(self.meta.id.clone());
+        fork.meta.name = name.map(str::to_string);
+
+        fork
+    }
+
+    /// Overwrites the session's file with the given conversation; empty conversations aren't
+    /// saved
+    pub(super) fn save(&mut self, contents: &[Content], model: &str) -> anyhow::Result<()> {
+        if contents.is_empty() {
+            return Ok(());
+        }
+
+        self.meta.updated_at = now();
+        self.meta.model = model.to_string();
+        self.meta.turns = contents.len();
+        if self.meta.title.is_empty() {
+            self.meta.title = get_title(contents);
+        }
+
+        let mut lines = vec![serde_json::to_string(&Record::Session(self.meta.clone()))?];
+        for content in contents {
+            lines.push(serde_json::to_string(&Record::Content(content.clone()))?);
+        }
+
+        std::fs::create_dir_all(&self.dir).context("couldn't create sessions directory")?;
+        let path = get_session_path(&self.dir, &self.meta.id);
+        // written to a temporary file first, so that a crash doesn't leave a truncated session
+        let tmp_path = path.with_extension("tmp");
+        std::fs::write(&tmp_path, lines.join("\n") + "\n")
+            .with_context(|| format!("couldn't write {}", tmp_path.to_string_lossy()))?;
+        std::fs::rename(&tmp_path, &path)
+            .with_context(|| format!("couldn't write {}", path.to_string_lossy()))?;
+
+        Ok(())
+    }
+}
+
+pub(super) fn get_sessions_dir() -> anyhow::Result<PathBuf> {
+    let strategy = choose_base_strategy()?;
+
+    Ok(strategy.data_dir().join("agent1").join("sessions"))
+}
+
+/// Lists the saved sessions, most recently updated first
+pub(super) fn list_sessions(dir: &Path) -> anyhow::Result<Vec<SessionMeta>> {
+    let entries = match std::fs::read_dir(dir) {
+        Ok(entries) => entries,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(vec![]),
+        Err(e) => return Err(e).context("couldn't read sessions directory"),
+    };
+
+    let mut sessions = vec![];
+    for entry in entries {
+        let path = entry.context("couldn't read sessions directory")?.path();
+        if path
+            .extension()
+            .is_none_or(|ext| ext != SESSION_FILE_EXTENSION)
+        {
+            continue;
+        }
+
+        // sessions that can't be read are skipped, so that one broken file doesn't hide the rest
+        if let Ok(meta) = read_meta(&path) {
+            sessions.push(meta);
+        }
+    }
+    sessions.sort_by_key(|s| std::cmp::Reverse(s.updated_at));
+
+    Ok(sessions)
+}
+
+/// Orders sessions so that forks follow the session they were forked from, and pairs each session
+/// with its depth in the fork tree.
+///
+/// Sessions whose parent isn't among the given ones are shown at the top level.
+pub(super) fn get_session_tree(sessions: Vec<SessionMeta>) -> Vec<(usize, SessionMeta)> {
+    fn add_children(
+        parent: &str,
+        depth: usize,
+        sessions: &[SessionMeta],
+        tree: &mut Vec<(usize, SessionMeta)>,
+    ) {
+        for child in sessions
+            .iter()
+            .filter(|s| s.parent.as_deref() == Some(parent))
+        {
+            tree.push((depth, child.clone()));
+            add_children(&child.id, depth + 1, sessions, tree);
+        }
+    }
+
+    let mut tree = vec![];
+    let roots = sessions.iter().filter(|s| {
+        s.parent
+            .as_ref()
+            .is_none_or(|parent| !sessions.iter().any(|p| &p.id == parent))
+    });
+    for root in roots {
+        tree.push((0, root.clone()));
+        add_children(&root.id, 1, &sessions, &mut tree);
+    }
+
+    tree
+}
+
+/// Loads a session by its id, or by a prefix of its id that's unique
+pub(super) fn load_session(
+    dir: &Path,
+    id_or_prefix: &str,
+) -> anyhow::Result<(Session, Vec<Content>)> {
+    // every id starts with an empty prefix
+    if id_or_prefix.is_empty() {
+        anyhow::bail!("no session id given");
+    }
+
+    let sessions = list_sessions(dir)?;
+    let matching = sessions
+        .iter()
+        .filter(|s| s.id.starts_with(id_or_prefix))
+        .collect::<Vec<_>>();
+
+    let meta = match matching.as_slice() {
+        [meta] => *meta,
+        [] => anyhow::bail!("no session with id {id_or_prefix}"),
+        _ => match matching.iter().find(|s| s.id == id_or_prefix) {
+            Some(meta) => *meta,
+            None => anyhow::bail!("{id_or_prefix} matches more than one session"),
+        },
+    };
+
+    let path = get_session_path(dir, &meta.id);
+    let file_contents = std::fs::read_to_string(&path)
+        .with_context(|| format!("couldn't read {}", path.to_string_lossy()))?;
+
+    let mut session_meta = None;
+    let mut contents = vec![];
+    for (i, line) in file_contents.lines().enumerate() {
+        if line.trim().is_empty() {
+            continue;
+        }
+        let record = serde_json::from_str::<Record>(line)
+            .with_context(|| format!("line {} of {} is invalid", i + 1, path.to_string_lossy()))?;
+        match record {
+            Record::Session(meta) => session_meta = Some(meta),
+            Record::Content(content) => contents.push(content),
+        }
+    }
+
+    let meta = session_meta
+        .with_context(|| format!("{} doesn't have session metadata", path.to_string_lossy()))?;
+
+    Ok((
+        Session {
+            dir: dir.to_path_buf(),
+            meta,
+        },
+        contents,
+    ))
+}
+
+/// Returns the id of the most recently updated session started in `cwd`
+pub(super) fn get_latest_session_id(dir: &Path, cwd: &Path) -> anyhow::Result<Option<String>> {
+    let sessions = list_sessions(dir)?;
+
+    Ok(sessions.into_iter().find(|s| s.cwd == cwd).map(|s| s.id))
+}
+
+/// Describes a session in a single line, eg. "6712f3a41c2f  2 hours ago  gemini-2.5-flash
+/// 12 turns  [try-sqlx] what does this project do?"
+pub(super) fn describe_session(meta: &SessionMeta, cwd: &Path) -> String {
+    let mut description = format!(
+        "{}  {}  {}  {} turns  ",
+        meta.id,
+        format_age(now().saturating_sub(meta.updated_at)),
+        meta.model,
+        meta.turns,
+    );
+    if let Some(name) = &meta.name {
+        description.push_str(&format!("[{name}] "));
+    }
+    description.push_str(&meta.title);
+    if meta.cwd != cwd {
+        description.push_str(&format!("  ({})", meta.cwd.to_string_lossy()));
+    }
+
+    description
+}
+
+fn read_meta(path: &Path) -> anyhow::Result<SessionMeta> {
+    use std::io::BufRead;
+
+    let file = std::fs::File::open(path)?;
+    let mut first_line = String::new();
+    std::io::BufReader::new(file).read_line(&mut first_line)?;
+
+    match serde_json::from_str::<Record>(&first_line)? {
+        Record::Session(meta) => Ok(meta),
+        Record::Content(_) => anyhow::bail!("session file doesn't start with metadata"),
+    }
+}
+
+fn get_session_path(dir: &Path, id: &str) -> PathBuf {
+    dir.join(format!("{id}.{SESSION_FILE_EXTENSION}"))
+}
+
+fn get_title(contents: &[Content]) -> String {
+    let text = contents
+        .iter()
+        .filter(|c| c.role == Some(Role::User))
+        .flat_map(|c| &c.parts)
+        .find_map(|part| match part {
+            Part::Text(t) if !t.trim().is_empty() => Some(t.as_str()),
+            _ => None,
+        })
+        .unwrap_or_default();
+
+    let first_line = text.trim().lines().next().unwrap_or_default();
+    let mut title = first_line.chars().take(MAX_TITLE_CHARS).collect::<String>();
+    if first_line.chars().count() > MAX_TITLE_CHARS {
+        title.push('…');
+    }
+
+    title
+}
+
+fn format_age(secs: u64) -> String {
+    let (value, unit) = match secs {
+        0..60 => return "just now".to_string(),
+        60..3600 => (secs / 60, "minute"),
+        3600..86400 => (secs / 3600, "hour"),
+        _ => (secs / 86400, "day"),
+    };
+
+    if value == 1 {
+        format!("1 {unit} ago")
+    } else {
+        format!("{value} {unit}s ago")
+    }
+}
+
+fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or_default()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use insta::assert_snapshot;
+
+    fn temp_dir() -> PathBuf {
+        std::env::temp_dir().join(format!("agent1-sessions-{:x}", fastrand::u64(..)))
+    }
+
+    fn conversation() -> Vec<Content> {
+        vec![
+            Content {
+                parts: vec![Part::Text(
+                    "what does this project do?\nbe brief".to_string(),
+                )],
+                role: Some(Role::User),
+            },
+            Content {
+                parts: vec![Part::Text("it's an AI agent".to_string())],
+                role: Some(Role::Model),
+            },
+        ]
+    }
+
+    //-------------//
+    //  SUCCESSES  //
+    //-------------//
+
+    #[test]
+    fn saved_sessions_can_be_listed_and_loaded() {
+        // GIVEN
+        let dir = temp_dir();
+        let mut session = Session::new(dir.clone(), ProviderKind::Gemini, "gemini-2.5-flash");
+        session
+            .save(&conversation(), "gemini-2.5-pro")
+            .expect("session should've been saved");
+
+        // WHEN
+        let sessions = list_sessions(&dir).expect("sessions should've been listed");
+        let (loaded, contents) =
+            load_session(&dir, &session.meta.id[..6]).expect("session should've been loaded");
+
+        // THEN
+        assert_eq!(sessions.len(), 1);
+        assert_eq!(loaded.meta.id, session.meta.id);
+        assert_eq!(loaded.meta.model, "gemini-2.5-pro");
+        assert_eq!(loaded.meta.title, "what does this project do?");
+        assert_eq!(contents.len(), 2);
+        std::fs::remove_dir_all(dir).expect("temp dir should've been removed");
+    }
+
+    #[test]
+    fn ages_are_shown_relative_to_now() {
+        // GIVEN
+        // WHEN
+        let ages = [30, 60, 7200, 86400 * 3].map(format_age);
+
+        // THEN
+        assert_snapshot!(ages.join(", "), @"just now, 1 minute ago, 2 hours ago, 3 days ago");
+    }
+
+    #[test]
+    fn forks_follow_the_session_they_were_forked_from() {
+        // GIVEN
+        let dir = temp_dir();
+        let root = Session::new(dir.clone(), ProviderKind::Gemini, "gemini-2.5-flash");
+        let fork = root.fork(Some("try-sqlx"));
+        let nested = fork.fork(None);
+        let other = Session::new(dir, ProviderKind::Gemini, "gemini-2.5-flash");
+        // most recently updated first, like list_sessions returns them
+        let sessions = [&nested, &other, &fork, &root].map(|s| s.meta.clone());
+
+        // WHEN
+        let tree = get_session_tree(sessions.to_vec());
+
+        // THEN
+        let tree = tree
+            .iter()
+            .map(|(depth, meta)| (*depth, meta.id.as_str()))
+            .collect::<Vec<_>>();
+        assert_eq!(
+            tree,
+            vec![
+                (0, other.meta.id.as_str()),
+                (0, root.meta.id.as_str()),
+                (1, fork.meta.id.as_str()),
+                (2, nested.meta.id.as_str()),
+            ]
+        );
+    }
+
+    //------------//
+    //  FAILURES  //
+    //------------//
+
+    #[test]
+    fn loading_an_unknown_session_fails() {
+        // GIVEN
+        let dir = temp_dir();
+
+        // WHEN
+        let result = load_session(&dir, "abc").expect_err("result wasn't an error");
+
+        // THEN
+        assert_snapshot!(result, @"no session with id abc");
+    }
+
+    #[test]
+    fn loading_a_session_without_an_id_fails() {
+        // GIVEN
+        let dir = temp_dir();
+        let mut session = Session::new(dir.clone(), ProviderKind::Gemini, "gemini-2.5-flash");
+        session
+            .save(&conversation(), "gemini-2.5-flash")
+            .expect("session should've been saved");
+
+        // WHEN
+        let result = load_session(&dir, "").expect_err("result wasn't an error");
+
+        // THEN
+        assert_snapshot!(result, @"no session id given");
+        std::fs::remove_dir_all(dir).expect("temp dir should've been removed");
+    }
+}
@@ -1,10 +1,18 @@
+use super::attachments::{get_mime_type, load_attachment};
 use super::domain::*;
 use crate::tools::{edit_file, list_files, read_file, run_cmd};
 use anyhow::Context;
 use schemars::schema_for;
 use serde_json::Value;
+use std::path::Path;
 
-pub(super) fn get_tools() -> Vec<Tool> {
+pub(super) struct FunctionCallResult {
+    pub(super) response: FunctionResponse,
+    /// Part to send right after the function's response, eg. an image the model asked to read
+    pub(super) follow_up: Option<Part>,
+}
+
+pub(super) fn get_tools() -> Vec<FunctionDeclaration> {
     let read_file_tool_schema = schema_for!(ReadFileToolArgs);
     let mut read_file_tool_schema_value: Value = read_file_tool_schema.to_value();
 
@@ -13,13 +21,11 @@ pub(super) fn get_tools() -> Vec<Tool> {
         obj.remove("title");
     }
 
-    let read_file_tool = Tool::FunctionDeclarations (
-        vec![FunctionDeclaration {
-            name: FunctionDeclarationName::ReadFile,
-            description: "Read the contents of a given relative file path. Use this when you want to see what's inside a file. Do not use this with directory names.".to_string(),
-            parameters: read_file_tool_schema_value,
-        }],
-    );
+    let read_file_tool = FunctionDeclaration {
+        name: FunctionDeclarationName::ReadFile,
+        description: "Read the contents of a given relative file path. Use this when you want to see what's inside a file. Do not use this with directory names.".to_string(),
+        parameters: read_file_tool_schema_value,
+    };
 
     let list_files_tool_schema = schema_for!(ListFileToolArgs);
     let mut list_files_tool_schema_value: Value = list_files_tool_schema.to_value();
@@ -29,13 +35,11 @@ pub(super) fn get_tools() -> Vec<Tool> {
         obj.remove("title");
     }
 
-    let list_files_tool = Tool::FunctionDeclarations (
-        vec![FunctionDeclaration {
-            name: FunctionDeclarationName::ListFiles,
-            description: "List files and directories at a given path. If no path is provided, lists files in the current directory.".to_string(),
-            parameters: list_files_tool_schema_value,
-        }],
-    );
+    let list_files_tool = FunctionDeclaration {
+        name: FunctionDeclarationName::ListFiles,
+        description: "List files and directories at a given path. If no path is provided, lists files in the current directory.".to_string(),
+        parameters: list_files_tool_schema_value,
+    };
 
     let edit_file_tool_schema = schema_for!(EditFileToolArgs);
     let mut edit_file_tool_schema_value: Value = edit_file_tool_schema.to_value();
@@ -45,18 +49,16 @@ pub(super) fn get_tools() -> Vec<Tool> {
         obj.remove("title");
     }
 
-    let edit_file_tool = Tool::FunctionDeclarations (
-        vec![FunctionDeclaration {
-            name: FunctionDeclarationName::EditFile,
-            description: r#"Make edits to a text file.
+    let edit_file_tool = FunctionDeclaration {
+        name: FunctionDeclarationName::EditFile,
+        description: r#"Make edits to a text file.
 
 Replaces 'old_str' with 'new_str' in the given file. 'old_str' and 'new_str' MUST be different from each other.
 
 If 'old_str' is empty, the entire file will be replaced with 'new_str' (use this for editing an entire file).
 If the file specified with path doesn't exist, it will be created."#.to_string(),
-            parameters: edit_file_tool_schema_value,
-        }],
-    );
+        parameters: edit_file_tool_schema_value,
+    };
 
     let run_command_tool_schema = schema_for!(RunCmdArgs);
     let mut run_command_tool_schema_value: Value = run_command_tool_schema.to_value();
@@ -66,11 +68,11 @@ If the file specified with path doesn't exist, it will be created."#.to_string()
         obj.remove("title");
     }
 
-    let run_cmd_tool = Tool::FunctionDeclarations(vec![FunctionDeclaration {
+    let run_cmd_tool = FunctionDeclaration {
         name: FunctionDeclarationName::RunCmd,
         description: "Run a shell command via bash. Will return the combined stdout and stderr of the command.".to_string(),
         parameters: run_command_tool_schema_value,
-    }]);
+    };
 
     vec![
         read_file_tool,
@@ -80,9 +82,15 @@ If the file specified with path doesn't exist, it will be created."#.to_string()
     ]
 }
 
+/// Executes a function call.
+///
+/// `supports_attachment` tells whether files of a MIME type can be sent to the model; if so,
+/// read_file returns images and PDFs as a follow up part with the file's data.
 pub(super) fn execute_function_call(
     function_call: &FunctionCall,
-) -> anyhow::Result<FunctionResponse> {
+    supports_attachment: &dyn Fn(&str) -> bool,
+) -> anyhow::Result<FunctionCallResult> {
+    let mut follow_up = None;
     let response = match &function_call.name {
         FunctionDeclarationName::ReadFile => {
             let args = match &function_call.args {
@@ -94,9 +102,33 @@ pub(super) fn execute_function_call(
 
             let args: ReadFileToolArgs =
                 serde_json::from_value(args.clone()).context("invalid arguments provided")?;
-            match read_file(&args.path) {
-                Ok(r) => FunctionCallResponse::Output(r),
-                Err(e) => FunctionCallResponse::Error(e.to_string()),
+            let path = Path::new(&args.path);
+            match get_mime_type(path) {
+                Some(mime_type)
+                    if mime_type.starts_with("image/") || mime_type == "application/pdf" =>
+                {
+                    if !supports_attachment(mime_type) {
+                        FunctionCallResponse::Error(format!(
+                            "{} can't be read: {mime_type} files aren't supported by the current model",
+                            args.path
+                        ))
+                    } else {
+                        match load_attachment(path) {
+                            Ok(blob) => {
+                                follow_up = Some(Part::InlineData(blob));
+                                FunctionCallResponse::Output(format!(
+                                    "the contents of {} ({mime_type}) follow this response",
+                                    args.path
+                                ))
+                            }
+                            Err(e) => FunctionCallResponse::Error(format!("{e:#}")),
+                        }
+                    }
+                }
+                _ => match read_file(&args.path) {
+                    Ok(r) => FunctionCallResponse::Output(r),
+                    Err(e) => FunctionCallResponse::Error(e.to_string()),
+                },
             }
         }
         FunctionDeclarationName::ListFiles => {
@@ -150,10 +182,68 @@ pub(super) fn execute_function_call(
         }
     };
 
-    Ok(FunctionResponse {
-        id: function_call.id.clone(),
-        name: function_call.name.clone(),
-        response,
-        will_continue: Some(false),
+    Ok(FunctionCallResult {
+        response: FunctionResponse {
+            id: function_call.id.clone(),
+            name: function_call.name.clone(),
+            response,
+            will_continue: Some(false),
+        },
+        follow_up,
     })
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use insta::assert_snapshot;
+    use serde_json::json;
+
+    fn read_file_call(path: &str) -> FunctionCall {
+        FunctionCall {
+            id: None,
+            name: FunctionDeclarationName::ReadFile,
+            args: Some(json!({"path": path})),
+            thought_signature: None,
+        }
+    }
+
+    //-------------//
+    //  SUCCESSES  //
+    //-------------//
+
+    #[test]
+    fn read_file_returns_images_as_a_follow_up_part() {
+        // GIVEN
+        let call = read_file_call("src/tools/testdata/pixel.png");
+
+        // WHEN
+        let result =
+            execute_function_call(&call, &|_| true).expect("result should've been a success");
+
+        // THEN
+        assert_snapshot!(format!("{:?}", result.response.response), @r#"Output("the contents of src/tools/testdata/pixel.png (image/png) follow this response")"#);
+        let Some(Part::InlineData(blob)) = result.follow_up else {
+            panic!("follow up wasn't inline data");
+        };
+        assert_eq!(blob.mime_type, "image/png");
+    }
+
+    //------------//
+    //  FAILURES  //
+    //------------//
+
+    #[test]
+    fn read_file_refuses_images_the_provider_does_not_support() {
+        // GIVEN
+        let call = read_file_call("src/tools/testdata/pixel.png");
+
+        // WHEN
+        let result =
+            execute_function_call(&call, &|_| false).expect("result should've been a success");
+
+        // THEN
+        assert_snapshot!(format!("{:?}", result.response.response), @r#"Error("src/tools/testdata/pixel.png can't be read: image/png files aren't supported by the current model")"#);
+        assert!(result.follow_up.is_none());
+    }
+}
@@ -0,0 +1,246 @@
+use super::domain::Usage;
+use std::collections::BTreeMap;
+
+/// Price of a model in USD per million tokens; thinking tokens are billed as output tokens
+#[derive(Debug, Clone, Copy, PartialEq)]
+struct Price {
+    input: f64,
+    cached_input: f64,
+    output: f64,
+}
+
+/// Standard (non batch) prices by model name prefix; the first matching prefix wins, so more
+/// specific prefixes come first.
+///
+/// Tiered prices for long prompts and cache write surcharges aren't taken into account.
+const PRICES: [(&str, Price); 14] = [
+    ("gemini-2.5-pro", price(1.25, 0.125, 10.0)),
+    ("gemini-2.5-flash-lite", price(0.10, 0.01, 0.40)),
+    ("gemini-2.5-flash", price(0.30, 0.03, 2.50)),
+    ("gemini-2.0-flash-lite", price(0.075, 0.075, 0.30)),
+    ("gemini-2.0-flash", price(0.10, 0.025, 0.40)),
+    ("claude-opus-4-5", price(5.0, 0.50, 25.0)),
+    ("claude-opus-4", price(15.0, 1.50, 75.0)),
+    ("claude-sonnet-4", price(3.0, 0.30, 15.0)),
+    ("claude-3-7-sonnet", price(3.0, 0.30, 15.0)),
+    ("claude-3-5-sonnet", price(3.0, 0.30, 15.0)),
+    ("claude-haiku-4-5", price(1.0, 0.10, 5.0)),
+    ("claude-3-5-haiku", price(0.80, 0.08, 4.0)),
+    ("claude-3-haiku", price(0.25, 0.03, 1.25)),
+    ("claude-3-opus", price(15.0, 1.50, 75.0)),
+];
+
+const fn price(input: f64, cached_input: f64, output: f64) -> Price {
+    Price {
+        input,
+        cached_input,
+        output,
+    }
+}
+
+fn get_price(model: &str) -> Option<Price> {
+    PRICES
+        .iter()
+        .find(|(prefix, _)| model.starts_with(prefix))
+        .map(|(_, price)| *price)
+}
+
+/// Estimated cost of a request in USD, if the model's price is known
+fn estimate_cost(model: &str, usage: &Usage) -> Option<f64> {
+    let price = get_price(model)?;
+    let uncached_tokens = usage.prompt_tokens.saturating_sub(usage.cached_tokens);
+
+    let cost = uncached_tokens as f64 * price.input
+        + usage.cached_tokens as f64 * price.cached_input
+        + (usage.output_tokens + usage.thinking_tokens) as f64 * price.output;
+
+    Some(cost / 1_000_000.0)
+}
+
+/// Describes the tokens used, eg. "1200 prompt (800 cached), 50 output, 30 thinking"
+fn describe_usage(usage: &Usage) -> String {
+    let mut description = format!("{} prompt", usage.prompt_tokens);
+    if usage.cached_tokens > 0 {
+        description.push_str(&format!(" ({} cached)", usage.cached_tokens));
+    }
+    description.push_str(&format!(", {} output", usage.output_tokens));
+    if usage.thinking_tokens > 0 {
+        description.push_str(&format!(", {} thinking", usage.thinking_tokens));
+    }
+
+    description
+}
+
+#[derive(Debug, Default)]
+struct ModelUsage {
+    requests: usize,
+    usage: Usage,
+    /// `None` if the model's price isn't known
+    cost: Option<f64>,
+}
+
+/// Tokens used (and their estimated cost) since agent1 started, by model
+#[derive(Debug, Default)]
+pub(super) struct SessionUsage {
+    by_model: BTreeMap<String, ModelUsage>,
+}
+
+impl SessionUsage {
+    /// Records the usage of a request, and returns a line describing it along with the session's
+    /// totals
+    pub(super) fn record(&mut self, model: &str, usage: Usage) -> String {
+        let cost = estimate_cost(model, &usage);
+
+        let model_usage = self
+            .by_model
+            .entry(model.to_string())
+            .or_insert(ModelUsage {
+                cost: Some(0.0),
+                ..Default::default()
+            });
+        model_usage.requests += 1;
+        model_usage.usage += usage;
+        model_usage.cost = model_usage.cost.zip(cost).map(|(total, cost)| total + cost);
+
+        let (total, total_cost) = self.totals();
+        format!(
+            "tokens: {}{} (session: {} tokens{})",
+            describe_usage(&usage),
+            format_cost(cost),
+            total.total_tokens,
+            format_cost(total_cost),
+        )
+    }
+
+    /// Returns the breakdown of the tokens used by model, followed by the totals
+    pub(super) fn summary(&self) -> Vec<String> {
+        if self.by_model.is_empty() {
+            return vec!["no tokens used yet".to_string()];
+        }
+
+        let mut lines = vec![];
+        for (model, model_usage) in &self.by_model {
+            let requests = match model_usage.requests {
+                1 => "1 request".to_string(),
+                n => format!("{n} requests"),
+            };
+            lines.push(format!(
+                "{model}: {requests}, {}{}",
+                describe_usage(&model_usage.usage),
+                format_cost(model_usage.cost),
+            ));
+        }
+
+        let (total, total_cost) = self.totals();
+        lines.push(format!(
+            "total: {} tokens{}",
+            total.total_tokens,
+            format_cost(total_cost)
+        ));
+        if total_cost.is_none() {
+            lines.push("costs are only estimated for known gemini and claude models".to_string());
+        }
+
+        lines
+    }
+
+    /// The cost is `None` if the price of any model that was used isn't known
+    fn totals(&self) -> (Usage, Option<f64>) {
+        let mut total = Usage::default();
+        let mut total_cost = Some(0.0);
+        for model_usage in self.by_model.values() {
+            total += model_usage.usage;
+            total_cost = total_cost
+                .zip(model_usage.cost)
+                .map(|(total, cost)| total + cost);
+        }
+
+        (total, total_cost)
+    }
+}
+
+fn format_cost(cost: Option<f64>) -> String {
+    match cost {
+        Some(cost) => format!(", ~${cost:.4}"),
+        None => String::new(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use insta::assert_snapshot;
+
+    //-------------//
+    //  SUCCESSES  //
+    //-------------//
+
+    #[test]
+    fn usage_is_broken_down_by_model_with_estimated_costs() {
+        // GIVEN
+        let mut usage = SessionUsage::default();
+
+        // WHEN
+        usage.record(
+            "gemini-2.5-flash",
+            Usage {
+                prompt_tokens: 10_000,
+                cached_tokens: 8000,
+                output_tokens: 500,
+                thinking_tokens: 300,
+                total_tokens: 10_800,
+            },
+        );
+        let line = usage.record(
+            "gemini-2.5-flash",
+            Usage {
+                prompt_tokens: 12_000,
+                cached_tokens: 0,
+                output_tokens: 200,
+                thinking_tokens: 0,
+                total_tokens: 12_200,
+            },
+        );
+
+        // THEN
+        assert_snapshot!(line, @"tokens: 12000 prompt, 200 output, ~$0.0041 (session: 23000 tokens, ~$0.0069)");
+        assert_snapshot!(usage.summary().join("\n"), @r"
+        gemini-2.5-flash: 2 requests, 22000 prompt (8000 cached), 700 output, 300 thinking, ~$0.0069
+        total: 23000 tokens, ~$0.0069
+        ");
+    }
+
+    #[test]
+    fn cost_is_left_out_for_models_without_a_known_price() {
+        // GIVEN
+        let mut usage = SessionUsage::default();
+        usage.record(
+            "claude-sonnet-4-5",
+            Usage {
+                prompt_tokens: 1000,
+                output_tokens: 100,
+                total_tokens: 1100,
+                ..Default::default()
+            },
+        );
+
+        // WHEN
+        usage.record(
+            "qwen2.5-coder",
+            Usage {
+                prompt_tokens: 2000,
+                output_tokens: 200,
+                total_tokens: 2200,
+                ..Default::default()
+            },
+        );
+
+        // THEN
+        assert_snapshot!(usage.summary().join("\n"), @r"
+        claude-sonnet-4-5: 1 request, 1000 prompt, 100 output, ~$0.0045
+        qwen2.5-coder: 1 request, 2000 prompt, 200 output
+        total: 3300 tokens
+        costs are only estimated for known gemini and claude models
+        ");
+    }
+}
@@ -0,0 +1,202 @@
+use crate::agent::{ExportFormat, GenerationSettings, ProviderKind};
+use clap::{ArgGroup, Parser, Subcommand};
+use serde_json::Value;
+use std::path::PathBuf;
+
+#[derive(Parser, Debug)]
+#[command(about, group(ArgGroup::new("one_shot").args(["prompt", "prompt_file"])))]
+pub struct Args {
+    #[command(subcommand)]
+    pub command: Option<Command>,
+    /// LLM provider to use [default: gemini]
+    #[arg(
+        long = "provider",
+        short = 'P',
+        value_name = "PROVIDER",
+        value_enum,
+        env = "AGENT1_PROVIDER"
+    )]
+    pub provider: Option<ProviderKind>,
+    /// Model to use (defaults to a model picked for the provider)
+    #[arg(
+        long = "model",
+        short = 'm',
+        value_name = "MODEL",
+        env = "AGENT1_MODEL"
+    )]
+    pub model: Option<String>,
+    /// Override the provider's API base URL (eg. to use a proxy)
+    #[arg(long = "base-url", value_name = "URL", env = "AGENT1_BASE_URL")]
+    pub base_url: Option<String>,
+    /// Path to the config file (defaults to agent1/config.toml in the user's config directory)
+    #[arg(
+        long = "config",
+        short = 'c',
+        value_name = "PATH",
+        env = "AGENT1_CONFIG"
+    )]
+    pub config: Option<PathBuf>,
+    /// Run the agent on this prompt without asking for anything, and print its final answer
+    #[arg(
+        long = "prompt",
+        short = 'p',
+        value_name = "PROMPT",
+        conflicts_with_all = ["prompt_file", "resume", "continue_session"]
+    )]
+    pub prompt: Option<String>,
+    /// Like --prompt, but reads the prompt from a file
+    #[arg(
+        long = "prompt-file",
+        short = 'f',
+        value_name = "PATH",
+        conflicts_with_all = ["resume", "continue_session"]
+    )]
+    pub prompt_file: Option<PathBuf>,
+    /// Send what's piped to stdin along with the prompt
+    #[arg(long = "stdin", requires = "one_shot")]
+    pub stdin: bool,
+    /// Resume a saved session (lets you pick one if no id is given)
+    #[arg(
+        long = "resume",
+        short = 'r',
+        value_name = "ID",
+        num_args = 0..=1,
+        default_missing_value = "",
+        conflicts_with = "continue_session"
+    )]
+    pub resume: Option<String>,
+    /// Continue the most recent session started in the current directory
+    #[arg(long = "continue")]
+    pub continue_session: bool,
+    /// How many times to retry requests that fail with a 429 or 5xx status code [default: 3]
+    #[arg(long = "max-retries", value_name = "NUM", env = "AGENT1_MAX_RETRIES")]
+    pub max_retries: Option<u32>,
+    /// Cache the system prompt, tools and older turns for this many seconds, via Gemini's context
+    /// caching (only used by gemini)
+    #[arg(long = "cache-ttl", value_name = "SECONDS", env = "AGENT1_CACHE_TTL")]
+    pub cache_ttl: Option<u64>,
+    /// Size of the model's context window in tokens, used to warn before the conversation
+    /// outgrows it (defaults to the known size for gemini and claude models, and to num_ctx for
+    /// ollama)
+    #[arg(
+        long = "context-window",
+        value_name = "TOKENS",
+        env = "AGENT1_CONTEXT_WINDOW"
+    )]
+    pub context_window: Option<usize>,
+    /// Compact the conversation once it takes up this share (in percent) of the context window
+    #[arg(
+        long = "auto-compact",
+        value_name = "PERCENT",
+        env = "AGENT1_AUTO_COMPACT",
+        value_parser = clap::value_parser!(u8).range(1..=100)
+    )]
+    pub auto_compact: Option<u8>,
+    /// Replace function outputs with a placeholder after this many model turns (0 disables this)
+    /// [default: 20]
+    #[arg(long = "elide-after", value_name = "TURNS", env = "AGENT1_ELIDE_AFTER")]
+    pub elide_after: Option<usize>,
+    /// Replace function outputs larger than this with a placeholder once the model has seen them
+    /// (0 disables this) [default: 32768]
+    #[arg(
+        long = "elide-larger-than",
+        value_name = "BYTES",
+        env = "AGENT1_ELIDE_LARGER_THAN"
+    )]
+    pub elide_larger_than: Option<usize>,
+    /// Continue responses that were cut off by the output token limit without asking
+    #[arg(long = "auto-continue", env = "AGENT1_AUTO_CONTINUE")]
+    pub auto_continue: bool,
+    /// Don't print the model's thoughts (they can be shown again via /thoughts)
+    #[arg(long = "hide-thoughts", env = "AGENT1_HIDE_THOUGHTS")]
+    pub hide_thoughts: bool,
+    /// Model option to pass to ollama (eg. num_ctx=16384); can be repeated
+    #[arg(long = "ollama-option", value_name = "KEY=VALUE", value_parser = parse_key_value)]
+    pub ollama_options: Vec<(String, Value)>,
+    /// Sampling temperature
+    #[arg(long = "temperature", value_name = "TEMPERATURE")]
+    pub temperature: Option<f64>,
+    /// Nucleus sampling probability mass
+    #[arg(long = "top-p", value_name = "TOP_P")]
+    pub top_p: Option<f64>,
+    /// Maximum number of tokens the model may generate per response
+    #[arg(long = "max-output-tokens", value_name = "NUM")]
+    pub max_output_tokens: Option<u32>,
+    /// Sequence that stops generation when produced; can be repeated
+    #[arg(long = "stop-sequence", value_name = "SEQUENCE")]
+    pub stop_sequences: Vec<String>,
+    /// Seed for sampling, for more deterministic responses
+    #[arg(long = "seed", value_name = "SEED")]
+    pub seed: Option<i64>,
+    /// Tokens the model may spend on thinking (for gemini: 0 disables thinking, -1 lets the model
+    /// decide)
+    #[arg(
+        long = "thinking-budget",
+        value_name = "NUM",
+        allow_negative_numbers = true
+    )]
+    pub thinking_budget: Option<i32>,
+    /// Ask the model for (summaries of) its thoughts (gemini and ollama; other providers return
+    /// thoughts whenever thinking is enabled)
+    #[arg(long = "include-thoughts")]
+    pub include_thoughts: bool,
+    /// Gemini safety setting (eg. HARM_CATEGORY_HARASSMENT=BLOCK_NONE); can be repeated
+    #[arg(long = "safety-setting", value_name = "CATEGORY=THRESHOLD", value_parser = parse_safety_setting)]
+    pub safety_settings: Vec<(String, String)>,
+}
+
+#[derive(Subcommand, Debug)]
+pub enum Command {
+    /// Export a saved session's transcript as Markdown or HTML
+    Export {
+        /// Id of the session to export (or a unique prefix of it)
+        session: String,
+        /// File to write the transcript to (printed to stdout if not given)
+        #[arg(long = "output", short = 'o', value_name = "PATH")]
+        output: Option<PathBuf>,
+        /// Format of the transcript [default: html for .html files, markdown otherwise]
+        #[arg(long = "format", value_name = "FORMAT", value_enum)]
+        format: Option<ExportFormat>,
+    },
+}
+
+impl Args {
+    pub fn generation_settings(&self) -> GenerationSettings {
+        GenerationSettings {
+            temperature: self.temperature,
+            top_p: self.top_p,
+            max_output_tokens: self.max_output_tokens,
+            stop_sequences: self.stop_sequences.clone(),
+            seed: self.seed,
+            thinking_budget: self.thinking_budget,
+            include_thoughts: self.include_thoughts.then_some(true),
+            safety_settings: self.safety_settings.iter().cloned().collect(),
+        }
+    }
+}
+
+fn parse_safety_setting(input: &str) -> Result<(String, String), String> {
+    let (category, threshold) = input
+        .split_once('=')
+        .ok_or_else(|| "expected CATEGORY=THRESHOLD".to_string())?;
+
+    Ok((category.trim().to_string(), threshold.trim().to_string()))
+}
+
+/// Parses a KEY=VALUE pair; values that are valid JSON (numbers, booleans, etc.) are kept as
+/// such, everything else is treated as a string
+fn parse_key_value(input: &str) -> Result<(String, Value), String> {
+    let (key, value) = input
+        .split_once('=')
+        .ok_or_else(|| "expected KEY=VALUE".to_string())?;
+
+    let key = key.trim();
+    if key.is_empty() {
+        return Err("key cannot be empty".to_string());
+    }
+
+    let value = serde_json::from_str(value.trim())
+        .unwrap_or_else(|_| Value::String(value.trim().to_string()));
+
+    Ok((key.to_string(), value))
+}
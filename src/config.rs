@@ -0,0 +1,179 @@
+use crate::agent::{GenerationSettings, ProviderKind};
+use anyhow::Context;
+use etcetera::{BaseStrategy, choose_base_strategy};
+use serde::Deserialize;
+use serde_json::{Map, Value};
+use std::path::{Path, PathBuf};
+
+const CONFIG_FILE_NAME: &str = "config.toml";
+
+/// Configuration read from agent1's TOML config file
+///
+/// ```toml
+/// provider = "ollama"
+///
+/// [ollama]
+/// model = "qwen2.5-coder"
+/// base_url = "http://gpu-box:11434"
+/// options = { num_ctx = 16384 }
+///
+/// [generation]
+/// temperature = 0.2
+/// ```
+#[derive(Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Config {
+    pub provider: Option<ProviderKind>,
+    pub max_retries: Option<u32>,
+    pub auto_continue: Option<bool>,
+    /// Share of the context window (in percent) past which the conversation is compacted
+    pub auto_compact: Option<u8>,
+    /// Number of model turns after which function outputs are elided; 0 disables this
+    pub elide_after: Option<usize>,
+    /// Size in bytes above which function outputs are elided once the model has seen them; 0
+    /// disables this
+    pub elide_larger_than: Option<usize>,
+    pub show_thoughts: Option<bool>,
+    #[serde(default)]
+    pub generation: GenerationSettings,
+    #[serde(default)]
+    pub gemini: ProviderSettings,
+    #[serde(default)]
+    pub anthropic: ProviderSettings,
+    #[serde(default)]
+    pub openai: ProviderSettings,
+    #[serde(default)]
+    pub ollama: ProviderSettings,
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct ProviderSettings {
+    pub model: Option<String>,
+    pub base_url: Option<String>,
+    /// Size of the model's context window in tokens, for models agent1 doesn't know it for
+    pub context_window: Option<usize>,
+    /// Only used by ollama
+    #[serde(default)]
+    pub options: Map<String, Value>,
+    /// Only used by gemini
+    pub cache_ttl: Option<u64>,
+}
+
+impl Config {
+    pub fn provider_settings(&mut self, kind: ProviderKind) -> ProviderSettings {
+        let settings = match kind {
+            ProviderKind::Gemini => &mut self.gemini,
+            ProviderKind::Anthropic => &mut self.anthropic,
+            ProviderKind::OpenAi => &mut self.openai,
+            ProviderKind::Ollama => &mut self.ollama,
+        };
+
+        std::mem::take(settings)
+    }
+}
+
+/// Reads the config file at the given path, or at the default location if no path is given.
+///
+/// A missing config file at the default location is not an error.
+pub fn get_config(path: Option<&Path>) -> anyhow::Result<Config> {
+    let (path, explicit) = match path {
+        Some(p) => (p.to_path_buf(), true),
+        None => (get_config_file_path()?, false),
+    };
+
+    let contents = match std::fs::read_to_string(&path) {
+        Ok(c) => c,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound && !explicit => {
+            return Ok(Config::default());
+        }
+        Err(e) => {
+            return Err(e).with_context(|| {
+                format!("couldn't read config file at {}", path.to_string_lossy())
+            });
+        }
+    };
+
+    parse_config(&contents)
+        .with_context(|| format!("invalid config file at {}", path.to_string_lossy()))
+}
+
+fn parse_config(contents: &str) -> anyhow::Result<Config> {
+    toml::from_str(contents).map_err(|e| anyhow::anyhow!("{}", e.message()))
+}
+
+fn get_config_file_path() -> anyhow::Result<PathBuf> {
+    let strategy = choose_base_strategy()?;
+
+    Ok(strategy.config_dir().join("agent1").join(CONFIG_FILE_NAME))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use insta::assert_snapshot;
+
+    //-------------//
+    //  SUCCESSES  //
+    //-------------//
+
+    #[test]
+    fn per_provider_settings_are_parsed() {
+        // GIVEN
+        let contents = r#"
+provider = "ollama"
+
+[ollama]
+model = "qwen2.5-coder"
+base_url = "http://gpu-box:11434"
+options = { num_ctx = 16384 }
+
+[openai]
+model = "Qwen/Qwen2.5-Coder-32B-Instruct"
+"#;
+
+        // WHEN
+        let mut config = parse_config(contents).expect("config should've been parsed");
+
+        // THEN
+        assert_eq!(config.provider, Some(ProviderKind::Ollama));
+        let ollama = config.provider_settings(ProviderKind::Ollama);
+        assert_eq!(ollama.model.as_deref(), Some("qwen2.5-coder"));
+        assert_eq!(ollama.base_url.as_deref(), Some("http://gpu-box:11434"));
+        assert_eq!(ollama.options.get("num_ctx"), Some(&Value::from(16384)));
+        let openai = config.provider_settings(ProviderKind::OpenAi);
+        assert_eq!(
+            openai.model.as_deref(),
+            Some("Qwen/Qwen2.5-Coder-32B-Instruct")
+        );
+    }
+
+    #[test]
+    fn empty_config_is_valid() {
+        // GIVEN
+        // WHEN
+        let config = parse_config("").expect("config should've been parsed");
+
+        // THEN
+        assert!(config.provider.is_none());
+    }
+
+    //------------//
+    //  FAILURES  //
+    //------------//
+
+    #[test]
+    fn unknown_keys_are_rejected() {
+        // GIVEN
+        let contents = r#"
+[gemini]
+modle = "gemini-2.5-flash"
+"#;
+
+        // WHEN
+        let result = parse_config(contents).expect_err("result wasn't an error");
+
+        // THEN
+        assert_snapshot!(result, @"unknown field `modle`, expected one of `model`, `base_url`, `context_window`, `options`, `cache_ttl`");
+    }
+}
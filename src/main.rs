@@ -1,13 +1,92 @@
 mod agent;
+mod args;
+mod config;
 mod log;
 mod tools;
 
+use agent::{ProviderConfig, ProviderKind, ResumeTarget, RunOptions};
 use anyhow::Context;
+use args::{Args, Command};
+use clap::Parser;
+use config::get_config;
+use std::io::IsTerminal;
 
 fn main() -> anyhow::Result<()> {
+    let args = Args::parse();
     log::setup_logging().context("couldn't set up logging")?;
+    if let Some(Command::Export {
+        session,
+        output,
+        format,
+    }) = &args.command
+    {
+        return agent::export_session(session, output.as_deref(), *format);
+    }
+
+    let mut config = get_config(args.config.as_deref())?;
+
+    let kind = args
+        .provider
+        .or(config.provider)
+        .unwrap_or(ProviderKind::Gemini);
+    let mut generation_settings = std::mem::take(&mut config.generation);
+    generation_settings.merge(args.generation_settings());
+
+    let settings = config.provider_settings(kind);
+    let mut ollama_options = settings.options;
+    ollama_options.extend(args.ollama_options);
+
+    let provider_config = ProviderConfig {
+        kind,
+        model: args.model.or(settings.model),
+        base_url: args.base_url.or(settings.base_url),
+        ollama_options,
+        max_retries: args.max_retries.or(config.max_retries),
+        cache_ttl: args.cache_ttl.or(settings.cache_ttl),
+    };
+
+    let prompt = match &args.prompt_file {
+        Some(path) => Some(
+            std::fs::read_to_string(path)
+                .with_context(|| format!("couldn't read {}", path.to_string_lossy()))?,
+        ),
+        None => args.prompt,
+    };
+
+    // reading stdin when it's a terminal would wait for the user to type something
+    if args.stdin && std::io::stdin().is_terminal() {
+        anyhow::bail!("--stdin was passed, but nothing is piped to agent1");
+    }
+
     let client = reqwest::blocking::Client::new();
-    agent::run(client)?;
+    let options = RunOptions {
+        generation_settings,
+        auto_continue: args.auto_continue || config.auto_continue.unwrap_or(false),
+        show_thoughts: !args.hide_thoughts && config.show_thoughts.unwrap_or(true),
+        context_window: args.context_window.or(settings.context_window),
+        auto_compact: args.auto_compact.or(config.auto_compact).map(usize::from),
+        elide_after: args.elide_after.or(config.elide_after),
+        elide_larger_than: args.elide_larger_than.or(config.elide_larger_than),
+        resume: match args.resume {
+            _ if args.continue_session => Some(ResumeTarget::Latest),
+            Some(id) if id.is_empty() => Some(ResumeTarget::Pick),
+            Some(id) => Some(ResumeTarget::Id(id)),
+            None => None,
+        },
+    };
+    match prompt {
+        Some(prompt) => {
+            let piped_input = args.stdin.then(agent::read_piped_input).transpose()?;
+            agent::run_once(
+                client,
+                provider_config,
+                options,
+                &prompt,
+                piped_input.as_deref(),
+            )?
+        }
+        None => agent::run(client, provider_config, options)?,
+    }
 
     Ok(())
 }